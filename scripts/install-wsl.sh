@@ -136,40 +136,12 @@ fi
 
 print_success "Binary installed to $INSTALL_DIR/xclip"
 
-# Copy example config if user doesn't have one
-CONFIG_DIR="$HOME/.config/wsl-clip-bridge"
-if [ ! -f "$CONFIG_DIR/config.toml" ]; then
+# Create default config if user doesn't have one
+CONFIG_FILE="$("$INSTALL_DIR/xclip" --config-path)"
+if [ ! -f "$CONFIG_FILE" ]; then
     print_step "Creating default config file..."
-    mkdir -p "$CONFIG_DIR"
-    
-    # Create default config
-    cat > "$CONFIG_DIR/config.toml" << 'EOF'
-# WSL Clip Bridge Configuration
-
-# Clipboard TTL in seconds (default: 300)
-ttl_secs = 300
-
-# Maximum image dimension in pixels (0 = no downscaling)
-# Recommended: 1568 for optimal Claude API performance
-max_image_dimension = 1568
-
-# Security Settings
-
-# Maximum file size in MB (default: 100)
-max_file_size_mb = 100
-
-# Directory access restrictions
-# If not configured, all paths are allowed
-# To restrict access to specific directories (and their subdirectories):
-#
-# allowed_directories = [
-#   "/mnt/c/Users/YOUR_USERNAME/Documents/ShareX",
-#   "/home/YOUR_USERNAME",
-#   "/tmp"
-# ]
-EOF
-    
-    print_success "Config file created at $CONFIG_DIR/config.toml"
+    "$INSTALL_DIR/xclip" init-config > /dev/null
+    print_success "Config file created at $CONFIG_FILE"
 fi
 
 echo ""
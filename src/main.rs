@@ -1,56 +1,400 @@
-use std::collections::HashSet;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
-use std::fs::{self, File};
-use std::io::{self, Cursor, Read, Write};
-use std::path::{Path, PathBuf};
+use std::fmt;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, Cursor, IsTerminal, Read, Write};
+use std::path::{Component, Path, PathBuf};
 use std::process::{Command, ExitCode};
-use std::time::{Duration, SystemTime};
+use std::sync::{Mutex, OnceLock, PoisonError, RwLock};
+use std::time::{Duration, Instant, SystemTime};
 
-use image::ImageFormat;
+use base64::Engine as _;
+use base64::alphabet;
+use base64::engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use glob::{MatchOptions, Pattern};
+use image::codecs::gif::GifDecoder;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::{CompressionType, FilterType as PngFilter, PngDecoder, PngEncoder};
+use image::codecs::webp::WebPDecoder;
 use image::imageops::FilterType;
-use serde::Deserialize;
+use image::metadata::Orientation;
+use image::{AnimationDecoder, DynamicImage, ImageDecoder, ImageFormat, ImageReader, Limits};
+use serde::de::{self, Deserializer, Visitor};
+use serde::{Deserialize, Serialize};
 
 #[cfg(unix)]
-use std::os::unix::fs::PermissionsExt;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+#[cfg(unix)]
+use std::sync::Arc;
+#[cfg(unix)]
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Bridge-specific commands that run instead of the xclip input/output modes
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Subcommand {
+    ConfigPath,
+    ShowConfig,
+    InitConfig,
+    Config(ConfigEdit),
+    Sniff,
+    Watch,
+    HistoryList,
+    Status,
+    Doctor,
+    Gc { dry_run: bool },
+    Clear,
+    Touch,
+    Dump,
+    Restore { preserve_age: bool },
+}
+
+// Exit codes. 0 and 1 keep their xclip meaning so existing callers don't
+// break; the others let scripts tell *why* a copy or paste failed.
+const EXIT_SUCCESS: i32 = 0; // Copied, or pasted content to stdout
+const EXIT_NO_CONTENT: i32 = 1; // Nothing (fresh) to paste, or an unexpected I/O error
+const EXIT_USAGE: i32 = 2; // Bad command line or unusable config
+const EXIT_ACCESS_DENIED: i32 = 3; // Path or MIME type rejected by the access policy
+const EXIT_TOO_LARGE: i32 = 4; // Input exceeds a configured size limit
+const EXIT_UNSUPPORTED: i32 = 5; // MIME type the bridge can't store
+const EXIT_EMPTY_INPUT: i32 = 6; // Zero-byte input rejected (empty_input = "reject")
+const EXIT_UNCLASSIFIED: i32 = 7; // -sniff couldn't identify the content type
+const EXIT_READ_ONLY: i32 = 8; // Copy refused because read_only = true
+const EXIT_PINNED: i32 = 9; // Copy refused because the stored content is pinned
+const EXIT_BUSY: i32 = 10; // Store stayed locked by another process past the wait
+const EXIT_INSECURE_STORAGE: i32 = 11; // Storage directory isn't private to this user
+const EXIT_WRITE_FAILED: i32 = 12; // Pasted content couldn't be written to --out-file
+const EXIT_TERMINAL_OUTPUT: i32 = 13; // Binary paste refused because stdout is a terminal
+
+/// Map an I/O failure onto the exit code scripts should see
+fn exit_code_for_error(err: &io::Error) -> i32 {
+    if matches!(err.get_ref(), Some(inner) if inner.is::<OutFileError>()) {
+        return EXIT_WRITE_FAILED;
+    }
+    if matches!(err.get_ref(), Some(inner) if inner.is::<TerminalOutputError>()) {
+        return EXIT_TERMINAL_OUTPUT;
+    }
+    match err.kind() {
+        io::ErrorKind::PermissionDenied => EXIT_ACCESS_DENIED,
+        io::ErrorKind::FileTooLarge => EXIT_TOO_LARGE,
+        io::ErrorKind::Unsupported => EXIT_UNSUPPORTED,
+        io::ErrorKind::TimedOut => EXIT_BUSY,
+        _ => EXIT_NO_CONTENT,
+    }
+}
+
+fn exit_with(code: i32) -> ExitCode {
+    ExitCode::from(u8::try_from(code).unwrap_or(1))
+}
 
 #[derive(Debug)]
 struct Args {
-    #[allow(dead_code)] // Keep for xclip compatibility
     selection: String,
     mime_type: Option<String>,
     mode_output: bool,
-    input_file: Option<String>,
+    input_files: Vec<String>,
+    subcommand: Option<Subcommand>,
+    force: bool,
+    json: bool,
+    check: Option<String>,
+    input: InputOptions,
+    output: OutputOptions,
+    ttl_secs: Option<u64>,
+    default_target: Option<String>,
+    follow_symlinks: Option<bool>,
+    profile: Option<String>,
+    verbosity: Verbosity,
+    storage_check: StorageCheck,
+    slot: Option<String>,
+}
+
+/// How chatty stderr is: -quiet drops advisory warnings, -verbose adds detail
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum Verbosity {
+    Quiet,
+    #[default]
+    Normal,
+    Verbose,
+}
+
+/// Set from -quiet/-verbose before any work starts
+static VERBOSITY: OnceLock<Verbosity> = OnceLock::new();
+
+fn verbose() -> bool {
+    VERBOSITY.get() == Some(&Verbosity::Verbose)
+}
+
+fn quiet() -> bool {
+    VERBOSITY.get() == Some(&Verbosity::Quiet)
+}
+
+/// Whether an unsafe storage directory stops the run (--insecure-storage
+/// turns the refusal into a warning)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum StorageCheck {
+    #[default]
+    Enforce,
+    Insecure,
+}
+
+/// Set to print how long each phase of the run took, on stderr once it ends.
+/// Independent of -verbose, so it can be turned on alone.
+const TIMING_ENV_VAR: &str = "WSL_CLIP_BRIDGE_TIMING";
+
+fn timing_enabled() -> bool {
+    static ENABLED: OnceLock<bool> = OnceLock::new();
+    *ENABLED.get_or_init(|| env::var_os(TIMING_ENV_VAR).is_some_and(|v| !v.is_empty()))
+}
+
+/// Total time per phase, in the order phases first ran, and the phases
+/// being timed right now
+static TIMINGS: Mutex<Vec<(&'static str, Duration)>> = Mutex::new(Vec::new());
+static ACTIVE_PHASES: Mutex<Vec<&'static str>> = Mutex::new(Vec::new());
+
+/// Adds the time until it is dropped to its phase. A phase entered again
+/// while already being timed (`encode_image` calling `encode_jpeg`) counts once.
+struct PhaseTimer {
+    phase: &'static str,
+    start: Option<Instant>,
+}
+
+impl PhaseTimer {
+    fn start(phase: &'static str) -> Self {
+        let start = timing_enabled()
+            .then(|| {
+                let mut active = ACTIVE_PHASES.lock().unwrap_or_else(PoisonError::into_inner);
+                (!active.contains(&phase)).then(|| active.push(phase))
+            })
+            .flatten()
+            .map(|()| Instant::now());
+        Self { phase, start }
+    }
+}
+
+impl Drop for PhaseTimer {
+    fn drop(&mut self) {
+        let Some(start) = self.start else {
+            return;
+        };
+        let elapsed = start.elapsed();
+        ACTIVE_PHASES
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|phase| *phase != self.phase);
+        let mut timings = TIMINGS.lock().unwrap_or_else(PoisonError::into_inner);
+        match timings.iter_mut().find(|(phase, _)| *phase == self.phase) {
+            Some((_, total)) => *total += elapsed,
+            None => timings.push((self.phase, elapsed)),
+        }
+    }
+}
+
+fn print_timings(total: Duration) {
+    let timings = TIMINGS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone();
+    for (phase, elapsed) in timings {
+        eprintln!("timing: {phase} {:.3}ms", elapsed.as_secs_f64() * 1000.0);
+    }
+    eprintln!("timing: total {:.3}ms", total.as_secs_f64() * 1000.0);
+}
+
+/// Canonical name of the -selection argument, set before config is read
+static SELECTION: OnceLock<String> = OnceLock::new();
+
+fn active_selection() -> &'static str {
+    SELECTION.get().map_or("clipboard", String::as_str)
+}
+
+/// Like xclip, any prefix of a selection name picks that selection
+fn canonical_selection(name: &str) -> String {
+    match name.chars().next() {
+        Some('p') => "primary".to_string(),
+        Some('s') => "secondary".to_string(),
+        Some('c') => "clipboard".to_string(),
+        _ => name.to_string(),
+    }
 }
 
-fn parse_args() -> Args {
+#[allow(clippy::too_many_lines)]
+fn parse_args() -> Result<Args, String> {
     let mut selection = String::from("clipboard");
     let mut mime_type: Option<String> = None;
     let mut mode_output = false;
-    let mut input_file: Option<String> = None;
+    let mut input_files: Vec<String> = Vec::new();
+    let mut subcommand: Option<Subcommand> = None;
+    let mut force = false;
+    let mut json = false;
+    let mut dry_run = false;
+    let mut preserve_age = false;
+    let mut check: Option<String> = None;
+    let mut input = InputOptions::default();
+    let mut output = OutputOptions::default();
+    let mut ttl_secs: Option<u64> = None;
+    let mut default_target: Option<String> = None;
+    let mut follow_symlinks: Option<bool> = None;
+    let mut profile: Option<String> = None;
+    let mut verbosity = Verbosity::default();
+    let mut storage_check = StorageCheck::default();
+    let mut slot: Option<String> = None;
 
     let mut it = env::args().skip(1).peekable();
     while let Some(arg) = it.next() {
         match arg.as_str() {
             "-selection" => {
-                if let Some(val) = it.next() {
-                    selection = val;
-                }
+                selection = it.next().ok_or("-selection requires a value")?;
             }
             "-t" => {
-                if let Some(val) = it.next() {
-                    mime_type = Some(val);
-                }
+                mime_type = Some(it.next().ok_or("-t requires a MIME type")?);
             }
-            "-o" => {
+            "-o" | "paste" => {
                 mode_output = true;
             }
             "-i" => {
-                // optional filename after -i if next isn't a flag
-                if let Some(peek) = it.peek()
+                // optional filenames after -i, up to the next flag
+                while let Some(peek) = it.peek()
                     && !peek.starts_with('-')
                 {
-                    input_file = it.next();
+                    input_files.extend(it.next());
+                }
+            }
+            "--config-path" => {
+                subcommand = Some(Subcommand::ConfigPath);
+            }
+            "show-config" | "--show-config" => {
+                subcommand = Some(Subcommand::ShowConfig);
+            }
+            "init-config" | "--init-config" => {
+                subcommand = Some(Subcommand::InitConfig);
+            }
+            "config" => {
+                subcommand = Some(Subcommand::Config(parse_config_edit(&mut it)?));
+            }
+            "-sniff" | "--sniff" => {
+                subcommand = Some(Subcommand::Sniff);
+            }
+            "-watch" | "--watch" => {
+                subcommand = Some(Subcommand::Watch);
+            }
+            "history" => {
+                if it.next().as_deref() != Some("list") {
+                    return Err("usage: history list".to_string());
+                }
+                subcommand = Some(Subcommand::HistoryList);
+            }
+            "-index" | "--index" => {
+                let raw = it.next().ok_or("--index requires a number")?;
+                let index = raw
+                    .parse()
+                    .map_err(|_| format!("--index must be a number, got '{raw}'"))?;
+                output.index = Some(index);
+            }
+            "--force" | "-force" => {
+                force = true;
+            }
+            "-pin" | "--pin" => {
+                input.pin = true;
+            }
+            "-json" | "--json" => {
+                json = true;
+            }
+            "gc" | "-gc" | "--gc" => {
+                subcommand = Some(Subcommand::Gc { dry_run: false });
+            }
+            "-dry-run" | "--dry-run" => {
+                dry_run = true;
+            }
+            "clear" | "-clear" | "--clear" => {
+                subcommand = Some(Subcommand::Clear);
+            }
+            "touch" | "-touch" | "--touch" => {
+                subcommand = Some(Subcommand::Touch);
+            }
+            "dump" | "-dump" | "--dump" => {
+                subcommand = Some(Subcommand::Dump);
+            }
+            "restore" | "-restore" | "--restore" => {
+                subcommand = Some(Subcommand::Restore {
+                    preserve_age: false,
+                });
+            }
+            "-preserve-age" | "--preserve-age" => {
+                preserve_age = true;
+            }
+            "doctor" | "-doctor" | "--doctor" => {
+                subcommand = Some(Subcommand::Doctor);
+            }
+            "status" | "-status" | "--status" => {
+                subcommand = Some(Subcommand::Status);
+            }
+            "-check" | "--check" => {
+                check = Some(it.next().ok_or("--check requires a MIME type")?);
+            }
+            "--default-target" | "-default-target" => {
+                let target = it.next().ok_or("--default-target requires a value")?;
+                if !valid_output_target(&target) {
+                    return Err(format!(
+                        "--default-target must be one of: {}, or a MIME type",
+                        OUTPUT_TARGET_CHOICES.join(", ")
+                    ));
                 }
+                default_target = Some(target);
+            }
+            "-follow-symlinks" | "--follow-symlinks" => {
+                follow_symlinks = Some(true);
+            }
+            "-no-follow-symlinks" | "--no-follow-symlinks" => {
+                follow_symlinks = Some(false);
+            }
+            "--profile" | "-profile" => {
+                profile = Some(it.next().ok_or("--profile requires a name")?);
+            }
+            "-verbose" | "--verbose" => {
+                verbosity = Verbosity::Verbose;
+            }
+            "-quiet" | "--quiet" => {
+                verbosity = Verbosity::Quiet;
+            }
+            "-insecure-storage" | "--insecure-storage" => {
+                storage_check = StorageCheck::Insecure;
+            }
+            "-slot" | "--slot" => {
+                let name = it.next().ok_or("--slot requires a name")?;
+                validate_slot_name(&name)?;
+                slot = Some(name);
+            }
+            "-newline" | "--newline" => {
+                output.ensure_newline = true;
+            }
+            "-framed" | "--framed" => {
+                input.framed = true;
+                output.framed = true;
+            }
+            "-out-file" | "--out-file" => {
+                let path = it.next().ok_or("--out-file requires a path")?;
+                output.out_file = Some(PathBuf::from(path));
+            }
+            "--ttl" | "-ttl" => {
+                let raw = it.next().ok_or("--ttl requires a duration")?;
+                ttl_secs = Some(parse_duration(&raw)?);
+            }
+            "-rotate" => {
+                input.rotate = Some(match it.next().as_deref() {
+                    Some("90") => Rotation::Quarter,
+                    Some("180") => Rotation::Half,
+                    Some("270") => Rotation::ThreeQuarter,
+                    _ => return Err("-rotate must be 90, 180 or 270".to_string()),
+                });
+            }
+            "-flip" => {
+                input.flip = Some(match it.next().as_deref() {
+                    Some("h") => Flip::Horizontal,
+                    Some("v") => Flip::Vertical,
+                    _ => return Err("-flip must be h or v".to_string()),
+                });
             }
             _ => {
                 // ignore other args; compatibility shim
@@ -58,17 +402,424 @@ fn parse_args() -> Args {
         }
     }
 
-    Args {
+    // --dry-run may come before or after gc, --preserve-age around restore
+    match subcommand {
+        Some(Subcommand::Gc { .. }) => subcommand = Some(Subcommand::Gc { dry_run }),
+        Some(Subcommand::Restore { .. }) => {
+            subcommand = Some(Subcommand::Restore { preserve_age });
+        }
+        _ => {}
+    }
+    if output.out_file.is_some() && !mode_output {
+        return Err("--out-file only applies to -o".to_string());
+    }
+    output.force_binary = force;
+
+    Ok(Args {
         selection,
         mime_type,
         mode_output,
-        input_file,
+        input_files,
+        subcommand,
+        force,
+        json,
+        check,
+        input,
+        output,
+        ttl_secs,
+        default_target,
+        follow_symlinks,
+        profile,
+        verbosity,
+        storage_check,
+        slot,
+    })
+}
+
+/// Where the store lives, picked once per process from `storage_backend`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StorageLocation {
+    Runtime,      // $XDG_RUNTIME_DIR/wsl-clip-bridge
+    SharedMemory, // /dev/shm/wsl-clip-bridge-$UID
+    Cache,        // $XDG_CACHE_HOME/wsl-clip-bridge or ~/.cache/wsl-clip-bridge
+    Unavailable,  // storage_backend = "memory" without a usable tmpfs
+}
+
+impl fmt::Display for StorageLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Runtime => f.write_str("runtime directory"),
+            Self::SharedMemory => f.write_str("/dev/shm"),
+            Self::Cache => f.write_str("cache directory"),
+            Self::Unavailable => f.write_str("no tmpfs location"),
+        }
     }
 }
 
+/// Resolved once, since probing the tmpfs locations touches the disk
+fn storage() -> &'static (PathBuf, StorageLocation) {
+    static STORAGE: OnceLock<(PathBuf, StorageLocation)> = OnceLock::new();
+    STORAGE.get_or_init(resolve_storage)
+}
+
+/// The store of the active slot: the storage root itself, or a directory
+/// under `slots/` for a named slot
 fn get_storage_directory() -> PathBuf {
+    let root = storage_root();
+    match current_slot() {
+        Some(slot) => root.join(SLOTS_DIR).join(slot),
+        None => root,
+    }
+}
+
+fn storage_root() -> PathBuf {
+    storage().0.clone()
+}
+
+/// Named stashes beside the selections (--slot NAME), each a complete store
+/// of its own with its own lock, index, history and TTLs
+const SLOTS_DIR: &str = "slots";
+const MAX_SLOT_NAME_LEN: usize = 64;
+
+/// Slot this invocation works on; None is the default store. Only status
+/// and gc switch it, to visit every slot in turn.
+static SLOT: RwLock<Option<String>> = RwLock::new(None);
+
+fn current_slot() -> Option<String> {
+    SLOT.read().unwrap_or_else(PoisonError::into_inner).clone()
+}
+
+fn set_slot(slot: Option<String>) {
+    *SLOT.write().unwrap_or_else(PoisonError::into_inner) = slot;
+}
+
+/// Run `f` against another slot's store, then switch back
+fn with_slot<T>(slot: Option<String>, f: impl FnOnce() -> T) -> T {
+    let previous = current_slot();
+    set_slot(slot);
+    let result = f();
+    set_slot(previous);
+    result
+}
+
+/// A slot name becomes a directory name, so it is limited to letters,
+/// digits, '-' and '_': no separators or dots to climb out of the store
+fn validate_slot_name(name: &str) -> Result<(), String> {
+    let valid = name.len() <= MAX_SLOT_NAME_LEN
+        && name.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(format!(
+            "--slot name '{name}' must be up to {MAX_SLOT_NAME_LEN} letters, digits, '-' or '_', starting with a letter or digit"
+        ))
+    }
+}
+
+/// Names of the slots that exist under the storage root, sorted
+fn slot_names() -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(storage_root().join(SLOTS_DIR))
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(|entry| entry.file_type().is_ok_and(|t| t.is_dir()))
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| validate_slot_name(name).is_ok())
+        .collect();
+    names.sort();
+    names
+}
+
+fn storage_location() -> StorageLocation {
+    storage().1
+}
+
+/// The configured `storage_backend`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StorageBackend {
+    Cache,
+    Runtime,
+    /// Like `Runtime`, but only ever on tmpfs, with `secure_delete` implied
+    Memory,
+}
+
+fn storage_backend() -> StorageBackend {
+    let config = load_config();
+    match config.storage_backend.as_deref() {
+        Some("runtime") => StorageBackend::Runtime,
+        Some("memory") => StorageBackend::Memory,
+        Some("cache") => StorageBackend::Cache,
+        Some(other) => {
+            eprintln!("Warning: Unknown storage_backend {other:?}; using the cache directory");
+            StorageBackend::Cache
+        }
+        // prefer_runtime_dir is the older spelling of storage_backend = "runtime"
+        None if config
+            .prefer_runtime_dir
+            .unwrap_or(DEFAULT_PREFER_RUNTIME_DIR) =>
+        {
+            StorageBackend::Runtime
+        }
+        None => StorageBackend::Cache,
+    }
+}
+
+/// With the runtime backend: `$XDG_RUNTIME_DIR` first, then `/dev/shm`, then
+/// the cache directory. Only the chosen location is ever read or written, so
+/// a copy and a paste agree as long as they see the same environment; doctor
+/// points out content left behind in the others. The memory backend takes
+/// either location only on tmpfs and never falls back to the disk: without
+/// one, copies and pastes are refused.
+fn resolve_storage() -> (PathBuf, StorageLocation) {
+    let backend = storage_backend();
+    if backend != StorageBackend::Cache {
+        let memory_only = backend == StorageBackend::Memory;
+        if let Some(dir) = runtime_storage_directory(memory_only) {
+            return (dir, StorageLocation::Runtime);
+        }
+        if let Some(dir) = shm_storage_directory(memory_only) {
+            return (dir, StorageLocation::SharedMemory);
+        }
+        if memory_only {
+            if verbose() {
+                eprintln!(
+                    "No tmpfs location is usable; storage_backend = \"memory\" won't use the disk"
+                );
+            }
+            let dir = runtime_storage_path()
+                .or_else(shm_storage_path)
+                .unwrap_or_else(homeless_directory);
+            return (dir, StorageLocation::Unavailable);
+        }
+        if verbose() {
+            eprintln!("No tmpfs location is usable; using the cache directory");
+        }
+    }
+    (cache_storage_directory(), StorageLocation::Cache)
+}
+
+fn runtime_storage_path() -> Option<PathBuf> {
+    let runtime = env::var_os("XDG_RUNTIME_DIR").filter(|dir| !dir.is_empty())?;
+    Some(PathBuf::from(runtime).join("wsl-clip-bridge"))
+}
+
+fn shm_storage_path() -> Option<PathBuf> {
+    Some(PathBuf::from(format!(
+        "/dev/shm/wsl-clip-bridge-{}",
+        current_uid()?
+    )))
+}
+
+/// `$XDG_RUNTIME_DIR/wsl-clip-bridge`, if that directory exists, belongs to
+/// this user and takes writes (and with `memory_only`, is on tmpfs)
+fn runtime_storage_directory(memory_only: bool) -> Option<PathBuf> {
+    let usable = |dir: &PathBuf| {
+        dir.parent().is_some_and(|runtime| {
+            runtime.is_dir() && owned_by_user(runtime) && (!memory_only || on_tmpfs(runtime))
+        })
+    };
+    let Some(dir) = runtime_storage_path().filter(usable) else {
+        if verbose() {
+            eprintln!("XDG_RUNTIME_DIR is not available; trying /dev/shm");
+        }
+        return None;
+    };
+    claim_storage_directory(&dir).then_some(dir)
+}
+
+/// `/dev/shm/wsl-clip-bridge-$UID`. /dev/shm is shared by every user, so a
+/// directory someone else created there (or a symlink) is never used.
+fn shm_storage_directory(memory_only: bool) -> Option<PathBuf> {
+    let shm = Path::new("/dev/shm");
+    if !shm.is_dir() || (memory_only && !on_tmpfs(shm)) {
+        return None;
+    }
+    let dir = shm_storage_path()?;
+    claim_storage_directory(&dir).then_some(dir)
+}
+
+/// Create or reuse a storage directory only if it is a real directory owned
+/// by this user that others can't write to, and a probe write succeeds
+fn claim_storage_directory(dir: &Path) -> bool {
+    if create_storage_directory(dir).is_err() {
+        return false;
+    }
+    #[cfg(unix)]
+    {
+        let safe = fs::symlink_metadata(dir)
+            .is_ok_and(|m| m.is_dir() && m.permissions().mode() & 0o022 == 0)
+            && owned_by_user(dir);
+        if !safe {
+            if verbose() {
+                eprintln!(
+                    "'{}' is not a private directory; skipping it",
+                    dir.display()
+                );
+            }
+            return false;
+        }
+    }
+    let probe = dir.join(format!(".probe.{}", std::process::id()));
+    let writable = File::create(&probe).is_ok();
+    let _ = fs::remove_file(&probe);
+    if !writable && verbose() {
+        eprintln!("'{}' is not writable; skipping it", dir.display());
+    }
+    writable
+}
+
+/// Check that an existing storage directory is one this user made: a real
+/// directory (not a symlink), owned by them, and not writable by others
+/// beyond what `dir_mode` grants. A directory that doesn't exist yet is
+/// fine; it is created private.
+#[cfg(unix)]
+fn verify_storage_directory(dir: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(dir) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let refuse = |reason: String| {
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("Storage directory '{}' {reason}", dir.display()),
+        ))
+    };
+    if metadata.file_type().is_symlink() {
+        return refuse("is a symlink".to_string());
+    }
+    if !metadata.is_dir() {
+        return refuse("is not a directory".to_string());
+    }
+    match current_uid() {
+        Some(uid) if metadata.uid() != uid => {
+            return refuse(format!("is owned by uid {}, not {uid}", metadata.uid()));
+        }
+        Some(_) => {}
+        None => return refuse("has an owner that can't be checked".to_string()),
+    }
+    let mode = metadata.permissions().mode() & 0o777;
+    if mode & 0o022 & !storage_dir_mode() != 0 {
+        return refuse(format!("is writable by other users (mode {mode:04o})"));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+#[allow(clippy::unnecessary_wraps)]
+const fn verify_storage_directory(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Refuse to run against a storage directory someone else could have
+/// planted or can tamper with, unless --insecure-storage says to go ahead
+fn check_storage_security(check: StorageCheck) -> Option<i32> {
+    // Not something --insecure-storage can wave through
+    if storage_location() == StorageLocation::Unavailable {
+        eprintln!(
+            "Error: storage_backend = \"memory\" but no tmpfs location is usable; \
+             refusing to store anything on disk"
+        );
+        return Some(EXIT_INSECURE_STORAGE);
+    }
+    let root = storage_root();
+    let mut dirs = vec![root.clone()];
+    if current_slot().is_some() {
+        dirs.push(root.join(SLOTS_DIR));
+        dirs.push(get_storage_directory());
+    }
+    let Some(Err(e)) = dirs
+        .iter()
+        .map(|dir| verify_storage_directory(dir))
+        .find(Result::is_err)
+    else {
+        return None;
+    };
+    if check == StorageCheck::Insecure {
+        if !quiet() {
+            eprintln!("Warning: {e}; using it anyway (--insecure-storage)");
+        }
+        return None;
+    }
+    eprintln!("Error: {e}; refusing to use it (override with --insecure-storage)");
+    Some(EXIT_INSECURE_STORAGE)
+}
+
+/// The real user ID, from /proc/self/status. The owner of /proc/self is the
+/// effective ID instead (or root, for a process that isn't dumpable).
+#[cfg(unix)]
+fn current_uid() -> Option<u32> {
+    real_uid(&fs::read_to_string("/proc/self/status").ok()?)
+}
+
+/// First of the real, effective, saved and filesystem IDs on the `Uid:` line
+#[cfg(unix)]
+fn real_uid(status: &str) -> Option<u32> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Uid:"))?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+#[cfg(not(unix))]
+const fn current_uid() -> Option<u32> {
+    None
+}
+
+#[cfg(unix)]
+fn owned_by_user(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok_and(|m| Some(m.uid()) == current_uid())
+}
+
+#[cfg(not(unix))]
+const fn owned_by_user(_path: &Path) -> bool {
+    true
+}
+
+/// Filesystem type of the mount holding `path` (e.g. "tmpfs", "ext4"), from
+/// /proc/self/mounts
+fn filesystem_type(path: &Path) -> Option<String> {
+    let path = fs::canonicalize(path).ok()?;
+    let mounts = fs::read_to_string("/proc/self/mounts").ok()?;
+    // The last of several mounts on one point is the one in effect
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let point = unescape_mount_field(fields.nth(1)?);
+            Some((point, fields.next()?.to_string()))
+        })
+        .filter(|(point, _)| path.starts_with(point))
+        .max_by_key(|(point, _)| point.as_os_str().len())
+        .map(|(_, kind)| kind)
+}
+
+/// Whether `path` is on a filesystem held in memory
+fn on_tmpfs(path: &Path) -> bool {
+    matches!(filesystem_type(path).as_deref(), Some("tmpfs" | "ramfs"))
+}
+
+/// /proc/self/mounts writes space, tab, newline and backslash as octal
+fn unescape_mount_field(field: &str) -> PathBuf {
+    PathBuf::from(
+        field
+            .replace("\\040", " ")
+            .replace("\\011", "\t")
+            .replace("\\012", "\n")
+            .replace("\\134", "\\"),
+    )
+}
+
+fn cache_storage_directory() -> PathBuf {
     // For WSL, prefer ~/.cache as it's more reliable and predictable
     // WSL's /run/user/ isn't always tmpfs and may not exist
+    // (storage_backend = "runtime" opts in to it where it is)
 
     // First try XDG_CACHE_HOME if set
     if let Ok(xdg_cache) = env::var("XDG_CACHE_HOME")
@@ -78,12 +829,25 @@ fn get_storage_directory() -> PathBuf {
     }
 
     // Use ~/.cache (most reliable for WSL)
-    if let Ok(home) = env::var("HOME") {
+    if let Some(home) = home_dir() {
         return PathBuf::from(home).join(".cache").join("wsl-clip-bridge");
     }
 
-    // Fall back to /tmp with UID for isolation
-    let uid = env::var("UID").unwrap_or_else(|_| "unknown".to_string());
+    homeless_directory()
+}
+
+/// $HOME, or None if it is unset or empty. An empty one would put the
+/// store under `./.cache` and turn a configured `~/x` into `/x`.
+fn home_dir() -> Option<String> {
+    env::var("HOME").ok().filter(|home| !home.trim().is_empty())
+}
+
+/// Where both the store and the config live without $HOME (or XDG
+/// overrides): one directory under /tmp per user. Startup refuses it unless
+/// it is this user's and private, which covers the config in it too.
+fn homeless_directory() -> PathBuf {
+    // $UID is a shell variable that is rarely exported, so ask the kernel
+    let uid = current_uid().map_or_else(|| "unknown".to_string(), |uid| uid.to_string());
     PathBuf::from(format!("/tmp/wsl-clip-bridge-{uid}"))
 }
 
@@ -91,6 +855,8 @@ fn get_image_path() -> PathBuf {
     get_storage_directory().join("image.bin")
 }
 
+/// Sidecar that held the image's MIME type before the index; still read
+/// from older stores and history entries
 fn get_image_format_path() -> PathBuf {
     get_storage_directory().join("image.format")
 }
@@ -99,509 +865,7621 @@ fn get_text_path() -> PathBuf {
     get_storage_directory().join("text.txt")
 }
 
+fn get_archive_path() -> PathBuf {
+    get_storage_directory().join("archive.tar")
+}
+
+fn get_uri_list_path() -> PathBuf {
+    get_storage_directory().join("uri-list.txt")
+}
+
+/// Writes land here first and are renamed into place once complete, so an
+/// interrupted copy never leaves a partial file in the live store
+fn get_spool_directory() -> PathBuf {
+    get_storage_directory().join("spool")
+}
+
+/// The payloads a store (or a history entry) can hold
+fn payload_paths() -> [PathBuf; 4] {
+    [
+        get_text_path(),
+        get_image_path(),
+        get_archive_path(),
+        get_uri_list_path(),
+    ]
+}
+
+/// Advisory lock file guarding the stored files
+fn get_lock_path() -> PathBuf {
+    get_storage_directory().join("lock")
+}
+
+/// Marker file present while the stored content is pinned
+fn get_pin_path() -> PathBuf {
+    get_storage_directory().join("pinned")
+}
+
+/// Pinned content never expires and is only replaced by a copy with -force
+fn is_pinned() -> bool {
+    get_pin_path().exists()
+}
+
+/// Pin or unpin the store after a successful copy
+fn set_pinned(pinned: bool) -> io::Result<()> {
+    let pin_path = get_pin_path();
+    if pinned {
+        ensure_storage_directory()?;
+        write_stored(&pin_path, b"")?;
+    } else if pin_path.exists() {
+        fs::remove_file(&pin_path)?;
+    }
+    Ok(())
+}
+
 fn ensure_storage_directory() -> io::Result<()> {
-    let dir = get_storage_directory();
+    create_storage_directory(&get_storage_directory())
+}
+
+fn create_storage_directory(dir: &Path) -> io::Result<()> {
     if !dir.exists() {
-        fs::create_dir_all(&dir)?;
+        let mut builder = fs::DirBuilder::new();
+        builder.recursive(true);
+        // A slot's store also creates `slots/`, which must be as private
+        #[cfg(unix)]
+        builder.mode(storage_dir_mode());
+        builder.create(dir)?;
         // restrict perms to user on unix
         #[cfg(unix)]
         {
-            let _ = fs::set_permissions(&dir, fs::Permissions::from_mode(0o700));
+            let _ = fs::set_permissions(dir, fs::Permissions::from_mode(storage_dir_mode()));
         }
     }
     Ok(())
 }
 
-fn is_file_non_empty(path: &Path) -> bool {
-    fs::metadata(path).is_ok_and(|m| m.is_file() && m.len() > 0)
+/// Spool `data` and atomically promote it to `dest`. A write that fails
+/// part-way leaves its spool file behind for cleanup to reclaim.
+fn write_stored(dest: &Path, data: &[u8]) -> io::Result<()> {
+    let _timer = PhaseTimer::start("write");
+    let spooled = spool_file(dest, data)?;
+    let _lock = StoreLock::acquire(LockMode::Exclusive)?;
+    replace_file(&spooled, dest)
 }
 
-fn print_targets() {
-    let ttl = load_ttl();
-    let mut printed = HashSet::new();
+/// Store a copied payload and its index entry
+fn write_stored_entry(dest: &Path, data: &[u8], mime: &str) -> io::Result<()> {
+    store_payload(dest, data, mime, true)
+}
 
-    // Check file-based targets (existing logic)
-    let image_path = get_image_path();
-    if is_file_fresh(&image_path, ttl) {
-        if let Ok(format) = fs::read_to_string(get_image_format_path()) {
-            let format = format.trim();
-            println!("{format}");
-            printed.insert(format.to_string());
-            // Also output jpg alias for jpeg
-            if format == "image/jpeg" {
-                println!("image/jpg");
-                printed.insert("image/jpg".to_string());
-            }
-        }
-    } else if image_path.exists() {
-        // Clean up expired image files
-        let _ = fs::remove_file(&image_path);
-        let _ = fs::remove_file(get_image_format_path());
+/// Store a payload primed now, unless it is a copy of what is stored already
+fn store_payload(dest: &Path, data: &[u8], mime: &str, copied: bool) -> io::Result<()> {
+    let entry = IndexEntry {
+        mime: mime.to_string(),
+        primed: now_millis(),
+        checksum: None,
+        content: None,
+        // Caching a wl-clipboard image isn't a copy from a file
+        source: copied.then(|| COPY_SOURCE.get().cloned()).flatten(),
+    };
+    if copied && keep_identical(dest, data, &entry)? {
+        return Ok(());
     }
+    install_payload(dest, data, entry, copied)
+}
 
-    // Add wl-clipboard targets
-    if wl_clipboard_available()
-        && let Ok(types) = get_wl_clipboard_types()
+/// Whether the fresh payload at `dest` already holds `data` as `entry`
+/// describes it. Then it isn't rewritten or added to history again, and
+/// with `refresh_ttl_on_identical` its TTL restarts as if it had been.
+fn keep_identical(dest: &Path, data: &[u8], entry: &IndexEntry) -> io::Result<bool> {
+    let Some(stored) = index_entry(dest) else {
+        return Ok(false);
+    };
+    if stored.mime != entry.mime
+        || stored.source != entry.source
+        || stored.content.as_deref() != Some(payload_checksum(data).as_str())
+        || !is_file_fresh(dest, load_ttl())
     {
-        for typ in types {
-            match typ.as_str() {
-                "image/bmp" => {
-                    // Only advertise PNG conversion for BMP
-                    if !printed.contains("image/png") {
-                        println!("image/png");
-                        printed.insert("image/png".to_string());
-                    }
-                }
-                "image/png" | "image/jpeg" | "image/gif" | "image/webp" => {
-                    if !printed.contains(&typ) {
-                        println!("{typ}");
-                        printed.insert(typ.clone());
-                        if typ == "image/jpeg" && !printed.contains("image/jpg") {
-                            println!("image/jpg");
-                            printed.insert("image/jpg".to_string());
-                        }
-                    }
-                }
-                t if t.starts_with("text/") => {
-                    if printed.insert(typ.clone()) {
-                        println!("{typ}");
+        return Ok(false);
+    }
+    // A matching checksum only makes it likely; the bytes decide
+    let same = {
+        let _lock = StoreLock::acquire(LockMode::Shared)?;
+        read_payload(dest).is_ok_and(|stored| stored == data)
+    };
+    if !same {
+        return Ok(false);
+    }
+    let refresh = load_config()
+        .refresh_ttl_on_identical
+        .unwrap_or(DEFAULT_REFRESH_TTL_ON_IDENTICAL);
+    if refresh {
+        let name = payload_name(dest)?;
+        let _lock = StoreLock::acquire(LockMode::Exclusive)?;
+        update_index(|index| {
+            if let Some(entry) = index.entries.get_mut(&name) {
+                entry.primed = now_millis();
+            }
+        })?;
+    }
+    if verbose() {
+        eprintln!(
+            "Content is unchanged; kept '{}'{}",
+            dest.display(),
+            if refresh {
+                " and restarted its TTL"
+            } else {
+                ""
+            }
+        );
+    }
+    Ok(true)
+}
+
+/// Spool a payload, then under the exclusive lock write its index entry and
+/// rename it into place. The index goes first, so a reader that sees the new
+/// payload also sees its entry. Copies are recorded in history.
+fn install_payload(
+    dest: &Path,
+    data: &[u8],
+    mut entry: IndexEntry,
+    copied: bool,
+) -> io::Result<()> {
+    entry.content = Some(payload_checksum(data));
+    let data = encrypt_payload(dest, encode_payload(data)?)?;
+    let _timer = PhaseTimer::start("write");
+    let name = payload_name(dest)?;
+    entry.checksum = Some(payload_checksum(&data));
+    let spooled = spool_file(dest, &data)?;
+    let _lock = StoreLock::acquire(LockMode::Exclusive)?;
+    if let Err(e) = make_room(dest, data.len() as u64) {
+        let _ = fs::remove_file(&spooled);
+        return Err(e);
+    }
+    update_index(|index| {
+        index.entries.insert(name, entry);
+    })?;
+    replace_file(&spooled, dest)?;
+    if copied {
+        record_history(dest);
+    }
+    Ok(())
+}
+
+/// `max_storage_mb` in bytes, or None when storage is unlimited
+fn storage_quota() -> Option<u64> {
+    match load_config()
+        .max_storage_mb
+        .unwrap_or(DEFAULT_MAX_STORAGE_MB)
+    {
+        0 => None,
+        mb => Some(mb.saturating_mul(1024 * 1024)),
+    }
+}
+
+/// Bytes stored under the storage root, every slot and history included.
+/// Hard links (history shares data with the current copy) count once, and
+/// spooled files about to be renamed into place don't count yet.
+fn storage_usage() -> u64 {
+    let root = storage_root();
+    let mut skip = vec![root.join("spool")];
+    skip.extend(
+        slot_names()
+            .into_iter()
+            .map(|name| root.join(SLOTS_DIR).join(name).join("spool")),
+    );
+    disk_usage(&root, &skip, &mut HashSet::new())
+}
+
+fn disk_usage(dir: &Path, skip: &[PathBuf], seen: &mut HashSet<(u64, u64)>) -> u64 {
+    let Ok(children) = fs::read_dir(dir) else {
+        return 0;
+    };
+    let mut total = 0;
+    for child in children.flatten() {
+        let path = child.path();
+        let Ok(metadata) = fs::symlink_metadata(&path) else {
+            continue;
+        };
+        if metadata.is_dir() {
+            if !skip.contains(&path) {
+                total += disk_usage(&path, skip, seen);
+            }
+            continue;
+        }
+        #[cfg(unix)]
+        if !seen.insert((metadata.dev(), metadata.ino())) {
+            continue;
+        }
+        total += metadata.len();
+    }
+    total
+}
+
+/// Before `dest` takes `incoming` bytes, evict from the active store until
+/// the storage root fits `max_storage_mb`: expired entries first, then the
+/// oldest history entries and other stored types. A pinned store's current
+/// content stays. Runs under the store's exclusive lock.
+fn make_room(dest: &Path, incoming: u64) -> io::Result<()> {
+    let Some(quota) = storage_quota() else {
+        return Ok(());
+    };
+    if incoming > quota {
+        return Err(io::Error::new(
+            io::ErrorKind::FileTooLarge,
+            format!(
+                "Content of {incoming} bytes exceeds max_storage_mb ({quota} bytes) on its own"
+            ),
+        ));
+    }
+    // What the rename frees: the old payload, unless history still links it
+    let freed = || {
+        fs::symlink_metadata(dest).map_or(0, |m| {
+            #[cfg(unix)]
+            if m.nlink() > 1 {
+                return 0;
+            }
+            m.len()
+        })
+    };
+    let fits = |usage: u64| usage.saturating_sub(freed()).saturating_add(incoming) <= quota;
+
+    let mut usage = storage_usage();
+    let mut evicted_payload = false;
+    for victim in eviction_candidates(dest) {
+        if fits(usage) {
+            break;
+        }
+        let removed = if victim.is_dir() {
+            remove_stored_dir(&victim)
+        } else {
+            evicted_payload = true;
+            entry_files(&victim)
+                .iter()
+                .filter(|path| path.exists())
+                .try_for_each(|path| remove_stored(path))
+        };
+        if let Err(e) = removed {
+            eprintln!("Warning: Could not evict '{}': {e}", victim.display());
+            continue;
+        }
+        let before = usage;
+        usage = storage_usage();
+        if verbose() {
+            eprintln!(
+                "Evicted {} ({} bytes) to stay within max_storage_mb",
+                victim.display(),
+                before.saturating_sub(usage)
+            );
+        }
+    }
+    if evicted_payload {
+        prune_index()?;
+    }
+    if fits(usage) {
+        return Ok(());
+    }
+    Err(io::Error::new(
+        io::ErrorKind::FileTooLarge,
+        format!(
+            "Storage is full: {usage} of max_storage_mb ({quota} bytes) in use and {incoming} more \
+             needed; the rest is pinned or in other slots"
+        ),
+    ))
+}
+
+/// What `make_room` may evict from the active store, in order: expired
+/// payloads and history entries, then the rest oldest first
+fn eviction_candidates(dest: &Path) -> Vec<PathBuf> {
+    let ttl = load_ttl();
+    let mut expired = Vec::new();
+    let mut live = Vec::new();
+    if !is_pinned() {
+        for path in payload_paths() {
+            if path == dest || !path.exists() {
+                continue;
+            }
+            if is_file_fresh(&path, ttl) {
+                live.push((primed_at(&path).unwrap_or(SystemTime::UNIX_EPOCH), path));
+            } else {
+                expired.push(path);
+            }
+        }
+    }
+    for entry in history_entries() {
+        if is_file_fresh(&entry.payload, ttl) {
+            live.push((entry.primed, entry.dir));
+        } else {
+            expired.push(entry.dir);
+        }
+    }
+    live.sort_by_key(|(primed, _)| *primed);
+    expired
+        .into_iter()
+        .chain(live.into_iter().map(|(_, path)| path))
+        .collect()
+}
+
+/// Rename a spooled file over `dest`. The rename drops the previous
+/// content, so that is wiped first.
+fn replace_file(spooled: &Path, dest: &Path) -> io::Result<()> {
+    if let Err(e) = wipe_file(dest) {
+        eprintln!("Warning: Could not wipe '{}': {e}", dest.display());
+    }
+    fs::rename(spooled, dest)?;
+    dest.parent().map_or(Ok(()), sync_directory)
+}
+
+/// Flush a directory after a rename into it when `durable_writes` is on,
+/// so the new entry survives a crash or `wsl --shutdown`. Spooled files
+/// are synced before they are renamed either way.
+fn sync_directory(dir: &Path) -> io::Result<()> {
+    if !load_config()
+        .durable_writes
+        .unwrap_or(DEFAULT_DURABLE_WRITES)
+    {
+        return Ok(());
+    }
+    #[cfg(unix)]
+    File::open(dir)
+        .and_then(|handle| handle.sync_all())
+        .map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Could not flush '{}': {e}", dir.display()),
+            )
+        })?;
+    Ok(())
+}
+
+/// Overwrite a stored file with zeros and flush it to disk when
+/// `secure_delete` is on, so its bytes don't outlive the unlink. A file
+/// still linked from history keeps its data, since that copy is still live.
+fn wipe_file(path: &Path) -> io::Result<()> {
+    if !secure_delete() {
+        return Ok(());
+    }
+    zero_file(path)
+}
+
+/// The overwrite behind `wipe_file`, whatever `secure_delete` says
+fn zero_file(path: &Path) -> io::Result<()> {
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return Ok(());
+    };
+    #[cfg(unix)]
+    if metadata.nlink() > 1 {
+        return Ok(());
+    }
+    if !metadata.is_file() || metadata.len() == 0 {
+        return Ok(());
+    }
+    let mut file = open_stored(path, OpenOptions::new().write(true))?;
+    let zeros = [0u8; 8 * 1024];
+    let mut remaining = metadata.len();
+    while remaining > 0 {
+        let chunk = usize::try_from(remaining).map_or(zeros.len(), |n| n.min(zeros.len()));
+        file.write_all(&zeros[..chunk])?;
+        remaining -= chunk as u64;
+    }
+    file.sync_all()
+}
+
+/// `secure_delete`, which the memory backend turns on unless it is set
+fn secure_delete() -> bool {
+    load_config()
+        .secure_delete
+        .unwrap_or_else(|| storage_backend() == StorageBackend::Memory || DEFAULT_SECURE_DELETE)
+}
+
+/// Remove a stored file, wiping it first if `secure_delete` is on
+fn remove_stored(path: &Path) -> io::Result<()> {
+    if let Err(e) = wipe_file(path) {
+        eprintln!("Warning: Could not wipe '{}': {e}", path.display());
+    }
+    fs::remove_file(path)
+}
+
+/// Remove a directory of stored files (a history entry, a scratch
+/// directory), wiping each file first if `secure_delete` is on
+fn remove_stored_dir(dir: &Path) -> io::Result<()> {
+    if let Ok(entries) = fs::read_dir(dir) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                remove_stored_dir(&path)?;
+            } else {
+                remove_stored(&path)?;
+            }
+        }
+    }
+    fs::remove_dir_all(dir)
+}
+
+/// Write `data` completely into a spool file named after `dest`, ready to be
+/// renamed into place
+fn spool_file(dest: &Path, data: &[u8]) -> io::Result<PathBuf> {
+    let spool_dir = get_spool_directory();
+    if !spool_dir.exists() {
+        fs::create_dir_all(&spool_dir)?;
+        #[cfg(unix)]
+        {
+            let _ = fs::set_permissions(&spool_dir, fs::Permissions::from_mode(storage_dir_mode()));
+        }
+    }
+    let name = dest
+        .file_name()
+        .unwrap_or(dest.as_os_str())
+        .to_string_lossy();
+    let spool_path = spool_dir.join(format!("{name}.{}", std::process::id()));
+
+    // Left by an earlier process with this pid; removing it (even a
+    // symlink) lets the exclusive create below succeed
+    let _ = fs::remove_file(&spool_path);
+    let mut file = open_stored(&spool_path, OpenOptions::new().write(true).create_new(true))?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    drop(file);
+    // restrict perms to user on unix, before the file becomes visible
+    #[cfg(unix)]
+    {
+        let _ = fs::set_permissions(&spool_path, fs::Permissions::from_mode(storage_file_mode()));
+    }
+    Ok(spool_path)
+}
+
+/// Open a file in the store without following a symlink in its place, then
+/// check that what was opened is a regular file owned by this user.
+/// Otherwise a planted `text.txt -> ~/.bashrc` could be pasted or wiped.
+/// `O_NONBLOCK` keeps a FIFO in its place from stalling the open.
+fn open_stored(path: &Path, options: &mut OpenOptions) -> io::Result<File> {
+    #[cfg(unix)]
+    options.custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK);
+    let file = options.open(path).map_err(|e| {
+        #[cfg(unix)]
+        if e.raw_os_error() == Some(libc::ELOOP) {
+            return unsafe_stored_file(path, "is a symlink");
+        }
+        e
+    })?;
+    let metadata = file.metadata()?;
+    if !metadata.is_file() {
+        return Err(unsafe_stored_file(path, "is not a regular file"));
+    }
+    #[cfg(unix)]
+    if Some(metadata.uid()) != current_uid() {
+        return Err(unsafe_stored_file(path, "belongs to another user"));
+    }
+    Ok(file)
+}
+
+fn unsafe_stored_file(path: &Path, reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!("Refusing to use '{}': it {reason}", path.display()),
+    )
+}
+
+fn read_stored(path: &Path) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    open_stored(path, OpenOptions::new().read(true))?.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// A small text file from the store (a legacy sidecar), if it is readable
+fn read_stored_text(path: &Path) -> Option<String> {
+    String::from_utf8(read_stored(path).ok()?).ok()
+}
+
+/// Store an image under its canonical MIME type. Only copies go into
+/// history, not images cached from wl-clipboard.
+fn write_stored_image(image_path: &Path, data: &[u8], mime: &str, copied: bool) -> io::Result<()> {
+    store_payload(image_path, data, mime, copied)
+}
+
+/// Marks a payload stored compressed: this magic, the original length as a
+/// little-endian u64, then one zstd frame. Anything else is stored as is.
+const COMPRESSED_MAGIC: &[u8] = b"\0WCBZST\0";
+const COMPRESSED_HEADER_LEN: usize = COMPRESSED_MAGIC.len() + 8;
+const COMPRESSION_LEVEL: i32 = 3; // zstd's own default
+// Inputs larger than this are sampled before compressing all of them
+const COMPRESSION_SAMPLE: usize = 64 * 1024;
+
+/// A payload as it is written to disk: zstd-compressed when
+/// `compress_storage` is on and it pays off. Data that happens to start with
+/// either magic is always wrapped, so it can't be mistaken for a compressed
+/// or encrypted file.
+fn encode_payload(data: &[u8]) -> io::Result<Cow<'_, [u8]>> {
+    let _timer = PhaseTimer::start("compress");
+    let wanted = load_config()
+        .compress_storage
+        .unwrap_or(DEFAULT_COMPRESS_STORAGE)
+        && worth_compressing(data);
+    let ambiguous = data.starts_with(COMPRESSED_MAGIC) || data.starts_with(ENCRYPTED_MAGIC);
+    if !wanted && !ambiguous {
+        return Ok(Cow::Borrowed(data));
+    }
+    let frame = zstd::bulk::compress(data, COMPRESSION_LEVEL)?;
+    if !ambiguous && COMPRESSED_HEADER_LEN + frame.len() >= data.len() {
+        return Ok(Cow::Borrowed(data));
+    }
+    let mut stored = Vec::with_capacity(COMPRESSED_HEADER_LEN + frame.len());
+    stored.extend_from_slice(COMPRESSED_MAGIC);
+    stored.extend_from_slice(&(data.len() as u64).to_le_bytes());
+    stored.extend_from_slice(&frame);
+    Ok(Cow::Owned(stored))
+}
+
+/// Already-compressed data (PNG, JPEG, tarballs of archives) barely shrinks,
+/// so large inputs are only compressed when a leading sample does
+fn worth_compressing(data: &[u8]) -> bool {
+    if data.len() <= COMPRESSION_SAMPLE {
+        return true; // The result is compared against the input anyway
+    }
+    zstd::bulk::compress(&data[..COMPRESSION_SAMPLE], 1)
+        .is_ok_and(|sample| sample.len() < COMPRESSION_SAMPLE / 10 * 9)
+}
+
+/// A stored payload's original bytes, decompressing it if needed
+fn read_payload(path: &Path) -> io::Result<Vec<u8>> {
+    let _timer = PhaseTimer::start("read");
+    let stored = read_stored(path)?;
+    check_integrity(path, &stored)?;
+    let stored = decrypt_payload(path, stored)?;
+    let Some(length) = compressed_length(&stored) else {
+        return Ok(stored);
+    };
+    let data = zstd::stream::decode_all(&stored[COMPRESSED_HEADER_LEN..]).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("'{}' is corrupt ({e})", path.display()),
+        )
+    })?;
+    if data.len() as u64 != length {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("'{}' is truncated or corrupt", path.display()),
+        ));
+    }
+    Ok(data)
+}
+
+/// Original length recorded in a compressed payload's header
+fn compressed_length(stored: &[u8]) -> Option<u64> {
+    let length = stored.strip_prefix(COMPRESSED_MAGIC)?.first_chunk::<8>()?;
+    Some(u64::from_le_bytes(*length))
+}
+
+/// Size of a stored payload before compression, without decompressing it.
+/// An encrypted payload has to be decrypted to find out.
+fn payload_size(path: &Path) -> u64 {
+    let mut header = Vec::with_capacity(COMPRESSED_HEADER_LEN);
+    let _ = open_stored(path, OpenOptions::new().read(true)).and_then(|f| {
+        f.take(COMPRESSED_HEADER_LEN as u64)
+            .read_to_end(&mut header)
+    });
+    let stored_len = || fs::metadata(path).map_or(0, |m| m.len());
+    if header.starts_with(ENCRYPTED_MAGIC) {
+        return read_payload(path).map_or_else(|_| stored_len(), |data| data.len() as u64);
+    }
+    compressed_length(&header).unwrap_or_else(stored_len)
+}
+
+/// Marks a payload stored encrypted: this magic, a random 24-byte nonce,
+/// then the XChaCha20-Poly1305 ciphertext and tag. What gets encrypted is
+/// the payload as `encode_payload` left it, so compression still pays off.
+const ENCRYPTED_MAGIC: &[u8] = b"\0WCBENC\0";
+const NONCE_LEN: usize = 24;
+const KEY_LEN: usize = 32;
+const KEY_FILE_ENV_VAR: &str = "WSL_CLIP_BRIDGE_KEY_FILE";
+
+fn encrypts_storage() -> bool {
+    load_config()
+        .encrypt_storage
+        .unwrap_or(DEFAULT_ENCRYPT_STORAGE)
+}
+
+/// The key from `WSL_CLIP_BRIDGE_KEY_FILE`, read once per run
+fn storage_key() -> Result<&'static Key, String> {
+    static KEY: OnceLock<Result<Key, String>> = OnceLock::new();
+    KEY.get_or_init(load_storage_key)
+        .as_ref()
+        .map_err(Clone::clone)
+}
+
+fn load_storage_key() -> Result<Key, String> {
+    let path = env::var_os(KEY_FILE_ENV_VAR)
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+        .ok_or_else(|| format!("{KEY_FILE_ENV_VAR} is not set"))?;
+    let key = fs::read(&path)
+        .map_err(|e| format!("could not read key file '{}': {e}", path.display()))?;
+    if key.len() != KEY_LEN {
+        return Err(format!(
+            "key file '{}' holds {} bytes, not {KEY_LEN}",
+            path.display(),
+            key.len()
+        ));
+    }
+    #[cfg(unix)]
+    if fs::metadata(&path).is_ok_and(|m| m.permissions().mode() & 0o077 != 0) {
+        eprintln!(
+            "Warning: Key file '{}' is readable by other users",
+            path.display()
+        );
+    }
+    Ok(*Key::from_slice(&key))
+}
+
+/// The payload's file name is bound in as associated data, so an encrypted
+/// image can't be swapped in as `text.txt`
+fn payload_aad(path: &Path) -> Vec<u8> {
+    path.file_name()
+        .map_or_else(Vec::new, |name| name.as_encoded_bytes().to_vec())
+}
+
+/// Encrypt an encoded payload when `encrypt_storage` is on. Without a usable
+/// key the copy fails rather than storing plaintext.
+fn encrypt_payload<'a>(dest: &Path, data: Cow<'a, [u8]>) -> io::Result<Cow<'a, [u8]>> {
+    if !encrypts_storage() {
+        return Ok(data);
+    }
+    let _timer = PhaseTimer::start("encrypt");
+    let key = storage_key().map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("encrypt_storage is on, but {e}"),
+        )
+    })?;
+    let mut nonce = [0u8; NONCE_LEN];
+    getrandom::fill(&mut nonce)
+        .map_err(|e| io::Error::other(format!("Could not generate a nonce: {e}")))?;
+    let sealed = XChaCha20Poly1305::new(key)
+        .encrypt(
+            XNonce::from_slice(&nonce),
+            Payload {
+                msg: &data,
+                aad: &payload_aad(dest),
+            },
+        )
+        .map_err(|_| io::Error::other("Could not encrypt the payload"))?;
+    let mut stored = Vec::with_capacity(ENCRYPTED_MAGIC.len() + NONCE_LEN + sealed.len());
+    stored.extend_from_slice(ENCRYPTED_MAGIC);
+    stored.extend_from_slice(&nonce);
+    stored.extend_from_slice(&sealed);
+    Ok(Cow::Owned(stored))
+}
+
+/// Decrypt a payload written with `encrypt_storage`, whatever the setting is
+/// now. A missing or wrong key is reported as access denied rather than
+/// corruption, so the payload is kept for when the right key is back.
+fn decrypt_payload(path: &Path, stored: Vec<u8>) -> io::Result<Vec<u8>> {
+    let Some(rest) = stored.strip_prefix(ENCRYPTED_MAGIC) else {
+        return Ok(stored);
+    };
+    let _timer = PhaseTimer::start("decrypt");
+    let key = storage_key().map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("'{}' is encrypted, but {e}", path.display()),
+        )
+    })?;
+    if rest.len() < NONCE_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("'{}' is truncated or corrupt", path.display()),
+        ));
+    }
+    let (nonce, sealed) = rest.split_at(NONCE_LEN);
+    XChaCha20Poly1305::new(key)
+        .decrypt(
+            XNonce::from_slice(nonce),
+            Payload {
+                msg: sealed,
+                aad: &payload_aad(path),
+            },
+        )
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "'{}' can't be decrypted with the key in {KEY_FILE_ENV_VAR}; \
+                     it was encrypted with another key or altered",
+                    path.display()
+                ),
+            )
+        })
+}
+
+/// Describes every payload in its directory (the store, or one history
+/// entry), replacing the `.primed` and `image.format` sidecars each payload
+/// used to carry. Only ever replaced whole, by a rename under the store's
+/// exclusive lock.
+const INDEX_FILE: &str = "index.json";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StoreIndex {
+    /// Layout the directory was written in; see `STORE_LAYOUT_VERSION`
+    #[serde(default = "unversioned_layout")]
+    version: u32,
+    /// Keyed by payload file name ("text.txt", "image.bin", ...)
+    #[serde(default)]
+    entries: BTreeMap<String, IndexEntry>,
+}
+
+impl Default for StoreIndex {
+    fn default() -> Self {
+        Self {
+            version: STORE_LAYOUT_VERSION,
+            entries: BTreeMap::new(),
+        }
+    }
+}
+
+/// On-disk layout of a store and of each history entry in it:
+/// 1. payloads with `.primed` and `image.format` sidecars, or just an mtime
+/// 2. `index.json` describing every payload
+///
+/// Older layouts are migrated at startup. A store in a newer one is left
+/// alone and reads as an empty clipboard.
+const STORE_LAYOUT_VERSION: u32 = 2;
+
+/// Indexes written before the version field are layout 2
+const fn unversioned_layout() -> u32 {
+    2
+}
+
+/// Just the version of an index, readable whatever else a newer layout
+/// changed about it
+#[derive(Deserialize)]
+struct LayoutProbe {
+    #[serde(default = "unversioned_layout")]
+    version: u32,
+}
+
+/// Layout of a store directory: from its index, else 1 if it holds
+/// anything, else the current one (a new store)
+fn store_layout(dir: &Path) -> u32 {
+    if let Ok(data) = read_stored(&index_path(dir)) {
+        return serde_json::from_slice::<LayoutProbe>(&data)
+            .map_or(STORE_LAYOUT_VERSION, |probe| probe.version);
+    }
+    let legacy = payload_paths()
+        .iter()
+        .filter_map(|path| path.file_name())
+        .any(|name| dir.join(name).exists());
+    if legacy { 1 } else { STORE_LAYOUT_VERSION }
+}
+
+/// Bring the active store and its history entries up to the current
+/// layout. Each directory's index is written (spooled, then renamed) before
+/// its sidecars go, so an interrupted run leaves every directory readable
+/// and the next run finishes it. Payloads are never touched. Err carries
+/// the layout of a store written by a newer version, which is left alone.
+fn migrate_store() -> Result<(), u32> {
+    let storage = get_storage_directory();
+    let layout = store_layout(&storage);
+    if layout > STORE_LAYOUT_VERSION {
+        return Err(layout);
+    }
+    if !storage.is_dir() || is_read_only() {
+        return Ok(());
+    }
+    let mut dirs = vec![storage];
+    if let Ok(entries) = fs::read_dir(get_history_directory()) {
+        dirs.extend(entries.flatten().map(|entry| entry.path()));
+    }
+    if !dirs.iter().any(|dir| needs_migration(dir)) {
+        return Ok(());
+    }
+    let migrated = StoreLock::acquire(LockMode::Exclusive).and_then(|_lock| {
+        dirs.iter()
+            .try_fold(0, |count, dir| Ok(count + usize::from(migrate_dir(dir)?)))
+    });
+    match migrated {
+        Ok(count) if verbose() => {
+            eprintln!("Migrated {count} directories to storage layout {STORE_LAYOUT_VERSION}");
+        }
+        Ok(_) => {}
+        // Reads still fall back to the sidecars
+        Err(e) => eprintln!("Warning: Could not migrate the storage layout: {e}"),
+    }
+    Ok(())
+}
+
+/// Whether a directory is in an older layout, still has sidecars, or holds
+/// a payload its index has no entry for
+fn needs_migration(dir: &Path) -> bool {
+    store_layout(dir) < STORE_LAYOUT_VERSION
+        || payload_paths()
+            .iter()
+            .filter_map(|path| path.file_name())
+            .flat_map(|name| entry_files(&dir.join(name)).into_iter().skip(1))
+            .any(|sidecar| sidecar.exists())
+        || has_unindexed_payload(dir)
+}
+
+/// A payload left without an entry, e.g. after an index write was lost or
+/// an unreadable index was rebuilt; migrating gives it one again
+fn has_unindexed_payload(dir: &Path) -> bool {
+    let Ok(index) = read_index(dir) else {
+        return false; // Left for the next write to rebuild
+    };
+    payload_paths()
+        .iter()
+        .filter_map(|path| path.file_name())
+        .any(|name| {
+            dir.join(name).is_file() && !index.entries.contains_key(&*name.to_string_lossy())
+        })
+}
+
+/// Give every payload in `dir` an index entry (primed at its `.primed`
+/// record or mtime), then drop the sidecars. Idempotent; an index that
+/// can't be read is left for the next write to rebuild.
+fn migrate_dir(dir: &Path) -> io::Result<bool> {
+    if !needs_migration(dir) {
+        return Ok(false);
+    }
+    let mut index = match read_index(dir) {
+        Ok(index) => index,
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    let mut sidecars = Vec::new();
+    for path in payload_paths() {
+        let name = payload_name(&path)?;
+        let payload = dir.join(&name);
+        sidecars.extend(
+            entry_files(&payload)
+                .into_iter()
+                .skip(1)
+                .filter(|file| file.exists()),
+        );
+        if !payload.is_file() || index.entries.contains_key(&name) {
+            continue;
+        }
+        let entry = IndexEntry {
+            mime: stored_mime(&payload),
+            primed: primed_at(&payload).map_or(0, system_time_millis),
+            checksum: legacy_checksum(&payload),
+            content: None,
+            source: None,
+        };
+        index.entries.insert(name, entry);
+    }
+    index.version = STORE_LAYOUT_VERSION;
+    write_index(dir, &index)?;
+    for sidecar in sidecars {
+        let _ = remove_stored(&sidecar);
+    }
+    Ok(true)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct IndexEntry {
+    mime: String,
+    primed: u64, // Unix milliseconds
+    /// "crc32:<hex>" of the payload exactly as stored (after compression)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    checksum: Option<String>,
+    /// "crc32:<hex>" of the payload as copied, to spot a copy of the same
+    /// content without decrypting or decompressing the stored one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    content: Option<String>,
+    /// File the content was copied from with -i, when `record_source_path`
+    /// is on and the file is under $HOME
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<PathBuf>,
+}
+
+fn index_path(dir: &Path) -> PathBuf {
+    dir.join(INDEX_FILE)
+}
+
+fn payload_name(payload: &Path) -> io::Result<String> {
+    payload
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "payload path has no name"))
+}
+
+/// The index of a directory; empty if there is none yet (a store written
+/// before the index) or it can't be parsed
+fn read_index(dir: &Path) -> io::Result<StoreIndex> {
+    match read_stored(&index_path(dir)) {
+        Ok(data) => {
+            serde_json::from_slice(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(StoreIndex::default()),
+        Err(e) => Err(e),
+    }
+}
+
+/// A payload's entry in the index next to it
+fn index_entry(payload: &Path) -> Option<IndexEntry> {
+    let name = payload_name(payload).ok()?;
+    read_index(payload.parent()?).ok()?.entries.remove(&name)
+}
+
+fn write_index(dir: &Path, index: &StoreIndex) -> io::Result<()> {
+    let data = serde_json::to_vec_pretty(index)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let dest = index_path(dir);
+    let spooled = spool_file(&dest, &data)?;
+    fs::rename(spooled, dest)?;
+    sync_directory(dir)
+}
+
+/// Apply `change` to the store's index and write it back; the caller holds
+/// the exclusive store lock. Payloads still described by legacy sidecars are
+/// folded in first, and the sidecars removed once the index has them.
+fn update_index(change: impl FnOnce(&mut StoreIndex)) -> io::Result<()> {
+    let storage = get_storage_directory();
+    let mut index = read_index(&storage).unwrap_or_else(|e| {
+        eprintln!("Warning: Rebuilding unreadable {INDEX_FILE}: {e}");
+        StoreIndex::default()
+    });
+    let mut sidecars = Vec::new();
+    for payload in payload_paths() {
+        let legacy: Vec<PathBuf> = entry_files(&payload)
+            .into_iter()
+            .skip(1)
+            .filter(|file| file.exists())
+            .collect();
+        if legacy.is_empty() {
+            continue;
+        }
+        let name = payload_name(&payload)?;
+        if payload.exists() && !index.entries.contains_key(&name) {
+            let primed = primed_at(&payload).map_or(0, system_time_millis);
+            index.entries.insert(
+                name,
+                IndexEntry {
+                    mime: stored_mime(&payload),
+                    primed,
+                    checksum: legacy_checksum(&payload),
+                    content: None,
+                    source: None,
+                },
+            );
+        }
+        sidecars.extend(legacy);
+    }
+    change(&mut index);
+    index.version = STORE_LAYOUT_VERSION;
+    write_index(&storage, &index)?;
+    for sidecar in sidecars {
+        let _ = remove_stored(&sidecar);
+    }
+    Ok(())
+}
+
+/// Drop index entries whose payload is gone; the caller holds the exclusive
+/// store lock
+fn prune_index() -> io::Result<()> {
+    let storage = get_storage_directory();
+    if !index_path(&storage).exists() {
+        return Ok(());
+    }
+    update_index(|index| {
+        index.entries.retain(|name, _| storage.join(name).exists());
+    })
+}
+
+/// Legacy record of when a stored payload was primed, next to it: Unix
+/// milliseconds, optionally followed by "crc32:<hex>"
+fn get_primed_path(payload: &Path) -> PathBuf {
+    let mut name = payload.as_os_str().to_owned();
+    name.push(".primed");
+    PathBuf::from(name)
+}
+
+fn legacy_checksum(payload: &Path) -> Option<String> {
+    let record = read_stored_text(&get_primed_path(payload))?;
+    let sum = record.split_whitespace().nth(1)?;
+    sum.starts_with("crc32:").then(|| sum.to_string())
+}
+
+fn now_millis() -> u64 {
+    system_time_millis(SystemTime::now())
+}
+
+fn system_time_millis(time: SystemTime) -> u64 {
+    let millis = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    u64::try_from(millis).unwrap_or(u64::MAX)
+}
+
+fn payload_checksum(stored: &[u8]) -> String {
+    format!("crc32:{:08x}", crc32fast::hash(stored))
+}
+
+fn recorded_checksum(payload: &Path) -> Option<u32> {
+    let sum = index_entry(payload)
+        .and_then(|entry| entry.checksum)
+        .or_else(|| legacy_checksum(payload))?;
+    u32::from_str_radix(sum.strip_prefix("crc32:")?, 16).ok()
+}
+
+/// MIME type a payload was stored as. Stores from before the index keep an
+/// image's type in `image.format`, and text's follows its encoding. An image
+/// with neither record (very old stores, or a lost index entry) is sniffed,
+/// so it can still be pasted rather than reading as absent.
+fn stored_mime(payload: &Path) -> String {
+    if let Some(entry) = index_entry(payload) {
+        return entry.mime;
+    }
+    let name = payload.file_name();
+    if name == get_image_path().file_name() {
+        let format_path = get_image_format_path();
+        let format_name = format_path.file_name().unwrap_or_default();
+        read_stored_text(&payload.with_file_name(format_name))
+            .map(|f| f.trim().to_string())
+            .or_else(|| {
+                let data = read_payload(payload).ok()?;
+                sniff_image_mime(&data).map(str::to_string)
+            })
+            .unwrap_or_else(|| "application/octet-stream".to_string())
+    } else if name == get_text_path().file_name() {
+        stored_text_mime(payload).to_string()
+    } else if name == get_uri_list_path().file_name() {
+        URI_LIST_MIME.to_string()
+    } else {
+        ARCHIVE_MIME.to_string()
+    }
+}
+
+/// Canonical type of the stored image, if it has a usable one
+fn stored_image_mime() -> Option<&'static str> {
+    canonical_image_mime(&stored_mime(&get_image_path()))
+}
+
+/// Fails with `InvalidData` when the stored bytes don't match the checksum
+/// recorded at prime time (a truncated write after an unclean shutdown)
+fn check_integrity(path: &Path, stored: &[u8]) -> io::Result<()> {
+    if !load_config()
+        .verify_checksums
+        .unwrap_or(DEFAULT_VERIFY_CHECKSUMS)
+    {
+        return Ok(());
+    }
+    match recorded_checksum(path) {
+        Some(sum) if crc32fast::hash(stored) != sum => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("'{}' is corrupt (checksum mismatch)", path.display()),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Whether a stored payload fails verification or won't decompress
+fn is_corrupt(path: &Path) -> bool {
+    read_payload(path).is_err_and(|e| e.kind() == io::ErrorKind::InvalidData)
+}
+
+/// Treat a payload that failed verification as absent: warn loudly and
+/// `discard` it, so later pastes don't trip over it. Other errors pass through.
+fn reject_corrupt(
+    result: io::Result<Vec<u8>>,
+    discard: impl FnOnce() -> io::Result<()>,
+) -> io::Result<Option<Vec<u8>>> {
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+            eprintln!("Warning: {e}; removing it");
+            if !is_read_only()
+                && let Err(e) = discard()
+            {
+                eprintln!("Warning: Could not remove the corrupt entry: {e}");
+            }
+            Ok(None)
+        }
+        other => other.map(Some),
+    }
+}
+
+/// When a payload was primed. Its mtime can be faked by `touch` or carried
+/// over by a restore, so it only stands in for entries without a record.
+fn primed_at(path: &Path) -> Option<SystemTime> {
+    let recorded = index_entry(path).map(|entry| entry.primed).or_else(|| {
+        read_stored_text(&get_primed_path(path))
+            .and_then(|record| record.split_whitespace().next()?.parse::<u64>().ok())
+    });
+    if let Some(millis) = recorded {
+        return Some(SystemTime::UNIX_EPOCH + Duration::from_millis(millis));
+    }
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Every copy while `history_size` is set, the current one included. Each
+/// entry is a directory laid out like the store itself, named after its
+/// prime time, holding hard links to the files the copy stored.
+fn get_history_directory() -> PathBuf {
+    get_storage_directory().join("history")
+}
+
+fn history_size() -> usize {
+    let size = load_config().history_size.unwrap_or(DEFAULT_HISTORY_SIZE);
+    usize::try_from(size).unwrap_or(usize::MAX)
+}
+
+/// A stored payload plus the legacy sidecars that travelled with it
+fn entry_files(payload: &Path) -> Vec<PathBuf> {
+    let mut files = vec![payload.to_path_buf(), get_primed_path(payload)];
+    if payload.file_name() == get_image_path().file_name()
+        && let Some(format_name) = get_image_format_path().file_name()
+    {
+        files.push(payload.with_file_name(format_name));
+    }
+    files
+}
+
+/// Record a copy that was just stored in history, then evict expired
+/// entries and the oldest beyond `history_size` earlier ones. Runs under
+/// the store's exclusive lock.
+fn record_history(payload: &Path) {
+    let size = history_size();
+    let ttl = load_ttl();
+    if size == 0 {
+        return;
+    }
+    let (Some(primed), Some(name)) = (primed_at(payload), payload.file_name()) else {
+        return;
+    };
+    let millis = primed
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let history_dir = get_history_directory();
+    let entry_dir = history_dir.join(format!("{millis}-{}", name.to_string_lossy()));
+    // A link shares the data, and the next copy's rename leaves it intact.
+    // The entry gets an index of its own describing just that payload.
+    let linked = create_storage_directory(&history_dir)
+        .and_then(|()| create_storage_directory(&entry_dir))
+        .and_then(|()| {
+            let target = entry_dir.join(name);
+            fs::hard_link(payload, &target).or_else(|_| fs::copy(payload, &target).map(drop))?;
+            let mut index = StoreIndex::default();
+            if let Some(entry) = index_entry(payload) {
+                index
+                    .entries
+                    .insert(name.to_string_lossy().into_owned(), entry);
+            }
+            write_index(&entry_dir, &index)
+        });
+    if let Err(e) = linked {
+        eprintln!("Warning: Could not record the copy in history: {e}");
+    }
+
+    for dir in stale_history(size, ttl) {
+        let _ = remove_stored_dir(&dir);
+    }
+}
+
+/// History entries past their TTL or beyond `size` earlier copies
+fn stale_history(size: usize, ttl: Duration) -> Vec<PathBuf> {
+    history_entries()
+        .into_iter()
+        .enumerate()
+        .filter(|(position, entry)| *position > size || !is_file_fresh(&entry.payload, ttl))
+        .map(|(_, entry)| entry.dir)
+        .collect()
+}
+
+#[derive(Debug)]
+struct HistoryEntry {
+    dir: PathBuf,
+    payload: PathBuf,
+    primed: SystemTime,
+}
+
+impl HistoryEntry {
+    fn is_text(&self) -> bool {
+        self.payload.file_name() == get_text_path().file_name()
+    }
+
+    fn is_image(&self) -> bool {
+        self.payload.file_name() == get_image_path().file_name()
+    }
+
+    fn is_uri_list(&self) -> bool {
+        self.payload.file_name() == get_uri_list_path().file_name()
+    }
+
+    fn mime(&self) -> String {
+        stored_mime(&self.payload)
+    }
+
+    /// A short, single-line hint of the content for `history list`
+    fn preview(&self) -> String {
+        if self.is_text() {
+            let head = read_payload(&self.payload).unwrap_or_default();
+            let head = &head[..head.len().min(256)];
+            let text: String = String::from_utf8_lossy(head).chars().take(40).collect();
+            format!("{text:?}")
+        } else if self.is_image() {
+            read_payload(&self.payload)
+                .ok()
+                .and_then(|data| header_dimensions(&data))
+                .map_or_else(|| "-".to_string(), |(w, h)| format!("{w}x{h}"))
+        } else if self.is_uri_list() {
+            read_payload(&self.payload).map_or_else(
+                |_| "-".to_string(),
+                |uris| {
+                    format!(
+                        "{} files",
+                        uris.split(|&b| b == b'\n')
+                            .filter(|l| !l.is_empty())
+                            .count()
+                    )
+                },
+            )
+        } else {
+            read_payload(&self.payload)
+                .ok()
+                .and_then(|data| {
+                    tar::Archive::new(Cursor::new(data))
+                        .entries()
+                        .ok()
+                        .map(Iterator::count)
+                })
+                .map_or_else(|| "-".to_string(), |n| format!("{n} files"))
+        }
+    }
+}
+
+/// History entries, newest first, including expired ones
+fn history_entries() -> Vec<HistoryEntry> {
+    let Ok(dirs) = fs::read_dir(get_history_directory()) else {
+        return Vec::new();
+    };
+    let payload_paths = payload_paths();
+    let mut entries: Vec<HistoryEntry> = dirs
+        .flatten()
+        .filter_map(|dirent| {
+            let dir = dirent.path();
+            let payload = payload_paths
+                .iter()
+                .filter_map(|path| path.file_name())
+                .map(|name| dir.join(name))
+                .find(|path| path.is_file())?;
+            let primed = primed_at(&payload)?;
+            Some(HistoryEntry {
+                dir,
+                payload,
+                primed,
+            })
+        })
+        .collect();
+    entries.sort_by_key(|entry| std::cmp::Reverse(entry.primed));
+    entries
+}
+
+/// Entries that can still be pasted; index 0 is the latest copy
+fn fresh_history_entries() -> Vec<HistoryEntry> {
+    let ttl = load_ttl();
+    history_entries()
+        .into_iter()
+        .filter(|entry| is_file_fresh(&entry.payload, ttl))
+        .collect()
+}
+
+/// `-o --index N`: paste the copy made N copies ago. A -t naming another
+/// type finds nothing, as it would for the current content.
+fn output_history(
+    requested: Option<&str>,
+    index: usize,
+    options: &OutputOptions,
+) -> io::Result<i32> {
+    let lock = StoreLock::acquire(LockMode::Shared)?;
+    let Some(entry) = fresh_history_entries().into_iter().nth(index) else {
+        return Ok(EXIT_NO_CONTENT);
+    };
+    let mime = entry.mime();
+    check_mime_policy(&mime)?;
+    if entry.is_image() && !image_format_allowed(&mime) {
+        return Ok(unsupported_image_format(&mime));
+    }
+    if let Some(requested) = requested {
+        let matches = if requested.starts_with("text/plain") {
+            entry.is_text()
+        } else {
+            canonical_image_mime(requested).unwrap_or(requested) == mime
+        };
+        if !matches {
+            return Ok(EXIT_NO_CONTENT);
+        }
+    }
+    let data = read_payload(&entry.payload);
+    drop(lock);
+    let discard = || {
+        let _lock = StoreLock::acquire(LockMode::Exclusive)?;
+        remove_stored_dir(&entry.dir)
+    };
+    let Some(data) = reject_corrupt(data, discard)? else {
+        return Ok(EXIT_NO_CONTENT);
+    };
+
+    if entry.is_text() {
+        write_text_output(&data, options)?;
+    } else if entry.is_uri_list() {
+        write_output(&data, options)?;
+    } else {
+        write_binary_output(&data, options)?;
+    }
+    Ok(EXIT_SUCCESS)
+}
+
+/// `history list`: index, MIME type, size in bytes, age and a preview,
+/// tab-separated, newest (index 0) first
+fn print_history() -> i32 {
+    if history_size() == 0 && !quiet() {
+        eprintln!("History is disabled (history_size = 0)");
+    }
+    let Ok(_lock) = StoreLock::acquire(LockMode::Shared) else {
+        eprintln!("Error: Clipboard store is busy (locked by another process)");
+        return EXIT_BUSY;
+    };
+    let now = SystemTime::now();
+    for (position, entry) in fresh_history_entries().iter().enumerate() {
+        let size = payload_size(&entry.payload);
+        let age = now.duration_since(entry.primed).unwrap_or_default();
+        println!(
+            "{position}\t{}\t{size}\t{}\t{}",
+            entry.mime(),
+            format_age(age),
+            entry.preview()
+        );
+    }
+    EXIT_SUCCESS
+}
+
+/// Payloads present in the store with their MIME types, fresh or not
+fn stored_entries() -> Vec<(PathBuf, String)> {
+    payload_paths()
+        .into_iter()
+        .filter(|path| path.exists())
+        .map(|path| {
+            let mime = stored_mime(&path);
+            (path, mime)
+        })
+        .collect()
+}
+
+/// `status --check MIME`: whether that target could be pasted from the
+/// store right now, without reading the payload
+fn is_target_fresh(mime: &str) -> bool {
+    if check_mime_policy(mime).is_err() {
+        return false;
+    }
+    let ttl = load_ttl();
+    let mime = canonical_image_mime(mime).unwrap_or(mime);
+    match mime {
+        m if m.starts_with("text/plain") => is_file_fresh(&get_text_path(), ttl),
+        ARCHIVE_MIME => is_file_fresh(&get_archive_path(), ttl),
+        URI_LIST_MIME => is_file_fresh(&get_uri_list_path(), ttl),
+        m => is_file_fresh(&get_image_path(), ttl) && stored_image_mime() == Some(m),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct StatusReport {
+    selection: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    slot: Option<String>, // Set with --slot
+    config_path: PathBuf,
+    config_state: &'static str, // "loaded", "missing", "invalid"
+    storage_path: PathBuf,
+    storage_bytes: u64,
+    /// Set with `max_storage_mb`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quota: Option<QuotaStatus>,
+    pinned: bool,
+    entries: Vec<StatusEntry>,
+    history: HistoryStatus,
+    /// Every named slot, listed when no --slot picks one
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    slots: Vec<SlotStatus>,
+}
+
+/// Earlier copies kept for one store, expired ones included until gc
+/// removes them
+#[derive(Debug, Serialize)]
+struct HistoryStatus {
+    entries: usize,
+    bytes: u64, // Size on disk; links to the current copy count in full
+}
+
+#[derive(Debug, Serialize)]
+struct QuotaStatus {
+    used_bytes: u64, // Whole storage root, slots and history included
+    quota_bytes: u64,
+    used_percent: u64, // Rounded down; past 100 once history pushes over
+}
+
+#[derive(Debug, Serialize)]
+struct SlotStatus {
+    name: String,
+    pinned: bool,
+    entries: Vec<StatusEntry>,
+    history: HistoryStatus,
+}
+
+#[derive(Debug, Serialize)]
+struct StatusEntry {
+    mime: String,
+    bytes: u64,        // Original size
+    stored_bytes: u64, // Size on disk, smaller when compress_storage applied
+
+    primed: u64,                 // Unix seconds
+    remaining_secs: Option<u64>, // None = never expires
+    expired: bool,
+    corrupt: bool, // Fails its checksum; the next paste or gc removes it
+}
+
+/// Whether the active store is pinned, its entries and its history, read
+/// under its lock
+fn store_status() -> io::Result<(bool, Vec<StatusEntry>, HistoryStatus)> {
+    let ttl = load_ttl();
+    let pinned = is_pinned();
+    let now = SystemTime::now();
+
+    let _lock = StoreLock::acquire(LockMode::Shared)?;
+    let entries = stored_entries()
+        .into_iter()
+        .map(|(path, mime)| {
+            let primed = primed_at(&path).unwrap_or(now);
+            let elapsed = now.duration_since(primed).unwrap_or_default();
+            let remaining_secs =
+                (ttl != NEVER_EXPIRES && !pinned).then(|| ttl.saturating_sub(elapsed).as_secs());
+            StatusEntry {
+                mime,
+                bytes: payload_size(&path),
+                stored_bytes: fs::metadata(&path).map_or(0, |m| m.len()),
+                primed: primed
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+                remaining_secs,
+                expired: !is_file_fresh(&path, ttl),
+                corrupt: is_corrupt(&path),
+            }
+        })
+        .collect();
+    let history = history_entries();
+    let history = HistoryStatus {
+        entries: history.len(),
+        bytes: history.iter().map(|entry| dir_size(&entry.dir)).sum(),
+    };
+    Ok((pinned, entries, history))
+}
+
+/// `status`: what's stored, how big, and how long it has left. Only reads;
+/// expired entries are reported, not removed.
+fn print_status(json: bool) -> io::Result<i32> {
+    let resolved = resolved_config();
+    let (pinned, entries, history) = store_status()?;
+    let slot = current_slot();
+    let slots = if slot.is_none() {
+        slot_names()
+            .into_iter()
+            .map(|name| {
+                let (pinned, entries, history) = with_slot(Some(name.clone()), store_status)?;
+                Ok(SlotStatus {
+                    name,
+                    pinned,
+                    entries,
+                    history,
+                })
+            })
+            .collect::<io::Result<_>>()?
+    } else {
+        Vec::new()
+    };
+    let storage_path = get_storage_directory();
+    let report = StatusReport {
+        selection: active_selection().to_string(),
+        slot,
+        config_path: config_path(),
+        config_state: match resolved.file_state {
+            ConfigFileState::Missing => "missing",
+            ConfigFileState::Loaded => "loaded",
+            ConfigFileState::Invalid { .. } => "invalid",
+        },
+        storage_bytes: dir_size(&storage_path),
+        storage_path,
+        quota: storage_quota().map(|quota_bytes| {
+            let used_bytes = storage_usage();
+            QuotaStatus {
+                used_bytes,
+                quota_bytes,
+                used_percent: used_bytes.saturating_mul(100) / quota_bytes,
+            }
+        }),
+        pinned,
+        entries,
+        history,
+        slots,
+    };
+
+    if json {
+        let line = serde_json::to_string(&report)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        println!("{line}");
+        return Ok(EXIT_SUCCESS);
+    }
+    println!("selection: {}", report.selection);
+    if let Some(slot) = &report.slot {
+        println!("slot: {slot}");
+    }
+    println!(
+        "config: {} ({})",
+        report.config_path.display(),
+        report.config_state
+    );
+    println!(
+        "storage: {} ({} bytes)",
+        report.storage_path.display(),
+        report.storage_bytes
+    );
+    if let Some(quota) = &report.quota {
+        println!(
+            "quota: {} of {} bytes used ({}%)",
+            quota.used_bytes, quota.quota_bytes, quota.used_percent
+        );
+    }
+    println!("pinned: {}", if report.pinned { "yes" } else { "no" });
+    for entry in &report.entries {
+        print_status_entry("", entry);
+    }
+    print_history_status("", &report.history);
+    // Each slot follows, its lines indented so `^entry:` still matches only
+    // the store that was asked about
+    for slot in &report.slots {
+        println!(
+            "slot: {} (pinned: {})",
+            slot.name,
+            if slot.pinned { "yes" } else { "no" }
+        );
+        for entry in &slot.entries {
+            print_status_entry("  ", entry);
+        }
+        print_history_status("  ", &slot.history);
+    }
+    Ok(EXIT_SUCCESS)
+}
+
+/// Entry count and bytes on disk; nothing while the store has no history
+fn print_history_status(indent: &str, history: &HistoryStatus) {
+    if history.entries > 0 {
+        println!(
+            "{indent}history: {} entries, {} bytes",
+            history.entries, history.bytes
+        );
+    }
+}
+
+/// One line per entry: MIME, bytes, prime time (Unix seconds), seconds
+/// left, bytes on disk
+fn print_status_entry(indent: &str, entry: &StatusEntry) {
+    let remaining = match entry.remaining_secs {
+        _ if entry.corrupt => "corrupt".to_string(),
+        _ if entry.expired => "expired".to_string(),
+        None => "never".to_string(),
+        Some(secs) => secs.to_string(),
+    };
+    println!(
+        "{indent}entry: {} {} {} {remaining} {}",
+        entry.mime, entry.bytes, entry.primed, entry.stored_bytes
+    );
+}
+
+/// Total size of the files under a directory
+fn dir_size(dir: &Path) -> u64 {
+    let Ok(children) = fs::read_dir(dir) else {
+        return 0;
+    };
+    children
+        .flatten()
+        .map(|child| match child.file_type() {
+            Ok(kind) if kind.is_dir() => dir_size(&child.path()),
+            _ => child.metadata().map_or(0, |m| m.len()),
+        })
+        .sum()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Check {
+    Pass,
+    Info,
+    Warn,
+    Fail, // Critical: the bridge won't work as configured
+}
+
+fn report_check(outcome: Check, area: &str, detail: &str) {
+    let label = match outcome {
+        Check::Pass => "PASS",
+        Check::Info => "INFO",
+        Check::Warn => "WARN",
+        Check::Fail => "FAIL",
+    };
+    println!("{label}  {area}: {detail}");
+}
+
+/// `doctor`: check the setup end to end and print a pass/fail report. The
+/// write probe uses a scratch directory, so stored content is never touched.
+#[allow(clippy::too_many_lines)]
+fn run_doctor() -> i32 {
+    let mut outcomes = Vec::new();
+    let mut check = |outcome: Check, area: &str, detail: &str| {
+        report_check(outcome, area, detail);
+        outcomes.push(outcome);
+    };
+
+    let resolved = resolved_config();
+    let (outcome, state) = match resolved.file_state {
+        ConfigFileState::Missing => (Check::Pass, "not found, using defaults"),
+        ConfigFileState::Loaded => (Check::Pass, "parsed"),
+        ConfigFileState::Invalid {
+            security_relevant: true,
+        } => (Check::Fail, "is invalid; copies and pastes are refused"),
+        ConfigFileState::Invalid { .. } => (Check::Warn, "is invalid and ignored"),
+    };
+    check(
+        outcome,
+        "config",
+        &format!("{} {state}", config_path().display()),
+    );
+    for key in &resolved.unknown_keys {
+        check(Check::Warn, "config", &format!("unknown key {key}"));
+    }
+    if let Some(profile) = &resolved.profile {
+        if profile.found {
+            check(Check::Pass, "profile", &profile.name);
+        } else {
+            check(
+                Check::Fail,
+                "profile",
+                &format!("'{}' is not defined", profile.name),
+            );
+        }
+    }
+
+    let ttl = match load_ttl() {
+        NEVER_EXPIRES => "never expires".to_string(),
+        ttl => format!("{}s", ttl.as_secs()),
+    };
+    check(
+        Check::Pass,
+        "ttl",
+        &format!("{ttl} (from {})", resolved.source("ttl_secs")),
+    );
+
+    let storage = get_storage_directory();
+    // Probing would create the directory where the memory backend won't
+    if storage_location() == StorageLocation::Unavailable {
+        check(
+            Check::Fail,
+            "storage",
+            "storage_backend = \"memory\" but no tmpfs location is usable; \
+             copies and pastes are refused",
+        );
+    } else {
+        match probe_storage(&storage) {
+            Ok(()) => check(
+                Check::Pass,
+                "storage",
+                &format!("{} is writable", storage.display()),
+            ),
+            Err(e) => check(
+                Check::Fail,
+                "storage",
+                &format!("{}: {e}", storage.display()),
+            ),
+        }
+    }
+    let location = storage_location();
+    let fs_type = filesystem_type(&storage);
+    let on_tmpfs = fs_type.as_deref() == Some("tmpfs");
+    let backend = format!(
+        "{location} on {}",
+        fs_type.as_deref().unwrap_or("an unknown filesystem")
+    );
+    if storage_backend() == StorageBackend::Cache {
+        check(Check::Info, "backend", &backend);
+    } else if location == StorageLocation::Unavailable {
+        check(Check::Fail, "backend", &backend);
+    } else if location == StorageLocation::Cache {
+        check(
+            Check::Warn,
+            "backend",
+            &format!("{backend}; no tmpfs location was usable"),
+        );
+    } else if on_tmpfs {
+        check(Check::Pass, "backend", &backend);
+    } else {
+        check(
+            Check::Warn,
+            "backend",
+            &format!("{backend}, not tmpfs; copies may reach the disk"),
+        );
+    }
+    let payload_names = payload_paths().map(|path| path.file_name().map(ToOwned::to_owned));
+    let elsewhere = [
+        runtime_storage_path(),
+        shm_storage_path(),
+        Some(cache_storage_directory()),
+    ];
+    for dir in elsewhere
+        .into_iter()
+        .flatten()
+        .filter(|dir| *dir != storage)
+    {
+        if payload_names
+            .iter()
+            .flatten()
+            .any(|name| dir.join(name).exists())
+        {
+            check(
+                Check::Warn,
+                "backend",
+                &format!(
+                    "{} holds clipboard files this storage_backend doesn't read",
+                    dir.display()
+                ),
+            );
+        }
+    }
+    match verify_storage_directory(&storage) {
+        Err(e) => check(
+            Check::Fail,
+            "permissions",
+            &format!("{e}; copies and pastes are refused without --insecure-storage"),
+        ),
+        #[cfg(unix)]
+        Ok(()) => {
+            if let Ok(metadata) = fs::metadata(&storage) {
+                let mode = metadata.permissions().mode() & 0o777;
+                check(
+                    Check::Pass,
+                    "permissions",
+                    &format!("{} is {mode:04o} and yours", storage.display()),
+                );
+            }
+        }
+        #[cfg(not(unix))]
+        Ok(()) => {}
+    }
+    if is_read_only() {
+        check(
+            Check::Info,
+            "storage",
+            "read_only = true; copies are refused",
+        );
+    }
+    match StoreLock::acquire(LockMode::Shared) {
+        Ok(_) => check(Check::Pass, "lock", "store is not held by another process"),
+        Err(e) => check(Check::Warn, "lock", &e.to_string()),
+    }
+    if encrypts_storage() {
+        match storage_key() {
+            Ok(_) => check(
+                Check::Pass,
+                "encryption",
+                &format!("payloads are encrypted with the key from {KEY_FILE_ENV_VAR}"),
+            ),
+            Err(e) => check(
+                Check::Fail,
+                "encryption",
+                &format!("encrypt_storage is on, but {e}; copies are refused"),
+            ),
+        }
+    }
+    let index = index_path(&storage);
+    match read_index(&storage) {
+        Ok(_) if !index.exists() => check(
+            Check::Info,
+            "index",
+            &format!("{INDEX_FILE} not written yet; the next copy creates it"),
+        ),
+        Ok(store) => check(
+            Check::Pass,
+            "index",
+            &format!(
+                "{} describes {} payload(s)",
+                index.display(),
+                store.entries.len()
+            ),
+        ),
+        Err(e) => check(
+            Check::Warn,
+            "index",
+            &format!("{}: {e}; the next copy rebuilds it", index.display()),
+        ),
+    }
+    match store_layout(&storage) {
+        layout if layout > STORE_LAYOUT_VERSION => check(
+            Check::Warn,
+            "layout",
+            &format!(
+                "version {layout} is from a newer release; pastes find nothing and copies are refused"
+            ),
+        ),
+        _ if needs_migration(&storage) => check(
+            Check::Info,
+            "layout",
+            "older files are migrated on the next copy or paste",
+        ),
+        layout => check(Check::Pass, "layout", &format!("version {layout}")),
+    }
+
+    let mimes: Vec<&str> = IMAGE_MIMES.iter().map(|(mime, _)| *mime).collect();
+    let broken: Vec<&str> = mimes
+        .iter()
+        .copied()
+        .filter(|mime| !image_roundtrips(mime))
+        .collect();
+    if broken.is_empty() {
+        check(
+            Check::Pass,
+            "images",
+            &format!("{} encode and decode", mimes.join(", ")),
+        );
+    } else {
+        check(
+            Check::Fail,
+            "images",
+            &format!("can't round-trip {}", broken.join(", ")),
+        );
+    }
+
+    if wl_clipboard_available() {
+        check(Check::Pass, "wl-clipboard", "wl-paste found");
+    } else {
+        check(
+            Check::Info,
+            "wl-clipboard",
+            "not used (file-based clipboard only)",
+        );
+    }
+
+    if outcomes.contains(&Check::Fail) {
+        EXIT_NO_CONTENT
+    } else {
+        EXIT_SUCCESS
+    }
+}
+
+/// Store, read back and remove a file the way copies do, in a scratch
+/// directory next to the real store
+fn probe_storage(storage: &Path) -> io::Result<()> {
+    create_storage_directory(storage)?;
+    let scratch = storage.join(format!("doctor.{}", std::process::id()));
+    create_storage_directory(&scratch)?;
+    let probe = || -> io::Result<()> {
+        let staged = scratch.join("probe.tmp");
+        let target = scratch.join("probe");
+        fs::write(&staged, b"wsl-clip-bridge")?;
+        fs::rename(&staged, &target)?;
+        if fs::read(&target)? != b"wsl-clip-bridge" {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "read back different bytes",
+            ));
+        }
+        Ok(())
+    };
+    let result = probe();
+    let _ = fs::remove_dir_all(&scratch);
+    result
+}
+
+/// Whether a small image survives encoding to `mime` and decoding again
+fn image_roundtrips(mime: &str) -> bool {
+    let img = DynamicImage::new_rgb8(8, 8);
+    encode_image(&img, mime)
+        .and_then(|data| image::load_from_memory(&data).ok())
+        .is_some_and(|decoded| decoded.width() == 8 && decoded.height() == 8)
+}
+
+/// Everything `gc` removes: expired payloads with their sidecars, sidecars
+/// whose payload is gone (or an image without its format), a pin with
+/// nothing left to pin, history that is stale or switched off, and scratch
+/// files of processes that have exited. Unknown files are left alone.
+fn gc_candidates() -> Vec<PathBuf> {
+    let ttl = load_ttl();
+    let image_path = get_image_path();
+    let payloads = [
+        get_text_path(),
+        image_path.clone(),
+        get_archive_path(),
+        get_uri_list_path(),
+    ];
+    let mut doomed = Vec::new();
+    for payload in &payloads {
+        let files = entry_files(payload);
+        let unusable = *payload == image_path && stored_image_mime().is_none();
+        if payload.exists() && (unusable || !is_file_fresh(payload, ttl) || is_corrupt(payload)) {
+            doomed.extend(files.into_iter().filter(|file| file.exists()));
+        } else if !payload.exists() {
+            doomed.extend(files.into_iter().skip(1).filter(|file| file.exists()));
+        }
+    }
+    let pin_path = get_pin_path();
+    if pin_path.exists()
+        && payloads
+            .iter()
+            .all(|payload| !payload.exists() || doomed.contains(payload))
+    {
+        doomed.push(pin_path);
+    }
+
+    let size = history_size();
+    let history = if size == 0 {
+        history_entries()
+            .into_iter()
+            .map(|entry| entry.dir)
+            .collect()
+    } else {
+        stale_history(size, ttl)
+    };
+    doomed.extend(history);
+
+    // Spool files are named <file>.<pid>, doctor's scratch dirs doctor.<pid>
+    let storage = get_storage_directory();
+    let scratch = fs::read_dir(get_spool_directory())
+        .into_iter()
+        .chain(fs::read_dir(&storage))
+        .flatten()
+        .flatten()
+        .map(|dirent| dirent.path())
+        .filter(|path| path.parent() != Some(storage.as_path()) || is_doctor_scratch(path))
+        .filter(|path| owner_exited(path));
+    doomed.extend(scratch);
+    doomed
+}
+
+fn is_doctor_scratch(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with("doctor."))
+}
+
+/// Whether the process a scratch file is named after (`name.<pid>`) has
+/// exited. Without /proc that can't be told, so the file is kept.
+fn owner_exited(path: &Path) -> bool {
+    let pid = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .and_then(|ext| ext.parse::<u32>().ok());
+    let proc = Path::new("/proc");
+    pid.is_some_and(|pid| {
+        pid != std::process::id() && proc.is_dir() && !proc.join(pid.to_string()).exists()
+    })
+}
+
+fn path_size(path: &Path) -> u64 {
+    if path.is_dir() {
+        dir_size(path)
+    } else {
+        fs::metadata(path).map_or(0, |m| m.len())
+    }
+}
+
+/// `gc`: purge what reads would otherwise clean up only when someone pastes.
+/// Holds the store lock so it can't race a copy; --dry-run only lists.
+fn collect_garbage(dry_run: bool) -> io::Result<i32> {
+    if is_read_only() && !dry_run {
+        eprintln!("Error: Clipboard is read-only (read_only = true); nothing was removed");
+        return Ok(EXIT_READ_ONLY);
+    }
+    if !get_storage_directory().exists() {
+        println!("Nothing to remove");
+        return Ok(EXIT_SUCCESS);
+    }
+    // Without --slot, every slot is collected after the default store
+    let mut stores = vec![current_slot()];
+    if current_slot().is_none() {
+        stores.extend(slot_names().into_iter().map(Some));
+    }
+    let mut reclaimed = 0;
+    let mut removed = 0;
+    for slot in stores {
+        let (bytes, items) = with_slot(slot, || collect_store_garbage(dry_run))?;
+        reclaimed += bytes;
+        removed += items;
+    }
+
+    let verb = if dry_run {
+        "Would reclaim"
+    } else {
+        "Reclaimed"
+    };
+    println!("{verb} {reclaimed} bytes ({removed} items)");
+    Ok(EXIT_SUCCESS)
+}
+
+/// Collect the active store under its lock; returns bytes and items freed
+fn collect_store_garbage(dry_run: bool) -> io::Result<(u64, usize)> {
+    let mode = if dry_run {
+        LockMode::Shared
+    } else {
+        LockMode::Exclusive
+    };
+    let _lock = StoreLock::acquire(mode)?;
+
+    let mut reclaimed = 0;
+    let mut removed = 0;
+    for path in gc_candidates() {
+        let size = path_size(&path);
+        if dry_run {
+            println!("Would remove {} ({size} bytes)", path.display());
+        } else {
+            let result = if path.is_dir() {
+                remove_stored_dir(&path)
+            } else {
+                remove_stored(&path)
+            };
+            if let Err(e) = result {
+                eprintln!("Warning: Could not remove '{}': {e}", path.display());
+                continue;
+            }
+            if verbose() {
+                eprintln!("Removed {} ({size} bytes)", path.display());
+            }
+        }
+        reclaimed += size;
+        removed += 1;
+    }
+    Ok((reclaimed, removed))
+}
+
+/// Largest whole unit of an age: "42s", "5m", "3h", "2d"
+fn format_age(age: Duration) -> String {
+    match age.as_secs() {
+        secs @ 0..60 => format!("{secs}s"),
+        secs @ 60..3600 => format!("{}m", secs / 60),
+        secs @ 3600..86_400 => format!("{}h", secs / 3600),
+        secs => format!("{}d", secs / 86_400),
+    }
+}
+
+// How long a copy or paste waits for another process to release the store
+const STORE_LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+const STORE_LOCK_POLL: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone, Copy)]
+enum LockMode {
+    Shared,    // Reading stored files
+    Exclusive, // Replacing or deleting them
+}
+
+/// Advisory flock on the store's lock file, released when dropped. Only
+/// renames and deletions take it exclusively, so writers hold it briefly;
+/// never nest two in one process, since separate handles conflict.
+struct StoreLock {
+    _file: Option<File>,
+}
+
+impl StoreLock {
+    fn acquire(mode: LockMode) -> io::Result<Self> {
+        let path = get_lock_path();
+        let opened = open_stored(
+            &path,
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false),
+        );
+        let file = match (opened, mode) {
+            (Ok(file), _) => file,
+            (Err(e), LockMode::Exclusive) => return Err(e),
+            // A store we can only read still locks; with nothing stored yet
+            // there is nothing to guard
+            (Err(_), LockMode::Shared) => match open_stored(&path, OpenOptions::new().read(true)) {
+                Ok(file) => file,
+                Err(_) => return Ok(Self { _file: None }),
+            },
+        };
+
+        let deadline = Instant::now() + STORE_LOCK_TIMEOUT;
+        loop {
+            let attempt = match mode {
+                LockMode::Shared => file.try_lock_shared(),
+                LockMode::Exclusive => file.try_lock(),
+            };
+            match attempt {
+                Ok(()) => return Ok(Self { _file: Some(file) }),
+                Err(TryLockError::WouldBlock) if Instant::now() < deadline => {
+                    std::thread::sleep(STORE_LOCK_POLL);
+                }
+                Err(TryLockError::WouldBlock) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "Clipboard store is busy (locked by another process)",
+                    ));
+                }
+                Err(TryLockError::Error(e)) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Parse an octal permission string like "0640" or "0o750". Modes that are
+/// world-writable or lock the owner out of `required` bits are rejected.
+#[cfg(unix)]
+fn parse_mode(raw: &str, required: u32) -> Option<u32> {
+    let digits = raw.trim();
+    let digits = digits.strip_prefix("0o").unwrap_or(digits);
+    let mode = u32::from_str_radix(digits, 8).ok()?;
+    let valid = mode <= 0o777 && mode & required == required && mode & 0o002 == 0;
+    valid.then_some(mode)
+}
+
+#[cfg(unix)]
+fn configured_mode(key: &str, raw: Option<&str>, required: u32, default: u32) -> u32 {
+    let Some(raw) = raw else {
+        return default;
+    };
+    parse_mode(raw, required).unwrap_or_else(|| {
+        eprintln!("Warning: Invalid {key} {raw:?}; using {default:04o}");
+        default
+    })
+}
+
+/// Permissions for stored clipboard files (`file_mode`), resolved once
+#[cfg(unix)]
+fn storage_file_mode() -> u32 {
+    static MODE: OnceLock<u32> = OnceLock::new();
+    *MODE.get_or_init(|| {
+        let raw = load_config().file_mode.as_deref();
+        configured_mode("file_mode", raw, 0o600, DEFAULT_FILE_MODE)
+    })
+}
+
+/// Permissions for a newly created storage directory (`dir_mode`), resolved once
+#[cfg(unix)]
+fn storage_dir_mode() -> u32 {
+    static MODE: OnceLock<u32> = OnceLock::new();
+    *MODE.get_or_init(|| {
+        let raw = load_config().dir_mode.as_deref();
+        configured_mode("dir_mode", raw, 0o700, DEFAULT_DIR_MODE)
+    })
+}
+
+fn is_read_only() -> bool {
+    load_config().read_only.unwrap_or(DEFAULT_READ_ONLY)
+}
+
+/// Delete expired content found while reading. Skipped in read-only mode,
+/// where reads must leave the store exactly as provisioned.
+fn remove_expired(paths: &[&Path]) {
+    if is_read_only() {
+        return;
+    }
+    let Ok(_lock) = StoreLock::acquire(LockMode::Exclusive) else {
+        return;
+    };
+    // A copy may have replaced the content since the caller looked
+    if paths
+        .first()
+        .is_some_and(|path| is_file_fresh(path, load_ttl()))
+    {
+        return;
+    }
+    for path in paths {
+        let _ = remove_stored(path);
+        let _ = remove_stored(&get_primed_path(path));
+    }
+    let _ = prune_index();
+}
+
+fn is_file_non_empty(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|m| m.is_file() && m.len() > 0)
+}
+
+/// What bare `-o` serves: "targets", or the MIME type to paste
+fn default_output_target() -> String {
+    let target = load_config()
+        .default_output_target
+        .as_deref()
+        .unwrap_or(DEFAULT_OUTPUT_TARGET);
+    match target {
+        "targets" => "targets".to_string(),
+        // Fresh text wins; otherwise whatever image is stored
+        "auto"
+            if !is_file_fresh(&get_text_path(), load_ttl())
+                && is_file_fresh(&get_image_path(), load_ttl()) =>
+        {
+            stored_image_mime().unwrap_or("text/plain").to_string()
+        }
+        // Whatever image is stored, in the format it was copied in
+        "image" => stored_image_mime().unwrap_or("image/png").to_string(),
+        mime if mime.contains('/') => mime.to_string(),
+        _ => "text/plain".to_string(),
+    }
+}
+
+fn valid_output_target(target: &str) -> bool {
+    OUTPUT_TARGET_CHOICES.contains(&target) || target.contains('/')
+}
+
+/// What -i without -t copies. A single file or stdin only: several files
+/// are always bundled into an archive.
+fn default_input_mime() -> String {
+    load_config()
+        .default_input_mime
+        .as_deref()
+        .unwrap_or(DEFAULT_INPUT_MIME)
+        .to_string()
+}
+
+/// Targets in the order they were found, each with its aliases (image/jpeg
+/// also as image/jpg, text also as STRING). A name is only listed once.
+#[derive(Debug, Default)]
+struct TargetList {
+    groups: Vec<Vec<String>>,
+    seen: HashSet<String>,
+}
+
+impl TargetList {
+    fn add<S: Into<String>>(&mut self, names: impl IntoIterator<Item = S>) {
+        let group: Vec<String> = names
+            .into_iter()
+            .map(Into::into)
+            .filter(|name| self.seen.insert(name.clone()))
+            .collect();
+        if !group.is_empty() {
+            self.groups.push(group);
+        }
+    }
+
+    fn add_image(&mut self, mime: &str) {
+        let Some(canonical) = canonical_image_mime(mime).filter(|m| image_format_allowed(m)) else {
+            return;
+        };
+        self.add(std::iter::once(canonical).chain(image_mime_aliases(canonical).iter().copied()));
+    }
+
+    fn contains(&self, name: &str) -> bool {
+        self.seen.contains(name)
+    }
+
+    /// Every name, in listing order. Groups whose main type matches a
+    /// `preferred_target_order` pattern come first, in pattern order; the
+    /// rest keep the order they were found in.
+    fn ordered(mut self) -> Vec<String> {
+        if let Some(order) = load_config()
+            .preferred_target_order
+            .as_ref()
+            .filter(|order| !order.is_empty())
+        {
+            let rank = |group: &Vec<String>| {
+                let mime = normalize_mime(&group[0]);
+                order
+                    .iter()
+                    .position(|pattern| mime_matches(pattern, &mime))
+                    .unwrap_or(order.len())
+            };
+            self.groups.sort_by_key(rank);
+        }
+        self.groups.into_iter().flatten().collect()
+    }
+}
+
+fn print_targets() {
+    for name in available_targets().ordered() {
+        println!("{name}");
+    }
+}
+
+/// What -t TARGETS lists. Expired files found on the way are removed.
+fn available_targets() -> TargetList {
+    let ttl = load_ttl();
+    let mut targets = TargetList::default();
+
+    // Check file-based targets (existing logic)
+    let image_path = get_image_path();
+    if is_file_fresh(&image_path, ttl) {
+        if let Some(format) = stored_image_mime() {
+            targets.add_image(format);
+        }
+    } else if image_path.exists() {
+        // Clean up expired image files
+        remove_expired(&[&image_path, &get_image_format_path()]);
+    }
+
+    // Add wl-clipboard targets
+    if wl_clipboard_available()
+        && let Ok(types) = get_wl_clipboard_types()
+    {
+        for typ in types {
+            match typ.as_str() {
+                // Only advertise PNG conversion for BMP
+                "image/bmp" => targets.add_image("image/png"),
+                t if canonical_image_mime(t).is_some() => targets.add_image(t),
+                t if t.starts_with("text/") => targets.add([typ]),
+                _ => {}
+            }
+        }
+    }
+
+    // Archive of copied files, and copied file lists
+    for (path, mime) in [
+        (get_archive_path(), ARCHIVE_MIME),
+        (get_uri_list_path(), URI_LIST_MIME),
+    ] {
+        if is_file_fresh(&path, ttl) {
+            targets.add([mime]);
+        } else if path.exists() {
+            remove_expired(&[&path]);
+        }
+    }
+
+    // Text targets (existing logic)
+    let text_path = get_text_path();
+    if is_file_fresh(&text_path, ttl) && !targets.contains("text/plain;charset=utf-8") {
+        targets.add([stored_mime(&text_path), "STRING".to_string()]);
+    } else if text_path.exists() && !is_file_fresh(&text_path, ttl) {
+        // Clean up expired text file
+        remove_expired(&[&text_path]);
+    }
+
+    if current_copy_source().is_some() {
+        targets.add([SOURCE_PATH_TARGET]);
+    }
+    targets
+}
+
+/// With `suggest_targets`, explain on stderr why `-o -t TYPE` found
+/// nothing by listing what can be pasted instead. The exit code stays 1.
+fn suggest_targets(requested: &str) {
+    if quiet()
+        || !load_config()
+            .suggest_targets
+            .unwrap_or(DEFAULT_SUGGEST_TARGETS)
+    {
+        return;
+    }
+    let available = available_targets().ordered();
+    if available.is_empty() {
+        eprintln!("Error: No content for '{requested}'; nothing is stored");
+    } else {
+        eprintln!(
+            "Error: No content for '{requested}'; available targets: {}",
+            available.join(", ")
+        );
+    }
+}
+
+/// Clockwise rotation requested with -rotate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Rotation {
+    Quarter,
+    Half,
+    ThreeQuarter,
+}
+
+/// Mirror axis requested with -flip
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Flip {
+    Horizontal,
+    Vertical,
+}
+
+/// Per-invocation switches that shape what `-i` stores
+#[derive(Debug, Default, Clone, Copy)]
+struct InputOptions {
+    rotate: Option<Rotation>,
+    flip: Option<Flip>,
+    pin: bool,
+    /// Read stdin as one length-prefixed frame (`--framed`)
+    framed: bool,
+}
+
+/// Per-invocation switches that shape what `-o` emits
+#[derive(Debug, Default, Clone)]
+struct OutputOptions {
+    /// End text output with exactly one newline (never applied to images)
+    ensure_newline: bool,
+    /// History entry to paste instead of the current content (0 = current)
+    index: Option<usize>,
+    /// Write the payload as one length-prefixed frame (`--framed`)
+    framed: bool,
+    /// Write the payload to this file instead of stdout (`--out-file`)
+    out_file: Option<PathBuf>,
+    /// Write binary content even when stdout is a terminal (`-force`)
+    force_binary: bool,
+}
+
+/// `--framed` carries one clipboard item as a frame: its length in bytes as
+/// an unsigned 64-bit little-endian integer, then exactly that many bytes.
+/// A paste with nothing to give writes no frame at all (and exits 1), so a
+/// reader can tell an empty clipboard from empty content (a zero-length
+/// frame). Copies read one frame from stdin and ignore anything after it.
+/// `-t TARGETS` and `-t image` still print plain lines.
+const FRAME_HEADER_LEN: usize = 8;
+
+fn write_text_output(data: &[u8], options: &OutputOptions) -> io::Result<()> {
+    if options.ensure_newline {
+        let mut end = data.len();
+        while end > 0 && data[end - 1] == b'\n' {
+            end -= 1;
+            if end > 0 && data[end - 1] == b'\r' {
+                end -= 1;
+            }
+        }
+        let mut text = data[..end].to_vec();
+        text.push(b'\n');
+        return write_output(&text, options);
+    }
+    write_output(data, options)
+}
+
+/// Write a pasted payload to stdout, or the `--out-file`, framed with `--framed`
+fn write_output(data: &[u8], options: &OutputOptions) -> io::Result<()> {
+    let header = options.framed.then(|| (data.len() as u64).to_le_bytes());
+    if let Some(path) = &options.out_file {
+        return write_out_file(path, header.as_ref().map(<[u8; 8]>::as_slice), data);
+    }
+    let mut stdout = io::stdout().lock();
+    if let Some(header) = header {
+        stdout.write_all(&header)?;
+    }
+    stdout.write_all(data)
+}
+
+/// Write an image or archive, unless stdout is a terminal it would garble.
+/// Pipes, redirects and `--out-file` are unaffected; `-force` writes anyway.
+fn write_binary_output(data: &[u8], options: &OutputOptions) -> io::Result<()> {
+    if options.out_file.is_none() && !options.force_binary && io::stdout().is_terminal() {
+        return Err(io::Error::other(TerminalOutputError(data.len())));
+    }
+    write_output(data, options)
+}
+
+/// A binary paste held back from a terminal; carries the payload size
+#[derive(Debug)]
+struct TerminalOutputError(usize);
+
+impl fmt::Display for TerminalOutputError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Not writing {} bytes of binary data to the terminal; redirect it to a file, \
+             use --out-file, or add -force",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for TerminalOutputError {}
+
+/// A failure to write the `--out-file`, kept apart from the store's own
+/// errors so it exits with `EXIT_WRITE_FAILED` rather than "nothing to paste"
+#[derive(Debug)]
+struct OutFileError(String);
+
+impl fmt::Display for OutFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for OutFileError {}
+
+/// A new file is created private to the user; an existing one is truncated
+/// and keeps its permissions. The file is only opened once there is content,
+/// so a paste with nothing to give leaves the destination untouched.
+fn write_out_file(path: &Path, header: Option<&[u8]>, data: &[u8]) -> io::Result<()> {
+    let write = || {
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let mut file = options.open(path)?;
+        if let Some(header) = header {
+            file.write_all(header)?;
+        }
+        file.write_all(data)?;
+        file.sync_all()
+    };
+    write().map_err(|e| out_file_error(path, &e))
+}
+
+fn out_file_error(path: &Path, err: &io::Error) -> io::Error {
+    io::Error::new(
+        err.kind(),
+        OutFileError(format!("Could not write '{}': {err}", path.display())),
+    )
+}
+
+/// `--out-file` is held to the same policy as a copied file: `allowed_directories`,
+/// `denied_directories` and `follow_symlinks`. A file that doesn't exist yet is
+/// judged by where its directory resolves to.
+fn check_out_file(path: &Path) -> io::Result<()> {
+    if path.is_dir() {
+        return Err(out_file_error(
+            path,
+            &io::Error::new(io::ErrorKind::InvalidInput, "it is a directory"),
+        ));
+    }
+    check_symlinks(path)?;
+    let resolved = match (path.canonicalize(), path.file_name()) {
+        (Ok(canonical), _) => canonical,
+        (Err(_), Some(name)) => {
+            let parent = path
+                .parent()
+                .filter(|parent| !parent.as_os_str().is_empty())
+                .unwrap_or_else(|| Path::new("."));
+            parent
+                .canonicalize()
+                .map_err(|e| out_file_error(path, &e))?
+                .join(name)
+        }
+        (Err(e), None) => return Err(out_file_error(path, &e)),
+    };
+    check_path_policy(&resolved)
+}
+
+/// Read what a copy takes from stdin: everything up to EOF, or with
+/// `--framed` exactly one frame
+fn read_stdin(max_bytes: u64, framed: bool) -> io::Result<Vec<u8>> {
+    if !framed {
+        return read_limited(io::stdin(), max_bytes);
+    }
+    let mut stdin = io::stdin();
+    let mut header = [0u8; FRAME_HEADER_LEN];
+    stdin.read_exact(&mut header).map_err(|e| {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Framed input ended before its 8-byte length header",
+            )
+        } else {
+            e
+        }
+    })?;
+    let length = u64::from_le_bytes(header);
+    if length > max_bytes {
+        return Err(io::Error::new(
+            io::ErrorKind::FileTooLarge,
+            "Input too large",
+        ));
+    }
+    let data = read_limited(stdin.take(length), length)?;
+    if (data.len() as u64) < length {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Framed input ended after {} of {length} bytes", data.len()),
+        ));
+    }
+    Ok(data)
+}
+
+#[allow(clippy::too_many_lines)]
+fn output_type(mime: &str, options: &OutputOptions) -> io::Result<i32> {
+    if mime.trim().eq_ignore_ascii_case("image") {
+        return Ok(print_stored_image_format());
+    }
+    // Checked on output too, so a type denied later can't be served from an old file
+    check_mime_policy(mime)?;
+    let mime = canonical_image_mime(mime).unwrap_or(mime);
+    match mime {
+        SOURCE_PATH_TARGET => output_source_path(options),
+        m if m.starts_with("text/plain") => {
+            let text_path = get_text_path();
+            let ttl = load_ttl();
+            let stored = {
+                let _lock = StoreLock::acquire(LockMode::Shared)?;
+                if is_file_fresh(&text_path, ttl) {
+                    Some(read_payload(&text_path))
+                } else {
+                    None
+                }
+            };
+            if let Some(result) = stored {
+                let Some(buffer) = reject_corrupt(result, || clear_stored(&[&text_path]))? else {
+                    return Ok(EXIT_NO_CONTENT);
+                };
+                write_text_output(&buffer, options)?;
+                refresh_ttl(&text_path);
+                return Ok(EXIT_SUCCESS);
+            }
+
+            // Clean up expired file
+            if text_path.exists() {
+                remove_expired(&[&text_path]);
+            }
+
+            // Try wl-clipboard if available
+            if wl_clipboard_available()
+                && let Ok(types) = get_wl_clipboard_types()
+                && types.iter().any(|t| t.starts_with("text/"))
+                && let Ok(data) = fetch_from_wl_clipboard("text/plain")
+            {
+                write_text_output(&data, options)?;
+                return Ok(EXIT_SUCCESS);
+            }
+            Ok(EXIT_NO_CONTENT)
+        }
+        m if canonical_image_mime(m).is_some() && !image_format_allowed(m) => {
+            Ok(unsupported_image_format(m))
+        }
+        m if canonical_image_mime(m).is_some() => {
+            let image_path = get_image_path();
+            let ttl = load_ttl();
+
+            // Priority 1: Check wl-clipboard first (always has the latest)
+            if wl_clipboard_available()
+                && let Ok(types) = get_wl_clipboard_types()
+            {
+                // Direct format available?
+                if types.contains(&mime.to_string())
+                    && let Ok(data) = fetch_from_wl_clipboard(mime)
+                {
+                    // Apply downscaling if configured
+                    let processed = downscale_image_if_needed(&data, mime, image_size_limits());
+
+                    write_binary_output(&processed, options)?;
+                    return Ok(EXIT_SUCCESS);
+                }
+
+                // Special case: BMP → PNG conversion ONLY
+                if mime == "image/png"
+                    && types.contains(&"image/bmp".to_string())
+                    && let Ok(bmp_data) = fetch_from_wl_clipboard("image/bmp")
+                {
+                    // Convert BMP to PNG
+                    let img = decode_image(&bmp_data)?;
+
+                    let mut output = Cursor::new(Vec::new());
+                    img.write_to(&mut output, ImageFormat::Png)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                    let png_data = output.into_inner();
+
+                    // Apply downscaling
+                    let config = load_config();
+                    let processed =
+                        downscale_image_if_needed(&png_data, "image/png", image_size_limits());
+
+                    // Cache if configured
+                    if config.cache_wl_images.unwrap_or(DEFAULT_CACHE_WL_IMAGES) && !is_read_only()
+                    {
+                        ensure_storage_directory()?;
+                        write_stored_image(&image_path, &processed, "image/png", false)?;
                     }
+
+                    write_binary_output(&processed, options)?;
+                    return Ok(EXIT_SUCCESS);
+                }
+            }
+
+            // Priority 2: Fall back to cached file if still fresh. The lock
+            // keeps the format and the image from two different copies apart.
+            let stored = {
+                let _lock = StoreLock::acquire(LockMode::Shared)?;
+                if is_file_fresh(&image_path, ttl) && stored_image_mime() == Some(mime) {
+                    Some(read_payload(&image_path))
+                } else {
+                    None
+                }
+            };
+            if let Some(result) = stored {
+                let discard = || clear_stored(&[&image_path, &get_image_format_path()]);
+                let Some(buffer) = reject_corrupt(result, discard)? else {
+                    return Ok(EXIT_NO_CONTENT);
+                };
+                write_binary_output(&buffer, options)?;
+                refresh_ttl(&image_path);
+                return Ok(EXIT_SUCCESS);
+            }
+
+            // Clean up expired files
+            if image_path.exists() && !is_file_fresh(&image_path, ttl) {
+                remove_expired(&[&image_path, &get_image_format_path()]);
+            }
+
+            Ok(EXIT_NO_CONTENT)
+        }
+        ARCHIVE_MIME | URI_LIST_MIME => {
+            let path = if mime == ARCHIVE_MIME {
+                get_archive_path()
+            } else {
+                get_uri_list_path()
+            };
+            if is_file_fresh(&path, load_ttl()) {
+                let stored = {
+                    let _lock = StoreLock::acquire(LockMode::Shared)?;
+                    read_payload(&path)
+                };
+                let Some(buffer) = reject_corrupt(stored, || clear_stored(&[&path]))? else {
+                    return Ok(EXIT_NO_CONTENT);
+                };
+                if mime == ARCHIVE_MIME {
+                    write_binary_output(&buffer, options)?;
+                } else {
+                    write_output(&buffer, options)?;
+                }
+                refresh_ttl(&path);
+                return Ok(EXIT_SUCCESS);
+            }
+            if path.exists() {
+                remove_expired(&[&path]);
+            }
+
+            // Files copied in Windows Explorer, if the fallback is on
+            if mime == URI_LIST_MIME
+                && windows_file_fallback_enabled()
+                && let Some(uris) = windows_file_drop_list()
+            {
+                write_output(uris.as_bytes(), options)?;
+                return Ok(EXIT_SUCCESS);
+            }
+            Ok(EXIT_NO_CONTENT)
+        }
+        _ => Ok(EXIT_NO_CONTENT),
+    }
+}
+
+/// With `refresh_ttl_on_read`, restart a payload's TTL after it was pasted
+/// by moving its prime time to now. Read-only stores are left as they are,
+/// and a failed refresh only costs the extension, never the paste.
+fn refresh_ttl(payload: &Path) {
+    if !load_config()
+        .refresh_ttl_on_read
+        .unwrap_or(DEFAULT_REFRESH_TTL_ON_READ)
+        || is_read_only()
+    {
+        return;
+    }
+    let ttl = load_ttl();
+    if ttl == NEVER_EXPIRES || is_pinned() {
+        return;
+    }
+    let Ok(name) = payload_name(payload) else {
+        return;
+    };
+    let refreshed = StoreLock::acquire(LockMode::Exclusive).and_then(|_lock| {
+        // Replaced or removed since it was read: nothing of ours to refresh
+        if !payload.exists() {
+            return Ok(false);
+        }
+        let mut found = false;
+        update_index(|index| {
+            if let Some(entry) = index.entries.get_mut(&name) {
+                entry.primed = now_millis();
+                found = true;
+            }
+        })?;
+        Ok(found)
+    });
+    match refreshed {
+        Ok(true) if verbose() => {
+            eprintln!(
+                "Refreshed TTL of {}: expires in {}s",
+                payload.display(),
+                ttl.as_secs()
+            );
+        }
+        Ok(_) => {}
+        Err(e) => {
+            if verbose() {
+                eprintln!("Could not refresh TTL of {}: {e}", payload.display());
+            }
+        }
+    }
+}
+
+/// `-o -t image`: print the MIME type of the fresh stored image, not its bytes
+fn print_stored_image_format() -> i32 {
+    let image_path = get_image_path();
+    if !is_file_fresh(&image_path, load_ttl()) {
+        return EXIT_NO_CONTENT;
+    }
+    let Some(stored_format) = stored_image_mime() else {
+        return EXIT_NO_CONTENT;
+    };
+    println!("{stored_format}");
+    EXIT_SUCCESS
+}
+
+/// Image types the bridge stores, with the aliases each is also served as.
+/// Everything past input/output parsing works with the canonical name only.
+const IMAGE_MIMES: &[(&str, &[&str])] = &[
+    ("image/png", &[]),
+    ("image/jpeg", &["image/jpg"]),
+    ("image/gif", &[]),
+    ("image/webp", &[]),
+    #[cfg(feature = "avif")]
+    ("image/avif", &[]),
+];
+
+/// Windows bitmap types accepted for copying; they are stored as PNG
+const BITMAP_MIMES: &[&str] = &["image/bmp", "image/x-bmp", "image/x-ms-bmp", "image/dib"];
+
+/// `data` as a BMP file, if it is one or is a bare DIB: a bitmap without its
+/// 14-byte file header, which is what the Windows clipboard (`CF_DIB`) and
+/// `Get-Clipboard -Format Image` pipelines hand over
+fn bitmap_file(data: &[u8]) -> Option<Cow<'_, [u8]>> {
+    match image::guess_format(data) {
+        Ok(ImageFormat::Bmp) => Some(Cow::Borrowed(data)),
+        Ok(_) => None,
+        Err(_) => {
+            let offset = dib_pixel_offset(data)?;
+            let file_size = u32::try_from(BMP_FILE_HEADER_LEN + data.len()).ok()?;
+            let mut file = Vec::with_capacity(BMP_FILE_HEADER_LEN + data.len());
+            file.extend_from_slice(b"BM");
+            file.extend_from_slice(&file_size.to_le_bytes());
+            file.extend_from_slice(&[0; 4]); // Reserved
+            file.extend_from_slice(&offset.to_le_bytes());
+            file.extend_from_slice(data);
+            Some(Cow::Owned(file))
+        }
+    }
+}
+
+const BMP_FILE_HEADER_LEN: usize = 14;
+
+/// HEIC/HEIF (Apple photos, some Windows captures), accepted for copying
+/// and stored as PNG. Decoding needs the `heic` cargo feature and libheif.
+const HEIF_MIMES: &[&str] = &["image/heic", "image/heif", "image/heic-sequence"];
+
+/// ISO BMFF brands of HEVC-coded HEIF files
+const HEIC_BRANDS: &[&[u8]] = &[b"heic", b"heix", b"heim", b"heis", b"hevc", b"hevx"];
+
+/// Whether `data` starts with the `ftyp` box of a HEIC/HEIF file. The
+/// generic `mif1`/`msf1` brands are shared with AVIF, which is left out.
+fn is_heif(data: &[u8]) -> bool {
+    if data.get(4..8) != Some(b"ftyp") {
+        return false;
+    }
+    let Some(size) = data
+        .first_chunk()
+        .map(|size| u32::from_be_bytes(*size) as usize)
+    else {
+        return false;
+    };
+    let Some(major) = data.get(8..12) else {
+        return false;
+    };
+    let compatible = data.get(16..size.min(data.len())).unwrap_or_default();
+    let mut brands = std::iter::once(major).chain(compatible.chunks_exact(4));
+    let is_avif = brands
+        .clone()
+        .any(|brand| brand == b"avif" || brand == b"avis");
+    brands.any(|brand| HEIC_BRANDS.contains(&brand))
+        || (!is_avif && matches!(major, b"heif" | b"mif1" | b"msf1"))
+}
+
+/// Decode the primary image of a HEIC/HEIF file (with its rotation, mirroring
+/// and crop applied by libheif) and re-encode it as PNG
+#[cfg(feature = "heic")]
+fn heif_to_png(data: &[u8]) -> io::Result<Vec<u8>> {
+    use libheif_rs::{ColorSpace, HeifContext, HeifError, LibHeif, RgbChroma};
+
+    let _timer = PhaseTimer::start("decode");
+    let invalid = |e: HeifError| io::Error::new(io::ErrorKind::InvalidData, e);
+    let context = HeifContext::read_from_bytes(data).map_err(invalid)?;
+    let handle = context.primary_image_handle().map_err(invalid)?;
+    check_dimensions(handle.width(), handle.height())?;
+    let decoded = LibHeif::new()
+        .decode(&handle, ColorSpace::Rgb(RgbChroma::Rgba), None)
+        .map_err(invalid)?;
+    let planes = decoded.planes();
+    let plane = planes.interleaved.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "HEIC image has no RGBA plane")
+    })?;
+    // Rows may be padded out to `stride` bytes
+    let row_len = plane.width as usize * 4;
+    let pixels: Vec<u8> = plane
+        .data
+        .chunks(plane.stride)
+        .take(plane.height as usize)
+        .flat_map(|row| &row[..row_len])
+        .copied()
+        .collect();
+    let img = image::RgbaImage::from_raw(plane.width, plane.height, pixels)
+        .map(DynamicImage::ImageRgba8)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Truncated HEIC image"))?;
+    encode_image(&img, "image/png").ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Could not convert the HEIC image to PNG",
+        )
+    })
+}
+
+#[cfg(not(feature = "heic"))]
+fn heif_to_png(_data: &[u8]) -> io::Result<Vec<u8>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "HEIC/HEIF input needs wsl-clip-bridge built with the `heic` feature \
+         (cargo install --features heic, with libheif installed); nothing was copied",
+    ))
+}
+
+/// Where the pixels of a bare DIB start once a file header is put in
+/// front, or None if `data` doesn't start with a plausible bitmap header.
+/// The palette and any `BI_BITFIELDS` masks sit between header and pixels.
+fn dib_pixel_offset(data: &[u8]) -> Option<u32> {
+    let u16_at = |at: usize| Some(u16::from_le_bytes(*data.get(at..)?.first_chunk()?));
+    let u32_at = |at: usize| Some(u32::from_le_bytes(*data.get(at..)?.first_chunk()?));
+    let header_len = u32_at(0)?;
+    // BITMAPCOREHEADER keeps 16-bit sizes and 3-byte palette entries
+    let (planes, bit_count, entry_len) = if header_len == 12 {
+        (u16_at(8)?, u16_at(10)?, 3)
+    } else if matches!(header_len, 40 | 52 | 56 | 108 | 124) {
+        (u16_at(12)?, u16_at(14)?, 4)
+    } else {
+        return None;
+    };
+    if planes != 1 || !matches!(bit_count, 1 | 4 | 8 | 16 | 24 | 32) {
+        return None;
+    }
+    let mut colors = if header_len == 12 { 0 } else { u32_at(32)? };
+    if colors == 0 && bit_count <= 8 {
+        colors = 1 << bit_count;
+    }
+    // BI_BITFIELDS (3) and BI_ALPHABITFIELDS (6) masks follow a 40-byte header
+    let masks = match (header_len, u32_at(16)) {
+        (40, Some(3)) => 12,
+        (40, Some(6)) => 16,
+        _ => 0,
+    };
+    let offset =
+        BMP_FILE_HEADER_LEN as u64 + u64::from(header_len) + u64::from(colors) * entry_len + masks;
+    (offset <= (BMP_FILE_HEADER_LEN + data.len()) as u64)
+        .then(|| u32::try_from(offset).ok())
+        .flatten()
+}
+
+/// Decode a bitmap and re-encode it as PNG
+fn bitmap_to_png(bmp: &[u8]) -> io::Result<Vec<u8>> {
+    let img = decode_image(bmp)?;
+    encode_image(&img, "image/png").ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Could not convert the bitmap to PNG",
+        )
+    })
+}
+
+/// Canonical name of a supported image type (case-insensitive, aliases
+/// resolved, parameters ignored), or None if the bridge can't store it
+fn canonical_image_mime(mime: &str) -> Option<&'static str> {
+    let mime = mime.split(';').next().unwrap_or_default().trim();
+    IMAGE_MIMES
+        .iter()
+        .find(|(canonical, aliases)| {
+            canonical.eq_ignore_ascii_case(mime)
+                || aliases.iter().any(|alias| alias.eq_ignore_ascii_case(mime))
+        })
+        .map(|(canonical, _)| *canonical)
+}
+
+fn image_mime_aliases(canonical: &str) -> &'static [&'static str] {
+    IMAGE_MIMES
+        .iter()
+        .find(|(name, _)| *name == canonical)
+        .map_or(&[], |(_, aliases)| aliases)
+}
+
+/// Canonical form of a MIME type for policy checks: text targets collapse
+/// to text/plain and image aliases to their canonical type
+fn normalize_mime(mime: &str) -> String {
+    if let Some(canonical) = canonical_image_mime(mime) {
+        return canonical.to_string();
+    }
+    let mime = mime.trim().to_ascii_lowercase();
+    match mime.as_str() {
+        m if m.starts_with("text/plain") => "text/plain".to_string(),
+        "string" | "utf8_string" | "text" => "text/plain".to_string(),
+        _ => mime,
+    }
+}
+
+/// Match a policy entry such as "image/png", "image/*" or "*/*"
+fn mime_matches(pattern: &str, mime: &str) -> bool {
+    let pattern = normalize_mime(pattern);
+    match pattern.strip_suffix("/*") {
+        Some("*") => true,
+        Some(major) => mime.split('/').next() == Some(major),
+        None => pattern == mime,
+    }
+}
+
+/// Apply `denied_mime_types` then `allowed_mime_types`; deny always wins
+fn check_mime_policy(mime: &str) -> io::Result<()> {
+    let cfg = load_config();
+    let normalized = normalize_mime(mime);
+    if let Some(pattern) = cfg
+        .denied_mime_types
+        .iter()
+        .flatten()
+        .find(|pattern| mime_matches(pattern, &normalized))
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("MIME type '{mime}' is blocked by denied_mime_types (\"{pattern}\")"),
+        ));
+    }
+    if let Some(allowed) = &cfg.allowed_mime_types
+        && !allowed.is_empty()
+        && !allowed
+            .iter()
+            .any(|pattern| mime_matches(pattern, &normalized))
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("MIME type '{mime}' is not in allowed_mime_types: {allowed:?}"),
+        ));
+    }
+    Ok(())
+}
+
+/// Image format named in `allowed_image_formats` ("png", "jpeg"/"jpg",
+/// "gif", "webp", or the full MIME type), as its canonical MIME type
+fn image_format_mime(name: &str) -> Option<&'static str> {
+    canonical_image_mime(name).or_else(|| canonical_image_mime(&format!("image/{name}")))
+}
+
+/// Whether `allowed_image_formats` lets the bridge store or serve this
+/// canonical image type. An unset or empty list allows every format.
+fn image_format_allowed(mime: &str) -> bool {
+    match &load_config().allowed_image_formats {
+        Some(allowed) if !allowed.is_empty() => allowed
+            .iter()
+            .any(|name| image_format_mime(name) == Some(mime)),
+        _ => true,
+    }
+}
+
+/// Refuse an image type outside `allowed_image_formats`, listing what is
+/// accepted
+fn unsupported_image_format(mime: &str) -> i32 {
+    let accepted: Vec<&str> = IMAGE_MIMES
+        .iter()
+        .map(|(canonical, _)| *canonical)
+        .filter(|canonical| image_format_allowed(canonical))
+        .map(|canonical| match canonical {
+            "image/png" => "PNG",
+            "image/jpeg" => "JPEG",
+            "image/gif" => "GIF",
+            "image/avif" => "AVIF",
+            _ => "WebP",
+        })
+        .collect();
+    let supported = match accepted.as_slice() {
+        [] => "No image formats are allowed".to_string(),
+        [only] => format!("Only {only} is supported"),
+        [first, second] => format!("Only {first} and {second} are supported"),
+        [rest @ .., last] => format!("Only {}, and {last} are supported", rest.join(", ")),
+    };
+    let hint = if cfg!(feature = "avif") || !mime.eq_ignore_ascii_case("image/avif") {
+        ""
+    } else {
+        " AVIF needs wsl-clip-bridge built with the `avif` feature."
+    };
+    eprintln!("Error: Unsupported format '{mime}'. {supported}.{hint}");
+    EXIT_UNSUPPORTED
+}
+
+fn validate_file_access(path: &Path) -> io::Result<()> {
+    // Check file size limit
+    let max_bytes = max_image_bytes();
+    if let Ok(metadata) = fs::metadata(path)
+        && metadata.len() > max_bytes
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::FileTooLarge,
+            format!("File exceeds maximum size of {}", format_size(max_bytes)),
+        ));
+    }
+    check_symlinks(path)?;
+    check_path_policy(path)
+}
+
+/// With `follow_symlinks = false`, refuse a path that is a symlink or passes
+/// through one, rather than judging wherever it resolves to
+fn check_symlinks(path: &Path) -> io::Result<()> {
+    if load_config()
+        .follow_symlinks
+        .unwrap_or(DEFAULT_FOLLOW_SYMLINKS)
+    {
+        return Ok(());
+    }
+    let absolute = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        env::current_dir()?.join(path)
+    };
+    let mut prefix = PathBuf::new();
+    for component in absolute.components() {
+        prefix.push(component);
+        if matches!(component, Component::Normal(_))
+            && fs::symlink_metadata(&prefix).is_ok_and(|m| m.file_type().is_symlink())
+        {
+            let reason = if prefix == absolute {
+                "is a symlink".to_string()
+            } else {
+                format!("goes through symlink '{}'", prefix.display())
+            };
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "Access denied - '{}' {reason} (follow_symlinks = false)",
+                    path.display()
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Apply `denied_directories` and `allowed_directories` to a path
+fn check_path_policy(path: &Path) -> io::Result<()> {
+    check_path_policy_with(load_config(), path)
+}
+
+fn check_path_policy_with(cfg: &BridgeConfig, path: &Path) -> io::Result<()> {
+    let canonical_path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+
+    // Security: denied_directories wins over allowed_directories. An entry
+    // that can't be used would silently widen access, so fail closed.
+    if let Some(denied_dirs) = &cfg.denied_directories {
+        for entry in denied_dirs {
+            let Some(rule) = DirRule::compile(entry) else {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("Access denied - denied_directories entry '{entry}' is unusable"),
+                ));
+            };
+            if rule.covers(&canonical_path) {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!(
+                        "Access denied - file '{}' is in denied directory '{entry}'",
+                        canonical_path.display()
+                    ),
+                ));
+            }
+        }
+    }
+
+    // Security: Check allowed directories if configured
+    // If allowed_directories is set, only those paths are permitted (recursively)
+    // If not set or empty, all paths are allowed
+    if let Some(allowed_dirs) = &cfg.allowed_directories
+        && !allowed_dirs.is_empty()
+    {
+        // Entries that fail to expand or compile are dropped, which can only narrow access
+        let rules: Vec<DirRule> = allowed_dirs
+            .iter()
+            .filter_map(|d| DirRule::compile(d))
+            .collect();
+        let is_allowed = rules.iter().any(|rule| rule.covers(&canonical_path));
+
+        if !is_allowed {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "Access denied - file '{}' is not in allowed directories: {:?}",
+                    canonical_path.display(),
+                    allowed_dirs
+                ),
+            ));
+        }
+    }
+    // No restrictions if allowed_directories is not configured
+    Ok(())
+}
+
+// `*` and `?` stay within one path component; `**` spans several
+const DIR_GLOB_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// An allowed/denied directory entry: a literal prefix or a glob pattern
+#[derive(Debug)]
+enum DirRule {
+    Prefix(PathBuf),
+    Glob(Pattern),
+}
+
+impl DirRule {
+    fn is_glob(entry: &str) -> bool {
+        entry.contains(['*', '?', '['])
+    }
+
+    /// Expand and compile a configured entry; None if it can't be used
+    fn compile(entry: &str) -> Option<Self> {
+        let expanded = expand_path(entry)?;
+        if !Self::is_glob(entry) {
+            return Some(Self::Prefix(expanded));
+        }
+        Pattern::new(&expanded.to_string_lossy())
+            .ok()
+            .map(Self::Glob)
+    }
+
+    /// Whether the (canonical) path is the directory or lies beneath it
+    fn covers(&self, path: &Path) -> bool {
+        match self {
+            Self::Prefix(dir) => path.starts_with(dir),
+            Self::Glob(pattern) => path
+                .ancestors()
+                .any(|dir| pattern.matches_path_with(dir, DIR_GLOB_OPTIONS)),
+        }
+    }
+}
+
+/// Report directory patterns that don't compile, once when the config loads
+fn check_dir_patterns(config: &BridgeConfig) {
+    let lists = [
+        ("allowed_directories", &config.allowed_directories),
+        ("denied_directories", &config.denied_directories),
+    ];
+    for (key, entries) in lists {
+        for entry in entries.iter().flatten().filter(|e| DirRule::is_glob(e)) {
+            // The expanded form is what gets compiled; an unset variable is
+            // reported when the entry is used
+            let Ok(expanded) = expand_vars(entry) else {
+                continue;
+            };
+            if let Err(e) = Pattern::new(&expanded.to_string_lossy()) {
+                eprintln!("Warning: Invalid pattern '{entry}' in {key}: {e}");
+            }
+        }
+    }
+}
+
+/// `jpeg_progressive` in a build that can only write baseline JPEGs
+fn check_jpeg_progressive(config: &BridgeConfig) {
+    if cfg!(not(feature = "progressive-jpeg")) && config.jpeg_progressive == Some(true) {
+        eprintln!(
+            "Warning: jpeg_progressive needs wsl-clip-bridge built with the \
+             `progressive-jpeg` feature; writing baseline JPEGs"
+        );
+    }
+}
+
+/// Report a `webp_mode` that isn't one or that this build can't encode, and
+/// a `webp_quality` that the chosen mode ignores
+fn check_webp_settings(config: &BridgeConfig) {
+    let mode = config.webp_mode.as_deref().unwrap_or(DEFAULT_WEBP_MODE);
+    if !matches!(mode, "lossless" | "lossy") {
+        eprintln!("Warning: Unknown webp_mode '{mode}'; using \"{DEFAULT_WEBP_MODE}\"");
+    }
+    if config.webp_quality.is_some() && mode != "lossy" {
+        eprintln!("Warning: webp_quality only applies with webp_mode = \"lossy\"; ignoring it");
+    }
+    if cfg!(not(feature = "lossy-webp")) && mode == "lossy" {
+        eprintln!(
+            "Warning: webp_mode = \"lossy\" needs wsl-clip-bridge built with the \
+             `lossy-webp` feature; writing lossless WebPs"
+        );
+    }
+}
+
+/// Report `allowed_image_formats` entries that name no supported format
+fn check_image_formats(config: &BridgeConfig) {
+    for name in config.allowed_image_formats.iter().flatten() {
+        if image_format_mime(name).is_none() {
+            eprintln!("Warning: Unknown image format '{name}' in allowed_image_formats");
+        }
+    }
+}
+
+/// Expand a leading `~` and `$VAR`/`${VAR}` references in a configured path.
+/// Returns None (after warning) if a referenced variable is unset or empty,
+/// so the entry is skipped rather than matched literally or from `/`.
+fn expand_path(raw: &str) -> Option<PathBuf> {
+    expand_vars(raw)
+        .inspect_err(|var| eprintln!("Warning: Skipping path '{raw}': ${var} is not set"))
+        .ok()
+}
+
+/// `expand_path` without the warning: the name of the unset variable on
+/// failure
+fn expand_vars(raw: &str) -> Result<PathBuf, String> {
+    let mut expanded = String::with_capacity(raw.len());
+    let mut rest = raw;
+    if rest == "~" || rest.starts_with("~/") {
+        let Some(home) = home_dir() else {
+            return Err("HOME".to_string());
+        };
+        expanded.push_str(&home);
+        rest = &rest[1..];
+    }
+
+    while let Some(pos) = rest.find('$') {
+        expanded.push_str(&rest[..pos]);
+        let after = &rest[pos + 1..];
+        let (name, consumed) = after.strip_prefix('{').map_or_else(
+            || {
+                let end = after
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(after.len());
+                (&after[..end], end)
+            },
+            |braced| {
+                braced
+                    .find('}')
+                    .map_or(("", 0), |end| (&braced[..end], end + 2))
+            },
+        );
+        if name.is_empty() {
+            // A lone `$` is kept literally
+            expanded.push('$');
+            rest = after;
+            continue;
+        }
+        let Some(value) = env::var(name).ok().filter(|value| !value.is_empty()) else {
+            return Err(name.to_string());
+        };
+        expanded.push_str(&value);
+        rest = &after[consumed..];
+    }
+    expanded.push_str(rest);
+    Ok(PathBuf::from(expanded))
+}
+
+/// Read all of `reader`, failing with `FileTooLarge` once more than
+/// `max_bytes` have been seen. Never buffers more than `max_bytes + 1`.
+fn read_limited(reader: impl Read, max_bytes: u64) -> io::Result<Vec<u8>> {
+    let _timer = PhaseTimer::start("read");
+    let mut buffer = Vec::new();
+    reader
+        .take(max_bytes.saturating_add(1))
+        .read_to_end(&mut buffer)?;
+    if buffer.len() as u64 > max_bytes {
+        return Err(io::Error::new(
+            io::ErrorKind::FileTooLarge,
+            "Input too large",
+        ));
+    }
+    Ok(buffer)
+}
+
+/// The file size limit every size check uses, in bytes; a configured
+/// limit of 0 disables it
+fn max_image_bytes() -> u64 {
+    match configured_max_file_size() {
+        Some(0) => u64::MAX,
+        Some(bytes) => bytes,
+        None => DEFAULT_MAX_FILE_SIZE_MB * 1024 * 1024,
+    }
+}
+
+fn max_text_bytes() -> u64 {
+    match load_config().max_text_size_kb {
+        Some(0) => u64::MAX,
+        Some(kb) => kb.saturating_mul(1024),
+        None => DEFAULT_MAX_TEXT_SIZE_KB * 1024,
+    }
+}
+
+/// Width and height limits for downscaling (0 = unconstrained). Each axis
+/// uses its own key when set and falls back to `max_image_dimension`.
+fn image_size_limits() -> (u32, u32) {
+    let config = load_config();
+    let fallback = config
+        .max_image_dimension
+        .unwrap_or(DEFAULT_MAX_IMAGE_DIMENSION);
+    (
+        config.max_image_width.unwrap_or(fallback),
+        config.max_image_height.unwrap_or(fallback),
+    )
+}
+
+#[allow(
+    clippy::cast_precision_loss,
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss
+)]
+fn downscale_image_if_needed(
+    data: &[u8],
+    mime: &str,
+    (max_width, max_height): (u32, u32),
+) -> Vec<u8> {
+    // If no limit configured, return original
+    if max_width == 0 && max_height == 0 {
+        return data.to_vec();
+    }
+
+    // Decoding keeps only the first frame, so animations pass through untouched
+    if is_animated(data, mime) {
+        return data.to_vec();
+    }
+
+    // The axis furthest over its limit decides the scale; never upscale
+    let ratio = |size: u32, max: u32| {
+        if max == 0 {
+            0.0
+        } else {
+            size as f32 / max as f32
+        }
+    };
+    let scale =
+        |(width, height): (u32, u32)| ratio(width, max_width).max(ratio(height, max_height));
+
+    // The header is enough to see that an image fits; only one over the
+    // limit (or with a header that can't be read) is decoded
+    if header_dimensions(data).is_some_and(|size| scale(size) <= 1.0) {
+        return data.to_vec();
+    }
+
+    // Try to load the image
+    let Ok(img) = decode_image(data) else {
+        return data.to_vec(); // If can't load, return original
+    };
+
+    let (width, height) = (img.width(), img.height());
+    let factor = scale((width, height));
+    if factor <= 1.0 {
+        return data.to_vec();
+    }
+
+    // Calculate new dimensions preserving aspect ratio
+    let new_width = ((width as f32 / factor).round() as u32).max(1);
+    let new_height = ((height as f32 / factor).round() as u32).max(1);
+
+    let resized = {
+        let _timer = PhaseTimer::start("downscale");
+        resize_image(&img, new_width, new_height)
+    };
+
+    // Encode back to original format; if that fails, return original
+    let Some(encoded) = encode_image(&resized, mime) else {
+        return data.to_vec();
+    };
+
+    // Re-encoding an already well-compressed image can inflate it; optionally
+    // look for a smaller encoding then. The original is never kept, since it
+    // breaks the dimension limit.
+    let never_grow = load_config()
+        .downscale_never_grow
+        .unwrap_or(DEFAULT_DOWNSCALE_NEVER_GROW);
+    if never_grow && encoded.len() >= data.len() {
+        return shrink_encoding(&resized, mime, encoded, data.len());
+    }
+    encoded
+}
+
+/// Cheaper encodings of `img` than `encoded`: lower JPEG quality steps until
+/// one is smaller than `target` bytes, or PNG at maximum compression. The
+/// smallest attempt wins, even if none gets below `target`.
+fn shrink_encoding(img: &DynamicImage, mime: &str, encoded: Vec<u8>, target: usize) -> Vec<u8> {
+    let mut smallest = encoded;
+    match mime {
+        "image/jpeg" => {
+            let quality = jpeg_quality();
+            for step in JPEG_QUALITY_STEPS
+                .into_iter()
+                .filter(|&step| step < quality)
+            {
+                let Some(candidate) = encode_jpeg(img, step) else {
+                    break;
+                };
+                if candidate.len() < smallest.len() {
+                    smallest = candidate;
+                }
+                if smallest.len() < target {
+                    break;
+                }
+            }
+        }
+        "image/png" => {
+            if let Some(candidate) = encode_png(img, CompressionType::Best)
+                && candidate.len() < smallest.len()
+            {
+                smallest = candidate;
+            }
+        }
+        _ => {}
+    }
+    if verbose() && smallest.len() >= target {
+        eprintln!(
+            "Downscaled image is {} bytes, larger than the {target} byte original; \
+             keeping it for max_image_dimension",
+            smallest.len()
+        );
+    }
+    smallest
+}
+
+// Lossy steps tried before shrinking; below this JPEG artifacts get obvious
+const JPEG_QUALITY_STEPS: [u8; 5] = [85, 75, 65, 55, 45];
+// Shrinking stops here so a tiny limit can't squeeze an image to nothing
+const MIN_SQUEEZE_DIMENSION: u32 = 64;
+
+fn encode_jpeg(img: &DynamicImage, quality: u8) -> Option<Vec<u8>> {
+    let _timer = PhaseTimer::start("encode");
+    let rgb = flatten_alpha(img, jpeg_background_color());
+    if load_config()
+        .jpeg_progressive
+        .unwrap_or(DEFAULT_JPEG_PROGRESSIVE)
+        && let Some(encoded) = encode_progressive_jpeg(&rgb, quality)
+    {
+        return Some(encoded);
+    }
+    let mut output = Vec::new();
+    let encoder = JpegEncoder::new_with_quality(&mut output, quality);
+    DynamicImage::ImageRgb8(rgb)
+        .write_with_encoder(encoder)
+        .ok()?;
+    Some(output)
+}
+
+/// A progressive JPEG through jpeg-encoder, since the image crate only
+/// writes baseline ones. None for images wider or taller than JPEG allows,
+/// which the caller then encodes as baseline (and the image crate rejects).
+#[cfg(feature = "progressive-jpeg")]
+fn encode_progressive_jpeg(rgb: &image::RgbImage, quality: u8) -> Option<Vec<u8>> {
+    let width = u16::try_from(rgb.width()).ok()?;
+    let height = u16::try_from(rgb.height()).ok()?;
+    let mut output = Vec::new();
+    let mut encoder = jpeg_encoder::Encoder::new(&mut output, quality);
+    encoder.set_progressive(true);
+    encoder
+        .encode(rgb.as_raw(), width, height, jpeg_encoder::ColorType::Rgb)
+        .ok()?;
+    Some(output)
+}
+
+#[cfg(not(feature = "progressive-jpeg"))]
+const fn encode_progressive_jpeg(_rgb: &image::RgbImage, _quality: u8) -> Option<Vec<u8>> {
+    None
+}
+
+/// Re-encode an image until it fits `max_encoded_size_kb`: lower JPEG quality
+/// first, then shrink the dimensions by a quarter per step (all formats).
+#[allow(
+    clippy::cast_precision_loss,
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss
+)]
+fn fit_encoded_size(data: Vec<u8>, mime: &str) -> io::Result<Vec<u8>> {
+    let limit_kb = load_config()
+        .max_encoded_size_kb
+        .unwrap_or(DEFAULT_MAX_ENCODED_SIZE_KB);
+    let limit = usize::try_from(limit_kb.saturating_mul(1024)).unwrap_or(usize::MAX);
+    if limit_kb == 0 || data.len() <= limit {
+        return Ok(data);
+    }
+
+    // Re-encoding would flatten animations, and some formats have no encoder
+    let img = if is_animated(&data, mime) || image_format_for_mime(mime).is_none() {
+        None
+    } else {
+        decode_image(&data).ok()
+    };
+    let Some(img) = img else {
+        eprintln!("Warning: Can't re-encode {mime} to fit max_encoded_size_kb; storing it as is");
+        return Ok(data);
+    };
+
+    let is_jpeg = mime == "image/jpeg";
+    let mut smallest = data.len();
+    let report = |encoded: &[u8], quality: Option<u8>, width: u32, height: u32| {
+        if verbose() {
+            let quality = quality.map_or_else(String::new, |q| format!(", quality {q}"));
+            eprintln!(
+                "Re-encoded to {} KB ({width}x{height}{quality}) to fit max_encoded_size_kb = {limit_kb}",
+                encoded.len().div_ceil(1024)
+            );
+        }
+    };
+
+    if is_jpeg {
+        for quality in JPEG_QUALITY_STEPS {
+            let Some(encoded) = encode_jpeg(&img, quality) else {
+                break;
+            };
+            if encoded.len() <= limit {
+                report(&encoded, Some(quality), img.width(), img.height());
+                return Ok(encoded);
+            }
+            smallest = smallest.min(encoded.len());
+        }
+    }
+
+    // Scale from the decoded original each step so quality doesn't compound
+    let floor_quality = JPEG_QUALITY_STEPS[JPEG_QUALITY_STEPS.len() - 1];
+    let mut scale = 1.0_f32;
+    loop {
+        scale *= 0.75;
+        let width = (img.width() as f32 * scale) as u32;
+        let height = (img.height() as f32 * scale) as u32;
+        if width.max(height) < MIN_SQUEEZE_DIMENSION || width == 0 || height == 0 {
+            break;
+        }
+        let resized = resize_image(&img, width, height);
+        let encoded = if is_jpeg {
+            encode_jpeg(&resized, floor_quality)
+        } else {
+            encode_image(&resized, mime)
+        };
+        let Some(encoded) = encoded else {
+            break;
+        };
+        if encoded.len() <= limit {
+            report(&encoded, is_jpeg.then_some(floor_quality), width, height);
+            return Ok(encoded);
+        }
+        smallest = smallest.min(encoded.len());
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::FileTooLarge,
+        format!(
+            "Image can't be reduced below max_encoded_size_kb = {limit_kb} (smallest attempt was {} KB)",
+            smallest.div_ceil(1024)
+        ),
+    ))
+}
+
+/// Reject an image whose header declares more pixels than `max_image_pixels`,
+/// before anything allocates for a full decode (decompression bombs)
+fn check_pixel_limit(data: &[u8]) -> io::Result<()> {
+    let limit = load_config()
+        .max_image_pixels
+        .unwrap_or(DEFAULT_MAX_IMAGE_PIXELS);
+    if limit == 0 {
+        return Ok(());
+    }
+    // A header we can't read is left to the decoder to reject
+    let Some((width, height)) = header_dimensions(data) else {
+        return Ok(());
+    };
+    check_dimensions(width, height)
+}
+
+/// Width and height from the image header alone, without decoding pixels
+fn header_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .ok()
+        .and_then(|reader| reader.into_dimensions().ok())
+}
+
+/// Reject dimensions over `max_image_pixels`
+fn check_dimensions(width: u32, height: u32) -> io::Result<()> {
+    let limit = load_config()
+        .max_image_pixels
+        .unwrap_or(DEFAULT_MAX_IMAGE_PIXELS);
+    if limit > 0 && u64::from(width) * u64::from(height) > limit {
+        return Err(io::Error::new(
+            io::ErrorKind::FileTooLarge,
+            format!("Image dimensions {width}x{height} exceed max_image_pixels = {limit}"),
+        ));
+    }
+    Ok(())
+}
+
+// Full decodes on this thread, so tests can see which paths skip them
+#[cfg(test)]
+thread_local! {
+    static DECODE_COUNT: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// Fully decode an image, after the header passes the pixel limit
+fn decode_image(data: &[u8]) -> io::Result<DynamicImage> {
+    let _timer = PhaseTimer::start("decode");
+    #[cfg(test)]
+    DECODE_COUNT.with(|count| count.set(count.get() + 1));
+    check_pixel_limit(data)?;
+    let mut reader = ImageReader::new(Cursor::new(data)).with_guessed_format()?;
+    reader.limits(decode_limits());
+    reader
+        .decode()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// 16-bit RGBA, the widest pixel any enabled decoder produces
+const MAX_DECODED_BYTES_PER_PIXEL: u64 = 8;
+
+/// Allocation cap for the decoders, so an image whose header couldn't be
+/// read (or lied) still can't take more memory than `max_image_pixels` allows
+fn decode_limits() -> Limits {
+    let limit = load_config()
+        .max_image_pixels
+        .unwrap_or(DEFAULT_MAX_IMAGE_PIXELS);
+    if limit == 0 {
+        return Limits::no_limits();
+    }
+    let mut limits = Limits::default();
+    limits.max_alloc = Some(limit.saturating_mul(MAX_DECODED_BYTES_PER_PIXEL));
+    limits
+}
+
+/// Whether the image has more than one frame (animated GIF, WebP or PNG)
+fn is_animated(data: &[u8], mime: &str) -> bool {
+    // Counting GIF frames decodes them; an oversized image is never decoded
+    if check_pixel_limit(data).is_err() {
+        return false;
+    }
+    match mime {
+        "image/gif" => GifDecoder::new(Cursor::new(data)).is_ok_and(|mut decoder| {
+            decoder.set_limits(decode_limits()).is_ok() && decoder.into_frames().take(2).count() > 1
+        }),
+        "image/webp" => {
+            WebPDecoder::new(Cursor::new(data)).is_ok_and(|decoder| decoder.has_animation())
+        }
+        "image/png" => PngDecoder::new(Cursor::new(data))
+            .and_then(|decoder| decoder.is_apng())
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+fn image_format_for_mime(mime: &str) -> Option<ImageFormat> {
+    match mime {
+        "image/png" => Some(ImageFormat::Png),
+        "image/jpeg" => Some(ImageFormat::Jpeg),
+        "image/gif" => Some(ImageFormat::Gif),
+        "image/webp" => Some(ImageFormat::WebP),
+        #[cfg(feature = "avif")]
+        "image/avif" => Some(ImageFormat::Avif),
+        _ => None,
+    }
+}
+
+fn encode_image(img: &DynamicImage, mime: &str) -> Option<Vec<u8>> {
+    let _timer = PhaseTimer::start("encode");
+    match image_format_for_mime(mime)? {
+        ImageFormat::Jpeg => encode_jpeg(img, jpeg_quality()),
+        ImageFormat::WebP => encode_webp(img),
+        ImageFormat::Png => encode_png(img, png_compression()),
+        format => {
+            let mut output = Cursor::new(Vec::new());
+            img.write_to(&mut output, format).ok()?;
+            Some(output.into_inner())
+        }
+    }
+}
+
+fn encode_png(img: &DynamicImage, compression: CompressionType) -> Option<Vec<u8>> {
+    let mut output = Vec::new();
+    let encoder = PngEncoder::new_with_quality(&mut output, compression, PngFilter::Adaptive);
+    img.write_with_encoder(encoder).ok()?;
+    Some(output)
+}
+
+/// WebP per `webp_mode`: lossless through the image crate, or lossy at
+/// `webp_quality` where the build can encode it
+fn encode_webp(img: &DynamicImage) -> Option<Vec<u8>> {
+    if webp_mode() == "lossy"
+        && let Some(encoded) = encode_lossy_webp(img, webp_quality())
+    {
+        return Some(encoded);
+    }
+    let mut output = Cursor::new(Vec::new());
+    img.write_to(&mut output, ImageFormat::WebP).ok()?;
+    Some(output.into_inner())
+}
+
+/// A lossy WebP through libwebp, which the image crate can't encode. None
+/// if libwebp refuses the image, which the caller then encodes losslessly.
+#[cfg(feature = "lossy-webp")]
+fn encode_lossy_webp(img: &DynamicImage, quality: u8) -> Option<Vec<u8>> {
+    let quality = f32::from(quality);
+    let encoded = if img.color().has_alpha() {
+        let rgba = img.to_rgba8();
+        webp::Encoder::from_rgba(&rgba, rgba.width(), rgba.height())
+            .encode_simple(false, quality)
+            .ok()?
+            .to_vec()
+    } else {
+        let rgb = img.to_rgb8();
+        webp::Encoder::from_rgb(&rgb, rgb.width(), rgb.height())
+            .encode_simple(false, quality)
+            .ok()?
+            .to_vec()
+    };
+    Some(encoded)
+}
+
+#[cfg(not(feature = "lossy-webp"))]
+const fn encode_lossy_webp(_img: &DynamicImage, _quality: u8) -> Option<Vec<u8>> {
+    None
+}
+
+fn webp_mode() -> &'static str {
+    match load_config().webp_mode.as_deref() {
+        Some("lossy") => "lossy",
+        _ => DEFAULT_WEBP_MODE,
+    }
+}
+
+fn webp_quality() -> u8 {
+    load_config()
+        .webp_quality
+        .unwrap_or(DEFAULT_WEBP_QUALITY)
+        .min(100)
+}
+
+/// Composite a transparent image onto `background`; JPEG has no alpha, and
+/// dropping it would show whatever color hides under transparent pixels
+fn flatten_alpha(img: &DynamicImage, background: [u8; 3]) -> image::RgbImage {
+    if !img.color().has_alpha() {
+        return img.to_rgb8();
+    }
+    let rgba = img.to_rgba8();
+    image::RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let [r, g, b, a] = rgba.get_pixel(x, y).0;
+        let alpha = u32::from(a);
+        let blend = |fg: u8, bg: u8| {
+            let mixed = u32::from(fg) * alpha + u32::from(bg) * (255 - alpha);
+            u8::try_from((mixed + 127) / 255).unwrap_or(u8::MAX)
+        };
+        image::Rgb([
+            blend(r, background[0]),
+            blend(g, background[1]),
+            blend(b, background[2]),
+        ])
+    })
+}
+
+fn jpeg_background_color() -> [u8; 3] {
+    load_config()
+        .jpeg_background_color
+        .unwrap_or(DEFAULT_JPEG_BACKGROUND_COLOR)
+}
+
+fn jpeg_quality() -> u8 {
+    load_config()
+        .jpeg_quality
+        .unwrap_or(DEFAULT_JPEG_QUALITY)
+        .clamp(1, 100)
+}
+
+fn png_compression() -> CompressionType {
+    match load_config().png_compression.as_deref() {
+        Some("default") => CompressionType::Default,
+        Some("best") => CompressionType::Best,
+        _ => CompressionType::Fast,
+    }
+}
+
+/// Scale to exactly `width` x `height` with the configured filter
+fn resize_image(img: &DynamicImage, width: u32, height: u32) -> DynamicImage {
+    let filter = downscale_filter();
+    fast_resize(img, width, height, filter)
+        .unwrap_or_else(|| img.resize_exact(width, height, filter))
+}
+
+/// SIMD resize through `fast_image_resize` for 8-bit images, with the same
+/// filters as `imageops`. None for the other pixel types (16-bit, float),
+/// which `resize_image` leaves to the image crate.
+#[cfg(feature = "fast-resize")]
+fn fast_resize(
+    img: &DynamicImage,
+    width: u32,
+    height: u32,
+    filter: FilterType,
+) -> Option<DynamicImage> {
+    use fast_image_resize::{FilterType as FirFilter, ResizeAlg, ResizeOptions, Resizer};
+
+    let mut resized = match img {
+        DynamicImage::ImageLuma8(_) => DynamicImage::new_luma8(width, height),
+        DynamicImage::ImageLumaA8(_) => DynamicImage::new_luma_a8(width, height),
+        DynamicImage::ImageRgb8(_) => DynamicImage::new_rgb8(width, height),
+        DynamicImage::ImageRgba8(_) => DynamicImage::new_rgba8(width, height),
+        _ => return None,
+    };
+    let algorithm = match filter {
+        FilterType::Nearest => ResizeAlg::Nearest,
+        FilterType::Triangle => ResizeAlg::Convolution(FirFilter::Bilinear),
+        FilterType::CatmullRom => ResizeAlg::Convolution(FirFilter::CatmullRom),
+        FilterType::Gaussian => ResizeAlg::Convolution(FirFilter::Gaussian),
+        FilterType::Lanczos3 => ResizeAlg::Convolution(FirFilter::Lanczos3),
+    };
+    Resizer::new()
+        .resize(
+            img,
+            &mut resized,
+            &ResizeOptions::new().resize_alg(algorithm),
+        )
+        .ok()?;
+    Some(resized)
+}
+
+#[cfg(not(feature = "fast-resize"))]
+const fn fast_resize(
+    _img: &DynamicImage,
+    _width: u32,
+    _height: u32,
+    _filter: FilterType,
+) -> Option<DynamicImage> {
+    None
+}
+
+/// Resampling filter used whenever an image is scaled down. Lanczos3 keeps
+/// text in screenshots sharpest; the others trade quality for speed.
+fn downscale_filter() -> FilterType {
+    match load_config().downscale_filter.as_deref() {
+        Some("nearest") => FilterType::Nearest,
+        Some("triangle") => FilterType::Triangle,
+        Some("catmullrom" | "catmull-rom") => FilterType::CatmullRom,
+        Some("gaussian") => FilterType::Gaussian,
+        _ => FilterType::Lanczos3,
+    }
+}
+
+/// Re-encode an image to `image_output_format`, returning the new data and
+/// MIME type. Anything that can't be converted is kept as it was.
+fn convert_image_format(data: Vec<u8>, mime: &str) -> (Vec<u8>, &str) {
+    let target = match load_config()
+        .image_output_format
+        .as_deref()
+        .unwrap_or(DEFAULT_IMAGE_OUTPUT_FORMAT)
+    {
+        "png" => "image/png",
+        "jpeg" | "jpg" => "image/jpeg",
+        "webp" => "image/webp",
+        "original" => return (data, mime),
+        other => {
+            eprintln!("Warning: Unknown image_output_format {other:?}; keeping {mime}");
+            return (data, mime);
+        }
+    };
+    if target == mime {
+        return (data, mime);
+    }
+    if !image_format_allowed(target) {
+        eprintln!(
+            "Warning: image_output_format {target} is not in allowed_image_formats; keeping {mime}"
+        );
+        return (data, mime);
+    }
+    // Decoding keeps only the first frame, as in the downscale path
+    if is_animated(&data, mime) {
+        eprintln!("Warning: Animated images keep their format ({mime})");
+        return (data, mime);
+    }
+
+    // The EXIF orientation would be lost with the metadata, so bake it in
+    let orientation = exif_orientation(&data);
+    let encoded = decode_image(&data).ok().and_then(|mut img| {
+        img.apply_orientation(orientation);
+        encode_image(&img, target)
+    });
+    let Some(encoded) = encoded else {
+        eprintln!("Warning: Could not convert {mime} to {target}; storing the original");
+        return (data, mime);
+    };
+    (encoded, target)
+}
+
+/// `optimize_png`: re-encode an 8-bit PNG losslessly with a palette (256
+/// colors or fewer) or as grayscale, keeping whichever file is smaller
+fn optimize_png(data: Vec<u8>) -> Vec<u8> {
+    if !load_config().optimize_png.unwrap_or(DEFAULT_OPTIMIZE_PNG)
+        || is_animated(&data, "image/png")
+    {
+        return data;
+    }
+    let Ok(img) = decode_image(&data) else {
+        return data;
+    };
+    // 16-bit and float samples can't be stored in 8 bits without loss
+    if !matches!(
+        img,
+        DynamicImage::ImageLuma8(_)
+            | DynamicImage::ImageLumaA8(_)
+            | DynamicImage::ImageRgb8(_)
+            | DynamicImage::ImageRgba8(_)
+    ) {
+        return data;
+    }
+    let _timer = PhaseTimer::start("optimize");
+    let rgba = img.to_rgba8();
+    let optimized = encode_indexed_png(&rgba).or_else(|| encode_gray_png(&rgba));
+    match optimized {
+        Some(smaller) if smaller.len() < data.len() => {
+            if verbose() {
+                eprintln!(
+                    "Optimized {} byte PNG to {} bytes",
+                    data.len(),
+                    smaller.len()
+                );
+            }
+            smaller
+        }
+        _ => data,
+    }
+}
+
+fn optimized_png_compression() -> png::Compression {
+    match png_compression() {
+        CompressionType::Best => png::Compression::High,
+        CompressionType::Default => png::Compression::Balanced,
+        _ => png::Compression::Fast,
+    }
+}
+
+/// A palette PNG at the smallest bit depth that holds every color, or None
+/// if the image has more than 256 distinct colors
+fn encode_indexed_png(rgba: &image::RgbaImage) -> Option<Vec<u8>> {
+    let mut palette: HashMap<[u8; 4], u8> = HashMap::new();
+    let mut colors: Vec<[u8; 4]> = Vec::new();
+    let mut indices = Vec::with_capacity(rgba.len() / 4);
+    for pixel in rgba.pixels() {
+        let index = if let Some(&index) = palette.get(&pixel.0) {
+            index
+        } else {
+            let index = u8::try_from(colors.len()).ok()?;
+            palette.insert(pixel.0, index);
+            colors.push(pixel.0);
+            index
+        };
+        indices.push(index);
+    }
+    let (depth, bits) = match colors.len() {
+        0..=2 => (png::BitDepth::One, 1),
+        3..=4 => (png::BitDepth::Two, 2),
+        5..=16 => (png::BitDepth::Four, 4),
+        _ => (png::BitDepth::Eight, 8),
+    };
+    // Rows are packed high bits first, each starting on a new byte
+    let per_byte = 8 / bits;
+    let width = rgba.width() as usize;
+    let mut packed = Vec::with_capacity(indices.len() / per_byte + rgba.height() as usize);
+    for row in indices.chunks(width.max(1)) {
+        for group in row.chunks(per_byte) {
+            let byte = group.iter().enumerate().fold(0u8, |byte, (i, &index)| {
+                byte | (index << (8 - bits * (i + 1)))
+            });
+            packed.push(byte);
+        }
+    }
+
+    let mut output = Vec::new();
+    let mut encoder = png::Encoder::new(&mut output, rgba.width(), rgba.height());
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(depth);
+    encoder.set_compression(optimized_png_compression());
+    encoder.set_palette(
+        colors
+            .iter()
+            .flat_map(|c| [c[0], c[1], c[2]])
+            .collect::<Vec<u8>>(),
+    );
+    // tRNS may stop after the last entry that isn't opaque
+    if let Some(last) = colors.iter().rposition(|c| c[3] < u8::MAX) {
+        encoder.set_trns(colors[..=last].iter().map(|c| c[3]).collect::<Vec<u8>>());
+    }
+    let mut writer = encoder.write_header().ok()?;
+    writer.write_image_data(&packed).ok()?;
+    writer.finish().ok()?;
+    Some(output)
+}
+
+/// A grayscale PNG (with alpha only if needed), or None if any pixel has color
+fn encode_gray_png(rgba: &image::RgbaImage) -> Option<Vec<u8>> {
+    if rgba.pixels().any(|p| p[0] != p[1] || p[1] != p[2]) {
+        return None;
+    }
+    let img = if rgba.pixels().all(|p| p[3] == u8::MAX) {
+        DynamicImage::ImageRgba8(rgba.clone()).into_luma8().into()
+    } else {
+        DynamicImage::ImageRgba8(rgba.clone())
+            .into_luma_alpha8()
+            .into()
+    };
+    encode_image(&img, "image/png")
+}
+
+/// `convert_large_png_to_jpeg_kb`: re-encode a PNG over the threshold as
+/// JPEG, unless any pixel is transparent or the JPEG would not be smaller
+fn convert_large_png(data: Vec<u8>) -> (Vec<u8>, &'static str) {
+    let threshold_kb = load_config()
+        .convert_large_png_to_jpeg_kb
+        .unwrap_or(DEFAULT_CONVERT_LARGE_PNG_TO_JPEG_KB);
+    if threshold_kb == 0
+        || (data.len() as u64) <= threshold_kb.saturating_mul(1024)
+        || !image_format_allowed("image/jpeg")
+    {
+        return (data, "image/png");
+    }
+    let Ok(img) = decode_image(&data) else {
+        return (data, "image/png");
+    };
+    let transparent = match &img {
+        DynamicImage::ImageLumaA8(gray) => gray.pixels().any(|p| p[1] < u8::MAX),
+        DynamicImage::ImageRgba8(rgba) => rgba.pixels().any(|p| p[3] < u8::MAX),
+        img if img.color().has_alpha() => img.to_rgba16().pixels().any(|p| p[3] < u16::MAX),
+        _ => false,
+    };
+    if transparent {
+        if verbose() {
+            eprintln!("Kept {} byte PNG: it has transparent pixels", data.len());
+        }
+        return (data, "image/png");
+    }
+    match encode_jpeg(&img, jpeg_quality()) {
+        Some(jpeg) if jpeg.len() < data.len() => {
+            if verbose() {
+                eprintln!(
+                    "Converted {} byte PNG to {} byte JPEG",
+                    data.len(),
+                    jpeg.len()
+                );
+            }
+            (jpeg, "image/jpeg")
+        }
+        _ => {
+            if verbose() {
+                eprintln!("Kept {} byte PNG: as JPEG it is no smaller", data.len());
+            }
+            (data, "image/png")
+        }
+    }
+}
+
+/// Orientation recorded in the image's EXIF data, if any
+fn exif_orientation(data: &[u8]) -> Orientation {
+    ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .ok()
+        .and_then(|reader| reader.into_decoder().ok())
+        .and_then(|mut decoder| decoder.orientation().ok())
+        .unwrap_or(Orientation::NoTransforms)
+}
+
+/// Bake a non-default EXIF orientation into the pixels. Encoders never
+/// write metadata back, so the result is upright with no orientation tag.
+/// None if there is nothing to apply, or the image can't be re-encoded.
+fn bake_orientation(data: &[u8], mime: &str) -> Option<Vec<u8>> {
+    let orientation = exif_orientation(data);
+    if orientation == Orientation::NoTransforms || is_animated(data, mime) {
+        return None;
+    }
+    let encoded = decode_image(data).ok().and_then(|mut img| {
+        img.apply_orientation(orientation);
+        encode_image(&img, mime)
+    });
+    if encoded.is_none() {
+        eprintln!("Warning: Could not re-encode image to apply its orientation");
+    }
+    encoded
+}
+
+/// `apply_exif_orientation`: make a sideways-tagged photo upright before
+/// anything else re-encodes it and loses the tag
+fn apply_exif_orientation(data: Vec<u8>, mime: &str) -> Vec<u8> {
+    if !load_config()
+        .apply_exif_orientation
+        .unwrap_or(DEFAULT_APPLY_EXIF_ORIENTATION)
+    {
+        return data;
+    }
+    bake_orientation(&data, mime).unwrap_or(data)
+}
+
+/// Remove EXIF/XMP metadata (GPS, device identifiers) from an image. A
+/// non-default orientation is baked into the pixels first, which needs a
+/// re-encode; otherwise the metadata is cut out without touching pixel data.
+fn strip_image_metadata(data: &[u8], mime: &str) -> Vec<u8> {
+    if let Some(upright) = bake_orientation(data, mime) {
+        return upright;
+    }
+
+    let stripped = match mime {
+        "image/jpeg" => strip_jpeg_metadata(data),
+        "image/png" => strip_png_metadata(data),
+        "image/webp" => strip_webp_metadata(data),
+        _ => None, // GIF carries no EXIF
+    };
+    stripped.unwrap_or_else(|| data.to_vec())
+}
+
+/// Drop APP1 (EXIF, XMP) and APP13 (IPTC) segments from a JPEG
+fn strip_jpeg_metadata(data: &[u8]) -> Option<Vec<u8>> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    let mut out = data[..2].to_vec();
+    let mut pos = 2;
+    while pos + 4 <= data.len() {
+        if data[pos] != 0xFF {
+            return None;
+        }
+        let marker = data[pos + 1];
+        // Start of scan: the rest is entropy-coded image data
+        if marker == 0xDA {
+            break;
+        }
+        let len = usize::from(u16::from_be_bytes([data[pos + 2], data[pos + 3]]));
+        let end = pos + 2 + len;
+        if len < 2 || end > data.len() {
+            return None;
+        }
+        if marker != 0xE1 && marker != 0xED {
+            out.extend_from_slice(&data[pos..end]);
+        }
+        pos = end;
+    }
+    out.extend_from_slice(&data[pos..]);
+    Some(out)
+}
+
+/// Drop eXIf and textual (tEXt/zTXt/iTXt, which hold XMP) chunks from a PNG
+fn strip_png_metadata(data: &[u8]) -> Option<Vec<u8>> {
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+    if !data.starts_with(SIGNATURE) {
+        return None;
+    }
+    let mut out = SIGNATURE.to_vec();
+    let mut pos = SIGNATURE.len();
+    while pos + 12 <= data.len() {
+        let len = usize::try_from(u32::from_be_bytes(data[pos..pos + 4].try_into().ok()?)).ok()?;
+        let end = pos.checked_add(12)?.checked_add(len)?;
+        if end > data.len() {
+            return None;
+        }
+        let kind = &data[pos + 4..pos + 8];
+        if !matches!(kind, b"eXIf" | b"tEXt" | b"zTXt" | b"iTXt") {
+            out.extend_from_slice(&data[pos..end]);
+        }
+        pos = end;
+    }
+    Some(out)
+}
+
+/// Drop EXIF and XMP chunks from an extended (VP8X) WebP and clear their flags
+fn strip_webp_metadata(data: &[u8]) -> Option<Vec<u8>> {
+    const EXIF_FLAG: u8 = 0x08;
+    const XMP_FLAG: u8 = 0x04;
+    if data.len() < 12 || &data[..4] != b"RIFF" || &data[8..12] != b"WEBP" {
+        return None;
+    }
+    let mut body = b"WEBP".to_vec();
+    let mut pos = 12;
+    while pos + 8 <= data.len() {
+        let len =
+            usize::try_from(u32::from_le_bytes(data[pos + 4..pos + 8].try_into().ok()?)).ok()?;
+        // Chunks are padded to an even length
+        let end = pos
+            .checked_add(8)?
+            .checked_add(len + (len & 1))?
+            .min(data.len());
+        let kind = &data[pos..pos + 4];
+        if kind == b"VP8X" && end > pos + 8 {
+            let mut chunk = data[pos..end].to_vec();
+            chunk[8] &= !(EXIF_FLAG | XMP_FLAG);
+            body.extend_from_slice(&chunk);
+        } else if kind != b"EXIF" && kind != b"XMP " {
+            body.extend_from_slice(&data[pos..end]);
+        }
+        pos = end;
+    }
+    let mut out = b"RIFF".to_vec();
+    out.extend_from_slice(&u32::try_from(body.len()).ok()?.to_le_bytes());
+    out.extend_from_slice(&body);
+    Some(out)
+}
+
+/// Apply the explicit -rotate/-flip flags. Rotation happens before flipping.
+fn transform_image(data: &[u8], mime: &str, options: InputOptions) -> Vec<u8> {
+    if options.rotate.is_none() && options.flip.is_none() {
+        return data.to_vec();
+    }
+    if is_animated(data, mime) {
+        eprintln!("Warning: -rotate/-flip are not supported for animated images; ignoring");
+        return data.to_vec();
+    }
+    let Ok(mut img) = decode_image(data) else {
+        eprintln!("Warning: Could not decode image; storing it untransformed");
+        return data.to_vec();
+    };
+    img = match options.rotate {
+        Some(Rotation::Quarter) => img.rotate90(),
+        Some(Rotation::Half) => img.rotate180(),
+        Some(Rotation::ThreeQuarter) => img.rotate270(),
+        None => img,
+    };
+    img = match options.flip {
+        Some(Flip::Horizontal) => img.fliph(),
+        Some(Flip::Vertical) => img.flipv(),
+        None => img,
+    };
+    encode_image(&img, mime).unwrap_or_else(|| {
+        eprintln!("Warning: Could not re-encode image; storing it untransformed");
+        data.to_vec()
+    })
+}
+
+/// Remove stored payloads (and their sidecars) under the store lock
+fn clear_stored(paths: &[&Path]) -> io::Result<()> {
+    let _lock = StoreLock::acquire(LockMode::Exclusive)?;
+    for path in paths {
+        let _ = remove_stored(path);
+        let _ = remove_stored(&get_primed_path(path));
+    }
+    prune_index()
+}
+
+/// `-clear`: deliberately empty the selection, or with -t only the stored
+/// content of that type. Unlike empty input, this works whatever
+/// `empty_input` says. History is left alone.
+fn clear_selection(mime: Option<&str>, force: bool) -> io::Result<i32> {
+    if is_read_only() {
+        eprintln!("Error: Clipboard is read-only (read_only = true); nothing was cleared");
+        return Ok(EXIT_READ_ONLY);
+    }
+    if is_pinned() && !force {
+        eprintln!("Error: Clipboard content is pinned; use -force to clear it");
+        return Ok(EXIT_PINNED);
+    }
+    let Some(mut paths) = typed_payloads(mime) else {
+        eprintln!(
+            "Error: Unsupported format '{}'; nothing was cleared",
+            mime.unwrap_or_default()
+        );
+        return Ok(EXIT_UNSUPPORTED);
+    };
+    if paths.contains(&get_image_path()) {
+        paths.push(get_image_format_path());
+    }
+    let paths: Vec<&Path> = paths.iter().map(PathBuf::as_path).collect();
+    if !get_storage_directory().exists() {
+        return Ok(EXIT_SUCCESS); // Nothing was ever stored
+    }
+    let cleared = paths.iter().filter(|path| path.exists()).count();
+    clear_stored(&paths)?;
+    // A pin with nothing left to pin would only block the next copy
+    if mime.is_none() {
+        set_pinned(false)?;
+    }
+    if verbose() {
+        eprintln!("Cleared {cleared} files");
+    }
+    Ok(EXIT_SUCCESS)
+}
+
+/// Payloads of the selection that -t picks for `clear` and `touch`: all of
+/// them without -t, None for a type the bridge never stores
+fn typed_payloads(mime: Option<&str>) -> Option<Vec<PathBuf>> {
+    Some(match mime.map(str::trim) {
+        None => vec![
+            get_text_path(),
+            get_image_path(),
+            get_archive_path(),
+            get_uri_list_path(),
+        ],
+        Some(m) if m.starts_with("text/plain") => vec![get_text_path()],
+        Some(m) if m.eq_ignore_ascii_case("image") || canonical_image_mime(m).is_some() => {
+            vec![get_image_path()]
+        }
+        Some(ARCHIVE_MIME) => vec![get_archive_path()],
+        Some(URI_LIST_MIME) => vec![get_uri_list_path()],
+        Some(_) => return None,
+    })
+}
+
+/// `touch`: restart the TTL of the selection's fresh content (only the type
+/// -t names, if given) as if it had just been copied. Exits 1 when nothing
+/// fresh was there to touch; expired content stays expired.
+fn touch_selection(mime: Option<&str>) -> io::Result<i32> {
+    if is_read_only() {
+        eprintln!("Error: Clipboard is read-only (read_only = true); nothing was touched");
+        return Ok(EXIT_READ_ONLY);
+    }
+    let Some(paths) = typed_payloads(mime) else {
+        eprintln!(
+            "Error: Unsupported format '{}'; nothing was touched",
+            mime.unwrap_or_default()
+        );
+        return Ok(EXIT_UNSUPPORTED);
+    };
+    if !get_storage_directory().exists() {
+        return Ok(EXIT_NO_CONTENT);
+    }
+    let ttl = load_ttl();
+    let _lock = StoreLock::acquire(LockMode::Exclusive)?;
+    let names = paths
+        .iter()
+        .filter(|path| is_file_fresh(path, ttl))
+        .map(|path| payload_name(path))
+        .collect::<io::Result<Vec<String>>>()?;
+    let mut touched = 0;
+    if !names.is_empty() {
+        let now = now_millis();
+        update_index(|index| {
+            for name in &names {
+                if let Some(entry) = index.entries.get_mut(name) {
+                    entry.primed = now;
+                    touched += 1;
+                }
+            }
+        })?;
+    }
+    if touched == 0 {
+        return Ok(EXIT_NO_CONTENT);
+    }
+    if verbose() {
+        match ttl {
+            NEVER_EXPIRES => eprintln!("Touched {touched} entries; they never expire"),
+            ttl => eprintln!(
+                "Touched {touched} entries: they expire in {}s",
+                ttl.as_secs()
+            ),
+        }
+    }
+    Ok(EXIT_SUCCESS)
+}
+
+/// First entry of a `dump` archive: an index (without checksums or source
+/// paths) describing the payloads that follow it
+const DUMP_MANIFEST: &str = INDEX_FILE;
+// Tar headers, padding and the manifest on top of the payloads themselves
+const DUMP_OVERHEAD: u64 = 64 * 1024;
+
+/// `dump`: write the fresh stored entries to stdout as a tar archive for
+/// `restore`, here or on another machine. Payloads go in as they would be
+/// pasted, so a dump is plaintext even with `encrypt_storage`. Content only
+/// wl-clipboard holds is not included.
+fn dump_store() -> io::Result<i32> {
+    let ttl = load_ttl();
+    let mut manifest = StoreIndex::default();
+    let mut payloads = Vec::new();
+    {
+        let _lock = StoreLock::acquire(LockMode::Shared)?;
+        for path in payload_paths() {
+            if !is_file_fresh(&path, ttl) {
+                continue;
+            }
+            let name = payload_name(&path)?;
+            let entry = IndexEntry {
+                mime: stored_mime(&path),
+                primed: primed_at(&path).map_or_else(now_millis, system_time_millis),
+                checksum: None,
+                content: None,
+                source: None,
+            };
+            payloads.push((name.clone(), read_payload(&path)?));
+            manifest.entries.insert(name, entry);
+        }
+    }
+    if payloads.is_empty() {
+        eprintln!("Error: Nothing is stored; no dump was written");
+        return Ok(EXIT_NO_CONTENT);
+    }
+
+    let index = serde_json::to_vec_pretty(&manifest)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let mut builder = tar::Builder::new(Vec::new());
+    append_dump_entry(&mut builder, DUMP_MANIFEST, &index, now_millis())?;
+    for (name, data) in &payloads {
+        append_dump_entry(&mut builder, name, data, manifest.entries[name].primed)?;
+    }
+    io::stdout().write_all(&builder.into_inner()?)?;
+    if verbose() {
+        eprintln!("Dumped {} entries", payloads.len());
+    }
+    Ok(EXIT_SUCCESS)
+}
+
+fn append_dump_entry(
+    builder: &mut tar::Builder<Vec<u8>>,
+    name: &str,
+    data: &[u8],
+    primed: u64,
+) -> io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_size(data.len() as u64);
+    header.set_mode(0o600);
+    header.set_mtime(primed / 1000);
+    builder.append_data(&mut header, name, data)
+}
+
+/// One payload read back from a dump
+struct DumpEntry {
+    dest: PathBuf,
+    entry: IndexEntry,
+    data: Vec<u8>,
+}
+
+/// `restore`: replace the stored entries with those of a `dump` on stdin.
+/// The whole archive is read and checked before anything is written, so a
+/// truncated one changes nothing, and each entry is then stored atomically
+/// like a copy. Entries the access policy or size limits forbid are refused
+/// and the rest restored; afterwards the store holds only what was restored.
+/// Entries count as primed now unless `--preserve-age` keeps the dumped times.
+fn restore_store(preserve_age: bool, force: bool) -> io::Result<i32> {
+    if is_read_only() {
+        eprintln!("Error: Clipboard is read-only (read_only = true); nothing was restored");
+        return Ok(EXIT_READ_ONLY);
+    }
+    if is_pinned() && !force {
+        eprintln!("Error: Clipboard content is pinned; use -force to overwrite it");
+        return Ok(EXIT_PINNED);
+    }
+    let max_bytes = max_text_bytes()
+        .saturating_add(max_image_bytes())
+        .saturating_mul(2)
+        .saturating_add(DUMP_OVERHEAD);
+    let archive = match read_limited(io::stdin(), max_bytes) {
+        Ok(archive) => archive,
+        Err(e) if e.kind() == io::ErrorKind::FileTooLarge => {
+            eprintln!("Error: Dump exceeds the configured size limits; nothing was restored");
+            return Ok(EXIT_TOO_LARGE);
+        }
+        Err(e) => return Err(e),
+    };
+    if archive.is_empty() {
+        eprintln!("Error: Input is empty; nothing was restored");
+        return Ok(EXIT_EMPTY_INPUT);
+    }
+    let entries = match parse_dump(&archive) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("Error: Not a usable dump ({e}); nothing was restored");
+            return Ok(EXIT_UNSUPPORTED);
+        }
+    };
+
+    ensure_storage_directory()?;
+    let mut code = EXIT_SUCCESS;
+    let mut restored = Vec::new();
+    for DumpEntry {
+        dest,
+        mut entry,
+        data,
+    } in entries
+    {
+        let name = dest.file_name().unwrap_or_default().to_string_lossy();
+        let refused = check_restored_entry(&entry.mime, &data);
+        if refused != EXIT_SUCCESS {
+            eprintln!("Error: {name} ({}) was not restored", entry.mime);
+            code = refused;
+            continue;
+        }
+        if !preserve_age {
+            entry.primed = now_millis();
+        }
+        if verbose() {
+            eprintln!("Restored {name} ({}, {} bytes)", entry.mime, data.len());
+        }
+        install_payload(&dest, &data, entry, false)?;
+        restored.push(dest);
+    }
+
+    let stale: Vec<PathBuf> = payload_paths()
+        .into_iter()
+        .filter(|path| !restored.contains(path))
+        .chain((!restored.contains(&get_image_path())).then(get_image_format_path))
+        .collect();
+    clear_stored(&stale.iter().map(PathBuf::as_path).collect::<Vec<_>>())?;
+    set_pinned(false)?;
+    Ok(code)
+}
+
+/// Read a dump: its manifest, then one payload per manifest entry, each
+/// complete and of a type that belongs in its file
+fn parse_dump(archive: &[u8]) -> io::Result<Vec<DumpEntry>> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let mut manifest: Option<StoreIndex> = None;
+    let mut entries = Vec::new();
+    let mut reader = tar::Archive::new(archive);
+    for item in reader.entries()? {
+        let mut item = item?;
+        let name = item.path()?.to_string_lossy().into_owned();
+        if item.header().entry_type() != tar::EntryType::Regular {
+            return Err(invalid(format!("'{name}' is not a regular file")));
+        }
+        let mut data = Vec::new();
+        item.read_to_end(&mut data)?;
+        if data.len() as u64 != item.size() {
+            return Err(invalid(format!("'{name}' is truncated")));
+        }
+        let Some(index) = manifest.as_mut() else {
+            if name != DUMP_MANIFEST {
+                return Err(invalid(format!("it doesn't start with {DUMP_MANIFEST}")));
+            }
+            let index = serde_json::from_slice(&data)
+                .map_err(|e| invalid(format!("{DUMP_MANIFEST}: {e}")))?;
+            manifest = Some(index);
+            continue;
+        };
+        let dest = payload_paths()
+            .into_iter()
+            .find(|path| payload_name(path).is_ok_and(|payload| payload == name))
+            .ok_or_else(|| invalid(format!("unexpected entry '{name}'")))?;
+        let entry = index
+            .entries
+            .remove(&name)
+            .ok_or_else(|| invalid(format!("'{name}' is repeated or not in {DUMP_MANIFEST}")))?;
+        if !payload_holds(&dest, &entry.mime, &data) {
+            return Err(invalid(format!("'{name}' doesn't hold {}", entry.mime)));
+        }
+        entries.push(DumpEntry { dest, entry, data });
+    }
+    let manifest = manifest.ok_or_else(|| invalid("it is empty".to_string()))?;
+    if let Some(name) = manifest.entries.keys().next() {
+        return Err(invalid(format!(
+            "'{name}' is missing; the dump may be truncated"
+        )));
+    }
+    Ok(entries)
+}
+
+/// Whether `mime` is a type stored in `dest`, with data to match
+fn payload_holds(dest: &Path, mime: &str, data: &[u8]) -> bool {
+    if dest == get_text_path() {
+        mime.starts_with("text/plain")
+    } else if dest == get_image_path() {
+        canonical_image_mime(mime).is_some_and(|mime| sniff_image_mime(data) == Some(mime))
+    } else if dest == get_archive_path() {
+        mime == ARCHIVE_MIME
+    } else {
+        mime == URI_LIST_MIME
+    }
+}
+
+/// Apply the policy a copy of this entry would face: allowed MIME types and
+/// image formats, size limits, and for a file list its paths. Returns the
+/// exit code of a refusal, after reporting why.
+fn check_restored_entry(mime: &str, data: &[u8]) -> i32 {
+    if let Err(e) = check_mime_policy(mime) {
+        eprintln!("Error: {e}");
+        return exit_code_for_error(&e);
+    }
+    if canonical_image_mime(mime).is_some() && !image_format_allowed(mime) {
+        return unsupported_image_format(mime);
+    }
+    let max_bytes = if mime.starts_with("text/") {
+        max_text_bytes()
+    } else {
+        max_image_bytes()
+    };
+    if data.len() as u64 > max_bytes {
+        eprintln!("Error: {mime} content exceeds the configured size limit");
+        return EXIT_TOO_LARGE;
+    }
+    if mime == URI_LIST_MIME {
+        // The files may not exist here, so only existing ones are resolved
+        for line in String::from_utf8_lossy(data).lines() {
+            let Some(path) = file_uri_path(line.trim()) else {
+                continue;
+            };
+            let path = path.canonicalize().unwrap_or(path);
+            if let Err(e) = check_path_policy(&path) {
+                eprintln!("Error: '{}': {e}", path.display());
+                return exit_code_for_error(&e);
+            }
+        }
+    }
+    EXIT_SUCCESS
+}
+
+/// Zero-byte input would otherwise leave an empty file that reads as "absent",
+/// so either clear the target deliberately or tell the caller nothing was stored
+fn handle_empty_input(paths: &[&Path]) -> i32 {
+    let mode = load_config()
+        .empty_input
+        .as_deref()
+        .unwrap_or(DEFAULT_EMPTY_INPUT);
+    if mode == "clear" {
+        if clear_stored(paths).is_err() {
+            eprintln!("Error: Clipboard store is busy (locked by another process)");
+            return EXIT_BUSY;
+        }
+        return EXIT_SUCCESS;
+    }
+    eprintln!("Error: Input is empty; nothing was copied");
+    EXIT_EMPTY_INPUT
+}
+
+#[allow(clippy::too_many_lines)]
+fn input_type(mime: &str, file: Option<&String>, options: InputOptions) -> io::Result<i32> {
+    check_mime_policy(mime)?;
+    let mime = canonical_image_mime(mime).unwrap_or(mime);
+    ensure_storage_directory()?;
+    match mime {
+        m if m.starts_with("text/plain") => {
+            if options.rotate.is_some() || options.flip.is_some() {
+                eprintln!("Warning: -rotate/-flip only apply to images; ignoring");
+            }
+            let text_path = get_text_path();
+            let max_bytes = max_text_bytes();
+            let read = if let Some(path_str) = file {
+                let path = Path::new(path_str);
+                validate_file_access(path)?;
+                note_copy_source(path);
+                read_limited(File::open(path)?, max_bytes)
+            } else {
+                read_stdin(max_bytes, options.framed)
+            };
+            let buffer = match read {
+                Ok(buffer) => buffer,
+                Err(e) if e.kind() == io::ErrorKind::FileTooLarge => {
+                    eprintln!(
+                        "Error: Text input exceeds maximum size of {}KB",
+                        max_bytes / 1024
+                    );
+                    return Ok(EXIT_TOO_LARGE);
+                }
+                Err(e) => return Err(e),
+            };
+            if buffer.is_empty() {
+                return Ok(handle_empty_input(&[&text_path]));
+            }
+            if let Some((uri_mime, payload)) = parse_data_uri(&buffer) {
+                return store_data_uri(&uri_mime, payload, options);
+            }
+            store_text(&buffer)
+        }
+        m if canonical_image_mime(m).is_some() && !image_format_allowed(m) => {
+            Ok(unsupported_image_format(m))
+        }
+        m if canonical_image_mime(m).is_some()
+            || BITMAP_MIMES.contains(&m)
+            || HEIF_MIMES.contains(&m) =>
+        {
+            let image_path = get_image_path();
+            let format_path = get_image_format_path();
+
+            // Read the image data
+            let max_bytes = max_image_bytes();
+            let read = if let Some(path_str) = file {
+                let path = Path::new(path_str);
+                validate_file_access(path)?;
+                note_copy_source(path);
+                read_limited(File::open(path)?, max_bytes)
+            } else {
+                read_stdin(max_bytes, options.framed)
+            };
+            let img_data = match read {
+                Ok(data) => data,
+                Err(e) if e.kind() == io::ErrorKind::FileTooLarge => {
+                    eprintln!(
+                        "Error: Input exceeds maximum size of {}",
+                        format_size(max_bytes)
+                    );
+                    return Ok(EXIT_TOO_LARGE);
+                }
+                Err(e) => return Err(e),
+            };
+            if img_data.is_empty() {
+                return Ok(handle_empty_input(&[&image_path, &format_path]));
+            }
+            // Bitmaps and HEIC are stored as PNG, whatever type they were labelled with
+            let (converted, source) = if is_heif(&img_data) {
+                (Some(heif_to_png(&img_data)), HEIF_MIMES[0])
+            } else {
+                let bitmap = bitmap_file(&img_data).map(|bmp| bitmap_to_png(&bmp));
+                (bitmap, "image/bmp")
+            };
+            let (img_data, mime) = match converted {
+                Some(_) if !image_format_allowed("image/png") => {
+                    return Ok(unsupported_image_format(source));
+                }
+                Some(Ok(png)) => (png, "image/png"),
+                Some(Err(e)) if e.kind() == io::ErrorKind::FileTooLarge => {
+                    eprintln!("Error: {e}");
+                    return Ok(EXIT_TOO_LARGE);
+                }
+                Some(Err(e)) => return Err(e),
+                None if BITMAP_MIMES.contains(&mime) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Input is not a BMP or DIB bitmap",
+                    ));
+                }
+                None if HEIF_MIMES.contains(&mime) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Input is not a HEIC or HEIF image",
+                    ));
+                }
+                None => (img_data, mime),
+            };
+            store_image(img_data, mime, options)
+        }
+        _ => {
+            // Reject unsupported formats
+            Ok(unsupported_image_format(mime))
+        }
+    }
+}
+
+/// What a successful copy wrote, for -json
+#[derive(Debug, Serialize)]
+struct CopyReport {
+    selection: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    slot: Option<String>,
+    mime: String,
+    bytes: usize,
+    path: PathBuf,
+    ttl_secs: u64, // 0 = never expires
+}
+
+static STORED: OnceLock<CopyReport> = OnceLock::new();
+
+fn record_stored(mime: &str, bytes: usize, path: &Path) {
+    let ttl_secs = match load_ttl() {
+        NEVER_EXPIRES => 0,
+        ttl => ttl.as_secs(),
+    };
+    let _ = STORED.set(CopyReport {
+        selection: active_selection().to_string(),
+        slot: current_slot(),
+        mime: mime.to_string(),
+        bytes,
+        path: path.to_path_buf(),
+        ttl_secs,
+    });
+}
+
+/// Target that pastes the file the current content was copied from
+const SOURCE_PATH_TARGET: &str = "text/x-source-path";
+
+/// File this copy reads with -i, recorded in the index entry it writes
+static COPY_SOURCE: OnceLock<PathBuf> = OnceLock::new();
+
+/// Remember where a copy came from, if `record_source_path` is on. Paths
+/// outside $HOME are never recorded, so pasting one can't reveal the
+/// layout of the rest of the system.
+fn note_copy_source(path: &Path) {
+    if !load_config()
+        .record_source_path
+        .unwrap_or(DEFAULT_RECORD_SOURCE_PATH)
+    {
+        return;
+    }
+    let Ok(canonical) = path.canonicalize() else {
+        return;
+    };
+    if within_home(&canonical) {
+        let _ = COPY_SOURCE.set(canonical);
+    } else if verbose() {
+        eprintln!(
+            "Not recording source path '{}': outside $HOME",
+            canonical.display()
+        );
+    }
+}
+
+fn within_home(path: &Path) -> bool {
+    home_dir()
+        .and_then(|home| Path::new(&home).canonicalize().ok())
+        .is_some_and(|home| home != Path::new("/") && path.starts_with(home))
+}
+
+/// Source path of the most recently copied fresh payload. Content copied
+/// from stdin replaces the payload's entry, so it has none.
+fn current_copy_source() -> Option<PathBuf> {
+    if !load_config()
+        .record_source_path
+        .unwrap_or(DEFAULT_RECORD_SOURCE_PATH)
+    {
+        return None;
+    }
+    let ttl = load_ttl();
+    let _lock = StoreLock::acquire(LockMode::Shared).ok()?;
+    let index = read_index(&get_storage_directory()).ok()?;
+    let (_, latest) = index
+        .entries
+        .iter()
+        .filter(|(name, _)| is_file_fresh(&get_storage_directory().join(name), ttl))
+        .max_by_key(|(_, entry)| entry.primed)?;
+    // HOME may differ from when it was copied
+    latest.source.clone().filter(|source| within_home(source))
+}
+
+fn output_source_path(options: &OutputOptions) -> io::Result<i32> {
+    let Some(source) = current_copy_source() else {
+        return Ok(EXIT_NO_CONTENT);
+    };
+    write_output(source.as_os_str().as_encoded_bytes(), options)?;
+    Ok(EXIT_SUCCESS)
+}
+
+/// One JSON line on stdout; copy is otherwise silent there, like xclip
+fn print_copy_report(report: &CopyReport) {
+    match serde_json::to_string(report) {
+        Ok(line) => println!("{line}"),
+        Err(e) => eprintln!("Warning: Could not format copy report: {e}"),
+    }
+}
+
+/// Text target for stored bytes: only valid UTF-8 may claim that charset
+const fn text_mime(data: &[u8]) -> &'static str {
+    if std::str::from_utf8(data).is_ok() {
+        "text/plain;charset=utf-8"
+    } else {
+        "text/plain"
+    }
+}
+
+fn stored_text_mime(path: &Path) -> &'static str {
+    read_payload(path).map_or("text/plain", |data| text_mime(&data))
+}
+
+fn store_text(buffer: &[u8]) -> io::Result<i32> {
+    // Apply invalid_utf8 before anything is written
+    let transcoded;
+    let buffer = match std::str::from_utf8(buffer) {
+        Ok(_) => buffer,
+        Err(e) => match load_config()
+            .invalid_utf8
+            .as_deref()
+            .unwrap_or(DEFAULT_INVALID_UTF8)
+        {
+            "reject" => {
+                eprintln!(
+                    "Error: Text input is not valid UTF-8 (first bad byte at offset {})",
+                    e.valid_up_to()
+                );
+                return Ok(EXIT_UNSUPPORTED);
+            }
+            // Latin-1 maps every byte to the code point of the same value
+            "transcode" => {
+                transcoded = buffer.iter().map(|&b| char::from(b)).collect::<String>();
+                transcoded.as_bytes()
+            }
+            _ => buffer,
+        },
+    };
+
+    let trimmed;
+    let buffer = if load_config()
+        .trim_trailing_whitespace
+        .unwrap_or(DEFAULT_TRIM_TRAILING_WHITESPACE)
+    {
+        trimmed = trim_trailing_whitespace(buffer);
+        trimmed.as_slice()
+    } else {
+        buffer
+    };
+
+    let text_path = get_text_path();
+    if buffer.is_empty() {
+        return Ok(handle_empty_input(&[&text_path]));
+    }
+    write_stored_entry(&text_path, buffer, text_mime(buffer))?;
+    record_stored(text_mime(buffer), buffer.len(), &text_path);
+    Ok(EXIT_SUCCESS)
+}
+
+/// Drop spaces and tabs at the end of every line, keeping LF and CRLF
+/// endings as they were
+fn trim_trailing_whitespace(text: &[u8]) -> Vec<u8> {
+    let mut trimmed = Vec::with_capacity(text.len());
+    for (i, line) in text.split(|&b| b == b'\n').enumerate() {
+        if i > 0 {
+            trimmed.push(b'\n');
+        }
+        let (body, cr) = line
+            .strip_suffix(b"\r")
+            .map_or((line, false), |body| (body, true));
+        let end = body
+            .iter()
+            .rposition(|&b| b != b' ' && b != b'\t')
+            .map_or(0, |pos| pos + 1);
+        trimmed.extend_from_slice(&body[..end]);
+        if cr {
+            trimmed.push(b'\r');
+        }
+    }
+    trimmed
+}
+
+fn store_image(img_data: Vec<u8>, mime: &str, options: InputOptions) -> io::Result<i32> {
+    let image_path = get_image_path();
+    let mime = match sniff_image_mime(&img_data) {
+        Some(actual) if actual != mime => match correct_image_label(mime, actual) {
+            Ok(actual) => actual,
+            Err(code) => return Ok(code),
+        },
+        _ => mime,
+    };
+    if let Err(e) = check_pixel_limit(&img_data) {
+        return store_oversized_image(&img_data, mime, options, &e);
+    }
+
+    // Make EXIF-rotated photos upright, convert to the configured format,
+    // strip metadata, apply explicit transforms, then optionally downscale
+    let img_data = apply_exif_orientation(img_data, mime);
+    let (img_data, mime) = convert_image_format(img_data, mime);
+    let img_data = if load_config().strip_exif.unwrap_or(DEFAULT_STRIP_EXIF) {
+        strip_image_metadata(&img_data, mime)
+    } else {
+        img_data
+    };
+    let img_data = transform_image(&img_data, mime, options);
+    let processed_data = downscale_image_if_needed(&img_data, mime, image_size_limits());
+    let (processed_data, mime) = match mime {
+        "image/png" => convert_large_png(optimize_png(processed_data)),
+        _ => (processed_data, mime),
+    };
+    let processed_data = match fit_encoded_size(processed_data, mime) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::FileTooLarge => {
+            eprintln!("Error: {e}");
+            return Ok(EXIT_TOO_LARGE);
+        }
+        Err(e) => return Err(e),
+    };
+
+    // Write the (possibly downscaled) image and its format (always the
+    // canonical name)
+    write_stored_image(&image_path, &processed_data, mime, true)?;
+    record_stored(mime, processed_data.len(), &image_path);
+    Ok(EXIT_SUCCESS)
+}
+
+/// An image over `max_image_pixels` is refused, unless
+/// `store_oversized_images` lets it be stored exactly as copied: never
+/// decoded, so it isn't downscaled, converted or stripped either
+fn store_oversized_image(
+    img_data: &[u8],
+    mime: &str,
+    options: InputOptions,
+    err: &io::Error,
+) -> io::Result<i32> {
+    let allowed = load_config()
+        .store_oversized_images
+        .unwrap_or(DEFAULT_STORE_OVERSIZED_IMAGES);
+    if !allowed {
+        eprintln!("Error: {err}");
+        return Ok(EXIT_TOO_LARGE);
+    }
+    if options.rotate.is_some() || options.flip.is_some() {
+        eprintln!("Error: {err}; -rotate/-flip need it decoded");
+        return Ok(EXIT_TOO_LARGE);
+    }
+    if verbose() {
+        eprintln!("{err}; storing it as copied without decoding");
+    }
+    let image_path = get_image_path();
+    write_stored_image(&image_path, img_data, mime, true)?;
+    record_stored(mime, img_data.len(), &image_path);
+    Ok(EXIT_SUCCESS)
+}
+
+/// The type to store an image labelled `labelled` under when its bytes are
+/// `actual`, per `mislabeled_images`; Err carries the exit code of a refusal.
+/// The real type has to pass the same policy a correct label would have.
+fn correct_image_label(labelled: &str, actual: &'static str) -> Result<&'static str, i32> {
+    if canonical_image_mime(actual).is_none() {
+        return Err(unsupported_image_format(actual));
+    }
+    let reject = load_config()
+        .mislabeled_images
+        .as_deref()
+        .unwrap_or(DEFAULT_MISLABELED_IMAGES)
+        == "reject";
+    if reject {
+        eprintln!("Error: Input labelled {labelled} is {actual}; nothing was copied");
+        return Err(EXIT_UNSUPPORTED);
+    }
+    if let Err(e) = check_mime_policy(actual) {
+        eprintln!("Error: Input labelled {labelled} is {actual}: {e}");
+        return Err(exit_code_for_error(&e));
+    }
+    if !image_format_allowed(actual) {
+        return Err(unsupported_image_format(actual));
+    }
+    if verbose() {
+        eprintln!("Input labelled {labelled} is {actual}; storing it as {actual}");
+    }
+    Ok(actual)
+}
+
+/// Split text that is exactly one `data:` URI into its MIME type and decoded
+/// payload. Anything that doesn't parse cleanly is left to be stored as text.
+fn parse_data_uri(buffer: &[u8]) -> Option<(String, Vec<u8>)> {
+    let text = std::str::from_utf8(buffer).ok()?.trim();
+    let rest = text
+        .get(..5)
+        .filter(|scheme| scheme.eq_ignore_ascii_case("data:"))
+        .map(|_| &text[5..])?;
+    let (header, data) = rest.split_once(',')?;
+    let mut params = header.split(';');
+    let mime = match params.next().map(str::trim) {
+        Some("") | None => "text/plain".to_string(), // RFC 2397 default
+        Some(mime) => mime.to_ascii_lowercase(),
+    };
+    let is_base64 = params.any(|param| param.trim().eq_ignore_ascii_case("base64"));
+
+    let payload = if is_base64 {
+        let compact: String = data.chars().filter(|c| !c.is_ascii_whitespace()).collect();
+        DATA_URI_BASE64.decode(compact).ok()?
+    } else {
+        percent_decode(data)?
+    };
+    Some((mime, payload))
+}
+
+/// Accepts padded and unpadded base64, since both show up in the wild
+const DATA_URI_BASE64: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::STANDARD,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+fn percent_decode(text: &str) -> Option<Vec<u8>> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Some(decoded)
+}
+
+/// Store a decoded data: URI under its own MIME type, subject to the same
+/// policy and size limits as direct input of that type
+fn store_data_uri(mime: &str, payload: Vec<u8>, options: InputOptions) -> io::Result<i32> {
+    check_mime_policy(mime)?;
+    let mime = canonical_image_mime(mime).unwrap_or(mime);
+    let (max_bytes, is_image) = match mime {
+        "text/plain" => (max_text_bytes(), false),
+        m if canonical_image_mime(m).is_some() && !image_format_allowed(m) => {
+            return Ok(unsupported_image_format(m));
+        }
+        m if canonical_image_mime(m).is_some() => (max_image_bytes(), true),
+        _ => {
+            eprintln!("Error: Unsupported data: URI type '{mime}'");
+            return Ok(EXIT_UNSUPPORTED);
+        }
+    };
+    if payload.len() as u64 > max_bytes {
+        eprintln!("Error: Decoded data: URI exceeds the maximum size for {mime}");
+        return Ok(EXIT_TOO_LARGE);
+    }
+    if payload.is_empty() {
+        let paths: &[&Path] = if is_image {
+            &[&get_image_path(), &get_image_format_path()]
+        } else {
+            &[&get_text_path()]
+        };
+        return Ok(handle_empty_input(paths));
+    }
+    if is_image {
+        store_image(payload, mime, options)
+    } else {
+        store_text(&payload)
+    }
+}
+
+const ARCHIVE_MIME: &str = "application/x-tar";
+const URI_LIST_MIME: &str = "text/uri-list";
+
+/// Store files as a text/uri-list of `file://` URIs. The paths come from the
+/// arguments, or one per line on stdin (`-i -`), which sidesteps argv limits
+/// for long lists. Only the names are stored, so file size limits don't apply.
+fn input_uri_list(files: &[String], framed: bool) -> io::Result<i32> {
+    check_mime_policy(URI_LIST_MIME)?;
+    ensure_storage_directory()?;
+
+    let listed;
+    let entries: Vec<&str> = if files.is_empty() {
+        let max_bytes = max_text_bytes();
+        listed = match read_stdin(max_bytes, framed) {
+            Ok(buffer) => String::from_utf8_lossy(&buffer).into_owned(),
+            Err(e) if e.kind() == io::ErrorKind::FileTooLarge => {
+                eprintln!(
+                    "Error: File list exceeds maximum size of {}KB",
+                    max_bytes / 1024
+                );
+                return Ok(EXIT_TOO_LARGE);
+            }
+            Err(e) => return Err(e),
+        };
+        // Lines starting with # are comments in a uri-list
+        listed
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .collect()
+    } else {
+        files.iter().map(String::as_str).collect()
+    };
+
+    let abort = load_config()
+        .invalid_paths
+        .as_deref()
+        .unwrap_or(DEFAULT_INVALID_PATHS)
+        == "abort";
+    let mut uris = String::new();
+    for entry in entries {
+        let checked = file_uri_path(entry)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a path or file:// URI"))
+            .and_then(|path| check_symlinks(&path).map(|()| path))
+            .and_then(|path| path.canonicalize())
+            .and_then(|path| check_path_policy(&path).map(|()| path));
+        match checked {
+            Ok(path) => {
+                uris.push_str(&file_uri(&path));
+                uris.push_str("\r\n");
+            }
+            Err(e) if abort => {
+                eprintln!("Error: '{entry}': {e}");
+                return Ok(exit_code_for_error(&e));
+            }
+            Err(e) => eprintln!("Warning: Skipping '{entry}': {e}"),
+        }
+    }
+    if uris.is_empty() {
+        eprintln!("Error: No usable paths to copy");
+        return Ok(EXIT_EMPTY_INPUT);
+    }
+
+    let uri_list_path = get_uri_list_path();
+    write_stored_entry(&uri_list_path, uris.as_bytes(), URI_LIST_MIME)?;
+    record_stored(URI_LIST_MIME, uris.len(), &uri_list_path);
+    Ok(EXIT_SUCCESS)
+}
+
+/// A listed entry as a path: plain paths pass through, `file://` URIs (with
+/// an empty or `localhost` host) are percent-decoded
+fn file_uri_path(entry: &str) -> Option<PathBuf> {
+    let Some(rest) = entry.strip_prefix("file://") else {
+        return Some(PathBuf::from(entry));
+    };
+    let rest = rest.strip_prefix("localhost").unwrap_or(rest);
+    if !rest.starts_with('/') {
+        return None;
+    }
+    let decoded = String::from_utf8(percent_decode(rest)?).ok()?;
+    Some(PathBuf::from(decoded))
+}
+
+/// `file://` URI for an absolute path, escaping everything but unreserved
+/// characters and `/`
+fn file_uri(path: &Path) -> String {
+    use std::fmt::Write as _;
+    let mut uri = String::from("file://");
+    for byte in path.to_string_lossy().bytes() {
+        if byte.is_ascii_alphanumeric() || b"/-._~".contains(&byte) {
+            uri.push(char::from(byte));
+        } else {
+            let _ = write!(uri, "%{byte:02X}");
+        }
+    }
+    uri
+}
+
+/// Bundle several files into a single tar entry so they can be pasted as one
+fn input_archive(files: &[String]) -> io::Result<i32> {
+    check_mime_policy(ARCHIVE_MIME)?;
+    ensure_storage_directory()?;
+    let max_bytes = max_image_bytes();
+
+    let mut names = HashSet::new();
+    let mut total: u64 = 0;
+    for path_str in files {
+        let path = Path::new(path_str);
+        validate_file_access(path)?;
+        let metadata = fs::metadata(path)?;
+        if !metadata.is_file() {
+            eprintln!("Error: '{path_str}' is not a regular file");
+            return Ok(EXIT_USAGE);
+        }
+        // Entries are stored flat, so two files can't share a name
+        let name = path.file_name().unwrap_or(path.as_os_str()).to_owned();
+        if !names.insert(name) {
+            eprintln!(
+                "Error: More than one input file is named '{}'",
+                path.display()
+            );
+            return Ok(EXIT_USAGE);
+        }
+        total = total.saturating_add(metadata.len());
+    }
+    if total > max_bytes {
+        eprintln!(
+            "Error: Input files exceed maximum size of {}",
+            format_size(max_bytes)
+        );
+        return Ok(EXIT_TOO_LARGE);
+    }
+    // Caught before the tar is built; room beside what is already stored
+    // is made (or refused) by the write like any other copy
+    if let Some(quota) = storage_quota()
+        && total > quota
+    {
+        eprintln!(
+            "Error: Input files total {total} bytes, more than max_storage_mb ({quota} bytes) allows"
+        );
+        return Ok(EXIT_TOO_LARGE);
+    }
+
+    let mut builder = tar::Builder::new(Vec::new());
+    for path_str in files {
+        let path = Path::new(path_str);
+        let name = path.file_name().unwrap_or(path.as_os_str());
+        builder.append_file(name, &mut File::open(path)?)?;
+    }
+    let archive = builder.into_inner()?;
+
+    let archive_path = get_archive_path();
+    write_stored_entry(&archive_path, &archive, ARCHIVE_MIME)?;
+    record_stored(ARCHIVE_MIME, archive.len(), &archive_path);
+    Ok(EXIT_SUCCESS)
+}
+
+/// MIME type of a supported image format, from its magic bytes
+fn sniff_image_mime(data: &[u8]) -> Option<&'static str> {
+    match image::guess_format(data).ok()? {
+        ImageFormat::Png => Some("image/png"),
+        ImageFormat::Jpeg => Some("image/jpeg"),
+        ImageFormat::Gif => Some("image/gif"),
+        ImageFormat::WebP => Some("image/webp"),
+        // Recognised even without the `avif` feature, so it isn't stored mislabelled
+        ImageFormat::Avif => Some("image/avif"),
+        ImageFormat::Bmp => Some("image/bmp"),
+        _ => None,
+    }
+}
+
+/// Classify content as a known image format or UTF-8 text
+fn sniff_mime(data: &[u8]) -> Option<&'static str> {
+    if let Some(mime) = sniff_image_mime(data) {
+        return Some(mime);
+    }
+    if is_heif(data) {
+        return Some(HEIF_MIMES[0]);
+    }
+    // NUL and most other C0 controls don't appear in real text
+    let is_text = !data.is_empty()
+        && std::str::from_utf8(data).is_ok_and(|text| {
+            !text
+                .chars()
+                .any(|c| c.is_control() && !matches!(c, '\n' | '\r' | '\t' | '\x0c' | '\x1b'))
+        });
+    is_text.then_some("text/plain;charset=utf-8")
+}
+
+/// Print the detected MIME type of stdin (or an -i file) without storing it
+fn sniff_input(file: Option<&String>, framed: bool) -> io::Result<i32> {
+    let max_bytes = max_image_bytes();
+    let read = if let Some(path_str) = file {
+        let path = Path::new(path_str);
+        validate_file_access(path)?;
+        read_limited(File::open(path)?, max_bytes)
+    } else {
+        read_stdin(max_bytes, framed)
+    };
+    let data = read?;
+    let Some(mime) = sniff_mime(&data) else {
+        eprintln!("Error: Could not determine the content type");
+        return Ok(EXIT_UNCLASSIFIED);
+    };
+    println!("{mime}");
+    Ok(EXIT_SUCCESS)
+}
+
+// Polling faster than this just burns CPU on stat calls
+const MIN_WATCH_INTERVAL_MS: u64 = 50;
+
+/// Modification time and size, or None while the file doesn't exist
+fn file_stamp(path: &Path) -> Option<(SystemTime, u64)> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+fn watch_interval() -> Duration {
+    let interval_ms = load_config()
+        .watch_interval_ms
+        .unwrap_or(DEFAULT_WATCH_INTERVAL_MS)
+        .max(MIN_WATCH_INTERVAL_MS);
+    Duration::from_millis(interval_ms)
+}
+
+/// Print "<selection> <mime>" each time stored content changes, until
+/// interrupted. Polls modification times, so the storage directory doesn't
+/// have to exist yet. SIGHUP reloads the config; the storage location comes
+/// from the environment and is fixed for the life of the process.
+fn watch_storage() -> io::Result<i32> {
+    let mut interval = watch_interval();
+    #[cfg(unix)]
+    let reload_requested = {
+        let flag = Arc::new(AtomicBool::new(false));
+        signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(&flag))?;
+        flag
+    };
+
+    // The index is renamed before the payload, so a payload changing marks a
+    // complete copy whose type can already be read
+    let targets = payload_paths();
+    let mut stamps: Vec<_> = targets.iter().map(|path| file_stamp(path)).collect();
+
+    let mut stdout = io::stdout();
+    loop {
+        std::thread::sleep(interval);
+        #[cfg(unix)]
+        if reload_requested.swap(false, Ordering::Relaxed) && reload_config() {
+            interval = watch_interval();
+            if verbose() {
+                eprintln!("Reloaded config from {}", config_path().display());
+            }
+        }
+        for (path, stamp) in targets.iter().zip(stamps.iter_mut()) {
+            let current = file_stamp(path);
+            if current == *stamp {
+                continue;
+            }
+            *stamp = current;
+            // Expired or cleared content isn't a new clipboard value
+            if current.is_none_or(|(_, len)| len == 0) {
+                continue;
+            }
+            let mime = stored_mime(path);
+            let written =
+                writeln!(stdout, "{} {mime}", active_selection()).and_then(|()| stdout.flush());
+            match written {
+                Ok(()) => {}
+                // The reader went away (e.g. `xclip -watch | head -1`)
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => return Ok(EXIT_SUCCESS),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let started = Instant::now();
+    let code = run();
+    if timing_enabled() {
+        print_timings(started.elapsed());
+    }
+    code
+}
+
+#[allow(clippy::too_many_lines)]
+fn run() -> ExitCode {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(msg) => {
+            eprintln!("Error: {msg}");
+            return exit_with(EXIT_USAGE);
+        }
+    };
+
+    let mut overrides = toml::Table::new();
+    if let Some(secs) = args.ttl_secs {
+        overrides.insert("ttl_secs".to_string(), toml_int(secs).into());
+    }
+    if let Some(target) = args.default_target {
+        overrides.insert("default_output_target".to_string(), target.into());
+    }
+    if let Some(follow) = args.follow_symlinks {
+        overrides.insert("follow_symlinks".to_string(), follow.into());
+    }
+    let _ = CLI_OVERRIDES.set(overrides);
+    if let Some(profile) = args.profile.clone() {
+        let _ = CLI_PROFILE.set(profile);
+    }
+    let _ = VERBOSITY.set(args.verbosity);
+    let _ = SELECTION.set(canonical_selection(&args.selection));
+    set_slot(args.slot.clone());
+
+    // Config tooling (and doctor, which reports on it) has to keep working
+    // while the config is broken
+    let manages_config = matches!(
+        args.subcommand,
+        Some(
+            Subcommand::ConfigPath
+                | Subcommand::ShowConfig
+                | Subcommand::InitConfig
+                | Subcommand::Config(_)
+                | Subcommand::Doctor
+        )
+    );
+
+    // Defaults would silently lift directory restrictions; fail closed instead
+    if !manages_config
+        && resolved_config().file_state
+            == (ConfigFileState::Invalid {
+                security_relevant: true,
+            })
+    {
+        eprintln!(
+            "Error: Refusing to run because the config restricting file access could not be read or parsed"
+        );
+        return exit_with(EXIT_USAGE);
+    }
+
+    // A mistyped profile would otherwise quietly run with the base settings
+    if !matches!(
+        args.subcommand,
+        Some(Subcommand::ConfigPath | Subcommand::InitConfig | Subcommand::Doctor)
+    ) && let Some(profile) = &resolved_config().profile
+        && !profile.found
+    {
+        let names = profile_names();
+        let defined = if names.is_empty() {
+            "none".to_string()
+        } else {
+            names.join(", ")
+        };
+        eprintln!(
+            "Error: Unknown profile '{}' from {} (defined: {defined})",
+            profile.name, profile.origin
+        );
+        return exit_with(EXIT_USAGE);
+    }
+
+    // Doctor reports on the directory instead, and -sniff never touches it
+    if !manages_config
+        && !matches!(args.subcommand, Some(Subcommand::Sniff))
+        && let Some(code) = check_storage_security(args.storage_check)
+    {
+        return exit_with(code);
+    }
+
+    // An older layout is migrated before anything reads it. One written by a
+    // newer version is an empty clipboard to pastes and left alone otherwise.
+    if !manages_config
+        && !matches!(args.subcommand, Some(Subcommand::Sniff))
+        && let Err(layout) = migrate_store()
+    {
+        let storage = get_storage_directory();
+        if args.mode_output && args.subcommand.is_none() {
+            if verbose() {
+                eprintln!(
+                    "{} uses storage layout {layout}, newer than {STORE_LAYOUT_VERSION}; nothing to paste",
+                    storage.display()
+                );
+            }
+            return exit_with(EXIT_NO_CONTENT);
+        }
+        eprintln!(
+            "Error: {} was written by a newer wsl-clip-bridge (storage layout {layout}); leaving it alone",
+            storage.display()
+        );
+        return exit_with(EXIT_UNSUPPORTED);
+    }
+
+    if let Some(subcommand) = args.subcommand {
+        let result = match subcommand {
+            Subcommand::ConfigPath => {
+                // Only resolve the path; never load or create the file
+                println!("{}", config_path().display());
+                Ok(EXIT_SUCCESS)
+            }
+            Subcommand::ShowConfig => {
+                print_effective_config();
+                Ok(EXIT_SUCCESS)
+            }
+            Subcommand::InitConfig => init_config(args.force),
+            Subcommand::Config(edit) => edit_config(&edit),
+            Subcommand::Sniff => sniff_input(args.input_files.first(), args.input.framed),
+            Subcommand::Watch => watch_storage(),
+            Subcommand::HistoryList => Ok(print_history()),
+            Subcommand::Doctor => Ok(run_doctor()),
+            Subcommand::Gc { dry_run } => collect_garbage(dry_run),
+            Subcommand::Clear => clear_selection(args.mime_type.as_deref(), args.force),
+            Subcommand::Touch => touch_selection(args.mime_type.as_deref()),
+            Subcommand::Dump => dump_store(),
+            Subcommand::Restore { preserve_age } => restore_store(preserve_age, args.force),
+            Subcommand::Status => match args.check {
+                Some(mime) => Ok(if is_target_fresh(&mime) {
+                    EXIT_SUCCESS
+                } else {
+                    EXIT_NO_CONTENT
+                }),
+                None => print_status(args.json),
+            },
+        };
+        return finish(result);
+    }
+
+    // Output mode handling
+    let result = if args.mode_output {
+        if let Some(path) = &args.output.out_file
+            && let Err(e) = check_out_file(path)
+        {
+            return finish(Err(e));
+        }
+        match args.mime_type.as_deref() {
+            requested if args.output.index.is_some_and(|index| index > 0) => {
+                output_history(requested, args.output.index.unwrap_or(1), &args.output)
+            }
+            Some("TARGETS") => {
+                print_targets();
+                Ok(EXIT_SUCCESS)
+            }
+            None => match default_output_target().as_str() {
+                "targets" => {
+                    print_targets();
+                    Ok(EXIT_SUCCESS)
                 }
-                _ => {}
+                mime => output_type(mime, &args.output),
+            },
+            Some(m) => {
+                let result = output_type(m, &args.output);
+                if matches!(result, Ok(EXIT_NO_CONTENT)) {
+                    suggest_targets(m);
+                }
+                result
+            }
+        }
+    } else if is_read_only() {
+        eprintln!("Error: Clipboard is read-only (read_only = true); nothing was copied");
+        Ok(EXIT_READ_ONLY)
+    } else if is_pinned() && !args.force {
+        eprintln!("Error: Clipboard content is pinned; use -force to overwrite it");
+        Ok(EXIT_PINNED)
+    } else {
+        let default_mime = (args.input_files.len() < 2).then(default_input_mime);
+        let mime_type = args.mime_type.as_deref().or(default_mime.as_deref());
+        let result = match (mime_type, args.input_files.as_slice()) {
+            // Several files (or an explicit tar target) are bundled into one archive
+            (None | Some(ARCHIVE_MIME), [_, _, ..]) | (Some(ARCHIVE_MIME), [_]) => {
+                input_archive(&args.input_files)
+            }
+            (Some(URI_LIST_MIME), files) => input_uri_list(files, args.input.framed),
+            (Some(_), [_, _, ..]) => {
+                eprintln!("Error: Multiple input files can only be copied as {ARCHIVE_MIME}");
+                Ok(EXIT_USAGE)
             }
+            (mime, files) => input_type(mime.unwrap_or("text/plain"), files.first(), args.input),
+        };
+        // A forced overwrite drops the old pin unless -pin asks for a new one
+        let result = match result {
+            Ok(EXIT_SUCCESS) => set_pinned(args.input.pin).map(|()| EXIT_SUCCESS),
+            other => other,
+        };
+        if args.json
+            && matches!(result, Ok(EXIT_SUCCESS))
+            && let Some(stored) = STORED.get()
+        {
+            print_copy_report(stored);
+        }
+        result
+    };
+
+    finish(result)
+}
+
+fn finish(result: io::Result<i32>) -> ExitCode {
+    match result {
+        Ok(code) => exit_with(code),
+        Err(e) => {
+            eprintln!("Error: {e}");
+            exit_with(exit_code_for_error(&e))
+        }
+    }
+}
+
+// Config & TTL handling
+const DEFAULT_TTL_SECS: u64 = 300; // 5 minutes
+const DEFAULT_REFRESH_TTL_ON_READ: bool = false;
+const DEFAULT_REFRESH_TTL_ON_IDENTICAL: bool = true;
+const DEFAULT_MAX_TTL_SECS: u64 = 86_400; // One day
+// Ceiling for max_ttl_secs itself, far from where expiry times overflow
+const ABSOLUTE_MAX_TTL_SECS: u64 = 365 * 86_400;
+const DEFAULT_MAX_IMAGE_DIMENSION: u32 = 0; // No downscaling
+const DEFAULT_DOWNSCALE_NEVER_GROW: bool = false;
+const DEFAULT_STRIP_EXIF: bool = false;
+const DEFAULT_APPLY_EXIF_ORIENTATION: bool = true;
+const DEFAULT_MAX_ENCODED_SIZE_KB: u64 = 0; // No limit
+const DEFAULT_MAX_IMAGE_PIXELS: u64 = 100_000_000; // ~400 MB once decoded to RGBA
+const DEFAULT_STORE_OVERSIZED_IMAGES: bool = false;
+const DEFAULT_MAX_FILE_SIZE_MB: u64 = 100;
+const DEFAULT_MAX_TEXT_SIZE_KB: u64 = 10 * 1024;
+const DEFAULT_CLIPBOARD_MODE: &str = "auto";
+const DEFAULT_CACHE_WL_IMAGES: bool = true;
+const DEFAULT_WINDOWS_FILE_FALLBACK: bool = false;
+const DEFAULT_EMPTY_INPUT: &str = "reject";
+const DEFAULT_WATCH_INTERVAL_MS: u64 = 500;
+const DEFAULT_READ_ONLY: bool = false;
+const DEFAULT_SUGGEST_TARGETS: bool = false;
+const DEFAULT_OUTPUT_TARGET: &str = "text";
+const DEFAULT_FILE_MODE: u32 = 0o600;
+const DEFAULT_DIR_MODE: u32 = 0o700;
+const DEFAULT_STORAGE_BACKEND: &str = "cache";
+const DEFAULT_PREFER_RUNTIME_DIR: bool = false;
+const DEFAULT_SECURE_DELETE: bool = false;
+const DEFAULT_DURABLE_WRITES: bool = false;
+const DEFAULT_COMPRESS_STORAGE: bool = false;
+const DEFAULT_ENCRYPT_STORAGE: bool = false;
+const DEFAULT_VERIFY_CHECKSUMS: bool = true;
+const DEFAULT_HISTORY_SIZE: u64 = 0; // History off
+const DEFAULT_MAX_STORAGE_MB: u64 = 0; // No quota
+const DEFAULT_IMAGE_OUTPUT_FORMAT: &str = "original";
+const DEFAULT_CONVERT_LARGE_PNG_TO_JPEG_KB: u64 = 0; // 0 = never
+const DEFAULT_JPEG_QUALITY: u8 = 75; // What the image crate used before it was configurable
+const DEFAULT_PNG_COMPRESSION: &str = "fast";
+const DEFAULT_OPTIMIZE_PNG: bool = false;
+const DEFAULT_WEBP_MODE: &str = "lossless"; // All the image crate can encode
+const DEFAULT_WEBP_QUALITY: u8 = 75; // cwebp's default
+const DEFAULT_JPEG_PROGRESSIVE: bool = false;
+const DEFAULT_JPEG_BACKGROUND_COLOR: [u8; 3] = [0xff, 0xff, 0xff]; // White, like a page
+const DEFAULT_DOWNSCALE_FILTER: &str = "lanczos3";
+const DEFAULT_INVALID_UTF8: &str = "keep";
+const DEFAULT_TRIM_TRAILING_WHITESPACE: bool = false;
+const DEFAULT_RECORD_SOURCE_PATH: bool = false;
+const DEFAULT_INVALID_PATHS: &str = "skip";
+const DEFAULT_MISLABELED_IMAGES: &str = "correct";
+const DEFAULT_FOLLOW_SYMLINKS: bool = true;
+const OUTPUT_TARGET_CHOICES: &[&str] = &["targets", "text", "auto", "image"];
+const DEFAULT_INPUT_MIME: &str = "text/plain";
+
+#[derive(Debug, Deserialize, Default)]
+struct BridgeConfig {
+    #[serde(default, alias = "ttl", deserialize_with = "deserialize_duration")]
+    ttl_secs: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    max_ttl_secs: Option<u64>,
+    #[serde(default)]
+    refresh_ttl_on_read: Option<bool>,
+    #[serde(default)]
+    refresh_ttl_on_identical: Option<bool>,
+    #[serde(default)]
+    max_image_dimension: Option<u32>,
+    #[serde(default)]
+    max_image_width: Option<u32>,
+    #[serde(default)]
+    max_image_height: Option<u32>,
+    #[serde(default, deserialize_with = "deserialize_size")]
+    max_file_size: Option<u64>,
+    #[serde(default)]
+    max_file_size_mb: Option<u64>,
+    #[serde(default)]
+    max_text_size_kb: Option<u64>,
+    #[serde(default)]
+    allowed_directories: Option<Vec<String>>,
+    #[serde(default)]
+    denied_directories: Option<Vec<String>>,
+    #[serde(default)]
+    follow_symlinks: Option<bool>,
+    #[serde(default)]
+    allowed_mime_types: Option<Vec<String>>,
+    #[serde(default)]
+    denied_mime_types: Option<Vec<String>>,
+    #[serde(default)]
+    allowed_image_formats: Option<Vec<String>>, // "png", "jpeg", "gif", "webp"
+    #[serde(default)]
+    downscale_never_grow: Option<bool>,
+    #[serde(default)]
+    strip_exif: Option<bool>,
+    #[serde(default)]
+    apply_exif_orientation: Option<bool>,
+    #[serde(default)]
+    image_output_format: Option<String>, // "original", "png", "jpeg", "webp"
+    #[serde(default)]
+    convert_large_png_to_jpeg_kb: Option<u64>,
+    #[serde(default)]
+    jpeg_quality: Option<u8>,
+    #[serde(default)]
+    png_compression: Option<String>, // "fast", "default", "best"
+    #[serde(default)]
+    optimize_png: Option<bool>,
+    #[serde(default)]
+    webp_mode: Option<String>, // "lossless", "lossy"
+    #[serde(default)]
+    webp_quality: Option<u8>,
+    #[serde(default)]
+    jpeg_progressive: Option<bool>,
+    #[serde(default, deserialize_with = "deserialize_color")]
+    jpeg_background_color: Option<[u8; 3]>,
+    #[serde(default)]
+    downscale_filter: Option<String>, // "nearest", "triangle", "catmullrom", "gaussian", "lanczos3"
+    #[serde(default)]
+    max_encoded_size_kb: Option<u64>,
+    #[serde(default)]
+    max_image_pixels: Option<u64>,
+    #[serde(default)]
+    store_oversized_images: Option<bool>,
+
+    // wl-clipboard integration options
+    #[serde(default)]
+    clipboard_mode: Option<String>, // "auto", "file_only"
+    #[serde(default)]
+    cache_wl_images: Option<bool>, // Cache converted BMP→PNG
+    #[serde(default)]
+    windows_file_fallback: Option<bool>, // Explorer file list for text/uri-list
+
+    #[serde(default)]
+    empty_input: Option<String>, // "reject", "clear"
+    #[serde(default)]
+    invalid_utf8: Option<String>, // "keep", "transcode", "reject"
+    #[serde(default)]
+    trim_trailing_whitespace: Option<bool>,
+    #[serde(default)]
+    record_source_path: Option<bool>,
+    #[serde(default)]
+    invalid_paths: Option<String>, // "skip", "abort"
+    #[serde(default)]
+    mislabeled_images: Option<String>, // "correct", "reject"
+    #[serde(default)]
+    watch_interval_ms: Option<u64>,
+    #[serde(default)]
+    read_only: Option<bool>,
+    #[serde(default)]
+    default_output_target: Option<String>, // "targets", "text", "auto", "image" or a MIME type
+    #[serde(default)]
+    default_input_mime: Option<String>,
+    #[serde(default)]
+    preferred_target_order: Option<Vec<String>>,
+    #[serde(default)]
+    suggest_targets: Option<bool>,
+    #[serde(default)]
+    file_mode: Option<String>, // octal, e.g. "0640"
+    #[serde(default)]
+    dir_mode: Option<String>,
+    #[serde(default)]
+    storage_backend: Option<String>, // "cache", "runtime", "memory"
+    #[serde(default)]
+    prefer_runtime_dir: Option<bool>, // Older spelling of storage_backend = "runtime"
+    #[serde(default)]
+    secure_delete: Option<bool>,
+    #[serde(default)]
+    durable_writes: Option<bool>,
+    #[serde(default)]
+    compress_storage: Option<bool>,
+    #[serde(default)]
+    encrypt_storage: Option<bool>,
+    #[serde(default)]
+    verify_checksums: Option<bool>,
+    #[serde(default)]
+    history_size: Option<u64>,
+    #[serde(default)]
+    max_storage_mb: Option<u64>,
+
+    // Per-selection overrides, e.g. [selections.primary]
+    #[serde(default)]
+    selections: Option<HashMap<String, SelectionOverrides>>,
+
+    // Named sets of overrides, e.g. [profiles.claude]; typed so their keys
+    // get the same validation as the top level
+    #[serde(default)]
+    #[allow(dead_code)] // Only deserialized; the merge happens on the raw table
+    profiles: Option<HashMap<String, Self>>,
+}
+
+/// Settings that can differ between primary, secondary and clipboard
+#[derive(Debug, Deserialize, Default)]
+struct SelectionOverrides {
+    #[serde(default, alias = "ttl", deserialize_with = "deserialize_duration")]
+    ttl_secs: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_size")]
+    max_file_size: Option<u64>,
+    #[serde(default)]
+    max_file_size_mb: Option<u64>,
+}
+
+/// Overrides configured for the selection this invocation targets
+fn selection_overrides() -> Option<&'static SelectionOverrides> {
+    load_config().selections.as_ref()?.get(active_selection())
+}
+
+/// The configured file size limit in bytes: the selection's value first,
+/// then the global one, with `max_file_size` winning over the older
+/// `max_file_size_mb` at each level
+fn configured_max_file_size() -> Option<u64> {
+    let from_mb = |mb: u64| mb.saturating_mul(1024 * 1024);
+    let config = load_config();
+    selection_overrides()
+        .and_then(|overrides| {
+            overrides
+                .max_file_size
+                .or_else(|| overrides.max_file_size_mb.map(from_mb))
+        })
+        .or(config.max_file_size)
+        .or_else(|| config.max_file_size_mb.map(from_mb))
+}
+
+const SIZE_UNITS: [(&str, u64); 5] = [
+    ("B", 1),
+    ("KB", 1 << 10),
+    ("MB", 1 << 20),
+    ("GB", 1 << 30),
+    ("TB", 1 << 40),
+];
+
+/// Parse bytes ("1048576") or a size with a unit ("500KB", "1.5GB", "10 mb")
+/// into bytes. Units are binary, as `max_file_size_mb` always was: 1KB is
+/// 1024 bytes. "K", "KiB" and friends are accepted too.
+#[allow(
+    clippy::cast_precision_loss,
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss
+)]
+fn parse_size(raw: &str) -> Result<u64, String> {
+    let text = raw.trim();
+    let invalid = || format!("invalid size {raw:?} (expected bytes or e.g. \"500KB\", \"1.5GB\")");
+    let split = text
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    // "KB", "KiB" and "K" all come down to the prefix "K"; "B" to none
+    let unit = unit.trim().to_ascii_uppercase();
+    let prefix = unit
+        .strip_suffix("IB")
+        .or_else(|| unit.strip_suffix('B'))
+        .unwrap_or(&unit);
+    let multiplier = SIZE_UNITS
+        .iter()
+        .find(|(name, _)| name.trim_end_matches('B') == prefix)
+        .map(|(_, multiplier)| *multiplier)
+        .ok_or_else(invalid)?;
+    if let Ok(whole) = number.parse::<u64>() {
+        return whole.checked_mul(multiplier).ok_or_else(invalid);
+    }
+    let fraction: f64 = number.parse().map_err(|_| invalid())?;
+    let bytes = (fraction * multiplier as f64).round();
+    if !bytes.is_finite() || bytes >= u64::MAX as f64 {
+        return Err(invalid());
+    }
+    Ok(bytes as u64)
+}
+
+/// A byte count in the largest unit it reaches: "500KB", "1.5GB", "100MB"
+#[allow(clippy::cast_precision_loss)]
+fn format_size(bytes: u64) -> String {
+    let (name, multiplier) = SIZE_UNITS
+        .iter()
+        .rev()
+        .find(|(_, multiplier)| bytes >= *multiplier)
+        .copied()
+        .unwrap_or(SIZE_UNITS[0]);
+    if bytes.is_multiple_of(multiplier) {
+        format!("{}{name}", bytes / multiplier)
+    } else {
+        format!("{:.1}{name}", bytes as f64 / multiplier as f64)
+    }
+}
+
+/// Accept a size as either integer bytes or a string like "10MB"
+fn deserialize_size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    struct SizeVisitor;
+
+    impl Visitor<'_> for SizeVisitor {
+        type Value = Option<u64>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("bytes or a size string like \"10MB\"")
+        }
+
+        fn visit_u64<E: de::Error>(self, bytes: u64) -> Result<Self::Value, E> {
+            Ok(Some(bytes))
+        }
+
+        fn visit_i64<E: de::Error>(self, bytes: i64) -> Result<Self::Value, E> {
+            u64::try_from(bytes)
+                .map(Some)
+                .map_err(|_| E::custom(format!("invalid size {bytes} (must not be negative)")))
+        }
+
+        fn visit_str<E: de::Error>(self, text: &str) -> Result<Self::Value, E> {
+            parse_size(text).map(Some).map_err(E::custom)
+        }
+    }
+
+    deserializer.deserialize_any(SizeVisitor)
+}
+
+/// Parse "300", "90s", "15m", "2h", "1d" or compounds like "1h30m" into
+/// seconds. "never" maps to 0, the never-expire TTL.
+fn parse_duration(raw: &str) -> Result<u64, String> {
+    let text = raw.trim();
+    if let Ok(secs) = text.parse::<u64>() {
+        return Ok(secs);
+    }
+    if text.eq_ignore_ascii_case("never") {
+        return Ok(0);
+    }
+    let invalid = || format!("invalid duration {raw:?} (use e.g. 300, 90s, 15m, 2h or 1h30m)");
+
+    let mut total: u64 = 0;
+    let mut digits = String::new();
+    for c in text.chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
         }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 3_600,
+            'd' => 86_400,
+            _ => return Err(invalid()),
+        };
+        let amount: u64 = digits.parse().map_err(|_| invalid())?;
+        total = amount
+            .checked_mul(unit)
+            .and_then(|secs| total.checked_add(secs))
+            .ok_or_else(invalid)?;
+        digits.clear();
     }
+    // Trailing digits without a unit ("1h30") are ambiguous
+    if !digits.is_empty() || text.is_empty() {
+        return Err(invalid());
+    }
+    Ok(total)
+}
 
-    // Text targets (existing logic)
-    let text_path = get_text_path();
-    if is_file_fresh(&text_path, ttl) && !printed.contains("text/plain;charset=utf-8") {
-        println!("text/plain;charset=utf-8");
-        println!("STRING");
-    } else if text_path.exists() && !is_file_fresh(&text_path, ttl) {
-        // Clean up expired text file
-        let _ = fs::remove_file(&text_path);
+/// Accept a TTL as either integer seconds or a duration string
+fn deserialize_duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u64>, D::Error> {
+    struct DurationVisitor;
+
+    impl Visitor<'_> for DurationVisitor {
+        type Value = Option<u64>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("seconds or a duration string like \"2h\"")
+        }
+
+        fn visit_u64<E: de::Error>(self, secs: u64) -> Result<Self::Value, E> {
+            Ok(Some(secs))
+        }
+
+        fn visit_i64<E: de::Error>(self, secs: i64) -> Result<Self::Value, E> {
+            u64::try_from(secs)
+                .map(Some)
+                .map_err(|_| E::custom(format!("invalid duration {secs} (must not be negative)")))
+        }
+
+        fn visit_str<E: de::Error>(self, text: &str) -> Result<Self::Value, E> {
+            parse_duration(text).map(Some).map_err(E::custom)
+        }
     }
+
+    deserializer.deserialize_any(DurationVisitor)
 }
 
-fn output_type(mime: &str) -> io::Result<i32> {
-    match mime {
-        m if m.starts_with("text/plain") => {
-            let text_path = get_text_path();
-            let ttl = load_ttl();
-            if is_file_fresh(&text_path, ttl) {
-                let mut file = File::open(text_path)?;
-                let mut buffer = Vec::new();
-                file.read_to_end(&mut buffer)?;
-                io::stdout().write_all(&buffer)?;
-                return Ok(0);
-            }
+/// Parse "#rrggbb" or the "#rgb" shorthand
+fn parse_hex_color(text: &str) -> Result<[u8; 3], String> {
+    let invalid = || format!("invalid color {text:?} (expected \"#rrggbb\" or \"#rgb\")");
+    let hex = text.trim().strip_prefix('#').ok_or_else(invalid)?;
+    if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(invalid());
+    }
+    let channel = |digits: &str| u8::from_str_radix(digits, 16).map_err(|_| invalid());
+    match hex.len() {
+        6 => Ok([
+            channel(&hex[0..2])?,
+            channel(&hex[2..4])?,
+            channel(&hex[4..6])?,
+        ]),
+        // Each shorthand digit is doubled: "#f80" is "#ff8800"
+        3 => Ok([
+            channel(&hex[0..1])? * 0x11,
+            channel(&hex[1..2])? * 0x11,
+            channel(&hex[2..3])? * 0x11,
+        ]),
+        _ => Err(invalid()),
+    }
+}
 
-            // Clean up expired file
-            if text_path.exists() {
-                let _ = fs::remove_file(&text_path);
-            }
+fn hex_color([r, g, b]: [u8; 3]) -> String {
+    format!("#{r:02x}{g:02x}{b:02x}")
+}
 
-            // Try wl-clipboard if available
-            if wl_clipboard_available()
-                && let Ok(types) = get_wl_clipboard_types()
-                && types.iter().any(|t| t.starts_with("text/"))
-                && let Ok(data) = fetch_from_wl_clipboard("text/plain")
-            {
-                io::stdout().write_all(&data)?;
-                return Ok(0);
-            }
-            Ok(1)
+fn deserialize_color<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<[u8; 3]>, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    parse_hex_color(&text).map(Some).map_err(de::Error::custom)
+}
+
+fn config_dir() -> PathBuf {
+    if let Ok(xdg) = env::var("XDG_CONFIG_HOME")
+        && !xdg.trim().is_empty()
+    {
+        return PathBuf::from(xdg).join("wsl-clip-bridge");
+    }
+    // Never a shared /tmp directory: anyone could plant a config there
+    home_dir().map_or_else(homeless_directory, |h| {
+        PathBuf::from(h).join(".config").join("wsl-clip-bridge")
+    })
+}
+
+fn config_path() -> PathBuf {
+    if let Ok(p) = env::var("WSL_CLIP_BRIDGE_CONFIG")
+        && !p.trim().is_empty()
+    {
+        return PathBuf::from(p);
+    }
+    config_dir().join("config.toml")
+}
+
+/// What happened to the config file itself
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConfigFileState {
+    Missing,
+    Loaded,
+    Invalid { security_relevant: bool },
+}
+
+/// Where an effective setting came from, lowest precedence first
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ConfigSource {
+    Default,
+    File,
+    Profile(String),
+    Env(String), // the variable that was read
+    Cli,
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Default => f.write_str("default"),
+            Self::File => f.write_str("config file"),
+            Self::Profile(name) => write!(f, "profile {name}"),
+            Self::Env(var) => write!(f, "env var {var}"),
+            Self::Cli => f.write_str("command line"),
         }
-        "image/png" | "image/jpeg" | "image/jpg" | "image/gif" | "image/webp" => {
-            let image_path = get_image_path();
-            let ttl = load_ttl();
+    }
+}
 
-            // Priority 1: Check wl-clipboard first (always has the latest)
-            if wl_clipboard_available()
-                && let Ok(types) = get_wl_clipboard_types()
-            {
-                // Direct format available?
-                if types.contains(&mime.to_string())
-                    && let Ok(data) = fetch_from_wl_clipboard(mime)
-                {
-                    // Apply downscaling if configured
-                    let config = load_config();
-                    let max_dim = config.and_then(|c| c.max_image_dimension);
-                    let processed = downscale_image_if_needed(&data, mime, max_dim);
+/// Settings given on the command line (e.g. --ttl); set once before config is read
+static CLI_OVERRIDES: OnceLock<toml::Table> = OnceLock::new();
 
-                    io::stdout().write_all(&processed)?;
-                    return Ok(0);
-                }
+/// The config file merged with environment overrides, resolved once per invocation
+#[derive(Debug)]
+struct ResolvedConfig {
+    file_state: ConfigFileState,
+    unknown_keys: Vec<UnknownKey>,
+    profile: Option<ActiveProfile>,
+    config: BridgeConfig,
+    values: toml::Table,
+    sources: HashMap<&'static str, ConfigSource>,
+}
 
-                // Special case: BMP → PNG conversion ONLY
-                if mime == "image/png"
-                    && types.contains(&"image/bmp".to_string())
-                    && let Ok(bmp_data) = fetch_from_wl_clipboard("image/bmp")
-                {
-                    // Convert BMP to PNG
-                    let img = image::load_from_memory(&bmp_data)
-                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+/// The profile requested with --profile or `WSL_CLIP_BRIDGE_PROFILE`
+#[derive(Debug)]
+struct ActiveProfile {
+    name: String,
+    origin: &'static str, // where the name came from, for messages
+    found: bool,
+}
 
-                    let mut output = Cursor::new(Vec::new());
-                    img.write_to(&mut output, ImageFormat::Png)
-                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-                    let png_data = output.into_inner();
+/// Profile name given on the command line; set once before config is read
+static CLI_PROFILE: OnceLock<String> = OnceLock::new();
 
-                    // Apply downscaling
-                    let config = load_config();
-                    let max_dim = config.as_ref().and_then(|c| c.max_image_dimension);
-                    let processed = downscale_image_if_needed(&png_data, "image/png", max_dim);
+const PROFILE_ENV_VAR: &str = "WSL_CLIP_BRIDGE_PROFILE";
 
-                    // Cache if configured
-                    if config
-                        .as_ref()
-                        .is_none_or(|c| c.cache_wl_images.unwrap_or(true))
-                    {
-                        ensure_storage_directory()?;
-                        fs::write(&image_path, &processed)?;
-                        fs::write(get_image_format_path(), "image/png")?;
-                        #[cfg(unix)]
-                        {
-                            let _ =
-                                fs::set_permissions(&image_path, fs::Permissions::from_mode(0o600));
-                        }
-                    }
+fn requested_profile() -> Option<(String, &'static str)> {
+    if let Some(name) = CLI_PROFILE.get() {
+        return Some((name.clone(), "--profile"));
+    }
+    env::var(PROFILE_ENV_VAR)
+        .ok()
+        .filter(|name| !name.trim().is_empty())
+        .map(|name| (name.trim().to_string(), PROFILE_ENV_VAR))
+}
 
-                    io::stdout().write_all(&processed)?;
-                    return Ok(0);
-                }
-            }
+/// Names of the profiles defined in the config file, sorted
+fn profile_names() -> Vec<String> {
+    let mut names: Vec<String> = match resolved_config().values.get("profiles") {
+        Some(toml::Value::Table(profiles)) => profiles.keys().cloned().collect(),
+        _ => Vec::new(),
+    };
+    names.sort();
+    names
+}
 
-            // Priority 2: Fall back to cached file if still fresh
-            if is_file_fresh(&image_path, ttl)
-                && let Ok(stored_format) = fs::read_to_string(get_image_format_path())
-            {
-                let stored_format = stored_format.trim();
-                let matches =
-                    mime == stored_format || (mime == "image/jpg" && stored_format == "image/jpeg");
-                if matches {
-                    let mut file = File::open(&image_path)?;
-                    let mut buffer = Vec::new();
-                    file.read_to_end(&mut buffer)?;
-                    io::stdout().write_all(&buffer)?;
-                    return Ok(0);
+impl ResolvedConfig {
+    fn source(&self, key: &str) -> &ConfigSource {
+        self.sources.get(key).unwrap_or(&ConfigSource::Default)
+    }
+}
+
+// A reload (SIGHUP in -watch) swaps in a new leaked config, so references
+// handed out earlier stay valid; reloads are rare enough for the leak to be moot
+static CONFIG: RwLock<Option<&'static ResolvedConfig>> = RwLock::new(None);
+
+fn resolved_config() -> &'static ResolvedConfig {
+    if let Some(resolved) = *CONFIG.read().unwrap_or_else(PoisonError::into_inner) {
+        return resolved;
+    }
+    let mut slot = CONFIG.write().unwrap_or_else(PoisonError::into_inner);
+    slot.get_or_insert_with(|| {
+        let _timer = PhaseTimer::start("config");
+        Box::leak(Box::new(resolve_config()))
+    })
+}
+
+/// Re-read the config, keeping the current one if the new file is invalid
+fn reload_config() -> bool {
+    let fresh = resolve_config();
+    if matches!(fresh.file_state, ConfigFileState::Invalid { .. }) {
+        eprintln!("Error: Config reload failed; keeping the previous settings");
+        return false;
+    }
+    *CONFIG.write().unwrap_or_else(PoisonError::into_inner) = Some(Box::leak(Box::new(fresh)));
+    true
+}
+
+fn load_config() -> &'static BridgeConfig {
+    &resolved_config().config
+}
+
+/// Shape of a key's value, used to parse its environment override
+#[derive(Debug, Clone, Copy)]
+enum ValueKind {
+    Integer,
+    Bool,
+    String,
+    PathList, // colon-separated in the environment, like PATH
+    List,     // comma-separated in the environment
+    Duration, // seconds or "1h30m"
+    Size,     // bytes or "500KB"
+}
+
+/// A config key, its env override shape, and its built-in default
+struct ConfigKey {
+    name: &'static str,
+    kind: ValueKind,
+    default: fn() -> toml::Value,
+}
+
+/// Every key `BridgeConfig` understands. Each can be overridden with
+/// `WSL_CLIP_BRIDGE_<KEY>` (env > config file > default).
+const CONFIG_KEYS: &[ConfigKey] = &[
+    ConfigKey {
+        name: "ttl_secs",
+        kind: ValueKind::Duration,
+        default: || toml_int(DEFAULT_TTL_SECS).into(),
+    },
+    ConfigKey {
+        name: "max_ttl_secs",
+        kind: ValueKind::Duration,
+        default: || toml_int(DEFAULT_MAX_TTL_SECS).into(),
+    },
+    ConfigKey {
+        name: "refresh_ttl_on_read",
+        kind: ValueKind::Bool,
+        default: || DEFAULT_REFRESH_TTL_ON_READ.into(),
+    },
+    ConfigKey {
+        name: "refresh_ttl_on_identical",
+        kind: ValueKind::Bool,
+        default: || DEFAULT_REFRESH_TTL_ON_IDENTICAL.into(),
+    },
+    ConfigKey {
+        name: "max_image_dimension",
+        kind: ValueKind::Integer,
+        default: || DEFAULT_MAX_IMAGE_DIMENSION.into(),
+    },
+    ConfigKey {
+        name: "max_image_width",
+        kind: ValueKind::Integer,
+        default: || DEFAULT_MAX_IMAGE_DIMENSION.into(),
+    },
+    ConfigKey {
+        name: "max_image_height",
+        kind: ValueKind::Integer,
+        default: || DEFAULT_MAX_IMAGE_DIMENSION.into(),
+    },
+    ConfigKey {
+        name: "downscale_never_grow",
+        kind: ValueKind::Bool,
+        default: || DEFAULT_DOWNSCALE_NEVER_GROW.into(),
+    },
+    ConfigKey {
+        name: "strip_exif",
+        kind: ValueKind::Bool,
+        default: || DEFAULT_STRIP_EXIF.into(),
+    },
+    ConfigKey {
+        name: "apply_exif_orientation",
+        kind: ValueKind::Bool,
+        default: || DEFAULT_APPLY_EXIF_ORIENTATION.into(),
+    },
+    ConfigKey {
+        name: "image_output_format",
+        kind: ValueKind::String,
+        default: || DEFAULT_IMAGE_OUTPUT_FORMAT.into(),
+    },
+    ConfigKey {
+        name: "convert_large_png_to_jpeg_kb",
+        kind: ValueKind::Integer,
+        default: || toml_int(DEFAULT_CONVERT_LARGE_PNG_TO_JPEG_KB).into(),
+    },
+    ConfigKey {
+        name: "jpeg_quality",
+        kind: ValueKind::Integer,
+        default: || i64::from(DEFAULT_JPEG_QUALITY).into(),
+    },
+    ConfigKey {
+        name: "png_compression",
+        kind: ValueKind::String,
+        default: || DEFAULT_PNG_COMPRESSION.into(),
+    },
+    ConfigKey {
+        name: "optimize_png",
+        kind: ValueKind::Bool,
+        default: || DEFAULT_OPTIMIZE_PNG.into(),
+    },
+    ConfigKey {
+        name: "webp_mode",
+        kind: ValueKind::String,
+        default: || DEFAULT_WEBP_MODE.into(),
+    },
+    ConfigKey {
+        name: "webp_quality",
+        kind: ValueKind::Integer,
+        default: || i64::from(DEFAULT_WEBP_QUALITY).into(),
+    },
+    ConfigKey {
+        name: "jpeg_progressive",
+        kind: ValueKind::Bool,
+        default: || DEFAULT_JPEG_PROGRESSIVE.into(),
+    },
+    ConfigKey {
+        name: "jpeg_background_color",
+        kind: ValueKind::String,
+        default: || hex_color(DEFAULT_JPEG_BACKGROUND_COLOR).into(),
+    },
+    ConfigKey {
+        name: "downscale_filter",
+        kind: ValueKind::String,
+        default: || DEFAULT_DOWNSCALE_FILTER.into(),
+    },
+    ConfigKey {
+        name: "max_encoded_size_kb",
+        kind: ValueKind::Integer,
+        default: || toml_int(DEFAULT_MAX_ENCODED_SIZE_KB).into(),
+    },
+    ConfigKey {
+        name: "max_image_pixels",
+        kind: ValueKind::Integer,
+        default: || toml_int(DEFAULT_MAX_IMAGE_PIXELS).into(),
+    },
+    ConfigKey {
+        name: "store_oversized_images",
+        kind: ValueKind::Bool,
+        default: || DEFAULT_STORE_OVERSIZED_IMAGES.into(),
+    },
+    ConfigKey {
+        name: "max_file_size",
+        kind: ValueKind::Size,
+        default: || toml_int(DEFAULT_MAX_FILE_SIZE_MB * 1024 * 1024).into(),
+    },
+    ConfigKey {
+        name: "max_file_size_mb",
+        kind: ValueKind::Integer,
+        default: || toml_int(DEFAULT_MAX_FILE_SIZE_MB).into(),
+    },
+    ConfigKey {
+        name: "max_text_size_kb",
+        kind: ValueKind::Integer,
+        default: || toml_int(DEFAULT_MAX_TEXT_SIZE_KB).into(),
+    },
+    ConfigKey {
+        name: "allowed_directories",
+        kind: ValueKind::PathList,
+        default: || toml::Value::Array(Vec::new()),
+    },
+    ConfigKey {
+        name: "denied_directories",
+        kind: ValueKind::PathList,
+        default: || toml::Value::Array(Vec::new()),
+    },
+    ConfigKey {
+        name: "follow_symlinks",
+        kind: ValueKind::Bool,
+        default: || DEFAULT_FOLLOW_SYMLINKS.into(),
+    },
+    ConfigKey {
+        name: "allowed_mime_types",
+        kind: ValueKind::List,
+        default: || toml::Value::Array(Vec::new()),
+    },
+    ConfigKey {
+        name: "denied_mime_types",
+        kind: ValueKind::List,
+        default: || toml::Value::Array(Vec::new()),
+    },
+    ConfigKey {
+        name: "allowed_image_formats",
+        kind: ValueKind::List,
+        default: || toml::Value::Array(Vec::new()),
+    },
+    ConfigKey {
+        name: "clipboard_mode",
+        kind: ValueKind::String,
+        default: || DEFAULT_CLIPBOARD_MODE.into(),
+    },
+    ConfigKey {
+        name: "cache_wl_images",
+        kind: ValueKind::Bool,
+        default: || DEFAULT_CACHE_WL_IMAGES.into(),
+    },
+    ConfigKey {
+        name: "windows_file_fallback",
+        kind: ValueKind::Bool,
+        default: || DEFAULT_WINDOWS_FILE_FALLBACK.into(),
+    },
+    ConfigKey {
+        name: "empty_input",
+        kind: ValueKind::String,
+        default: || DEFAULT_EMPTY_INPUT.into(),
+    },
+    ConfigKey {
+        name: "invalid_utf8",
+        kind: ValueKind::String,
+        default: || DEFAULT_INVALID_UTF8.into(),
+    },
+    ConfigKey {
+        name: "trim_trailing_whitespace",
+        kind: ValueKind::Bool,
+        default: || DEFAULT_TRIM_TRAILING_WHITESPACE.into(),
+    },
+    ConfigKey {
+        name: "record_source_path",
+        kind: ValueKind::Bool,
+        default: || DEFAULT_RECORD_SOURCE_PATH.into(),
+    },
+    ConfigKey {
+        name: "invalid_paths",
+        kind: ValueKind::String,
+        default: || DEFAULT_INVALID_PATHS.into(),
+    },
+    ConfigKey {
+        name: "mislabeled_images",
+        kind: ValueKind::String,
+        default: || DEFAULT_MISLABELED_IMAGES.into(),
+    },
+    ConfigKey {
+        name: "watch_interval_ms",
+        kind: ValueKind::Integer,
+        default: || toml_int(DEFAULT_WATCH_INTERVAL_MS).into(),
+    },
+    ConfigKey {
+        name: "read_only",
+        kind: ValueKind::Bool,
+        default: || DEFAULT_READ_ONLY.into(),
+    },
+    ConfigKey {
+        name: "default_output_target",
+        kind: ValueKind::String,
+        default: || DEFAULT_OUTPUT_TARGET.into(),
+    },
+    ConfigKey {
+        name: "default_input_mime",
+        kind: ValueKind::String,
+        default: || DEFAULT_INPUT_MIME.into(),
+    },
+    ConfigKey {
+        name: "preferred_target_order",
+        kind: ValueKind::List,
+        default: || toml::Value::Array(Vec::new()),
+    },
+    ConfigKey {
+        name: "suggest_targets",
+        kind: ValueKind::Bool,
+        default: || DEFAULT_SUGGEST_TARGETS.into(),
+    },
+    ConfigKey {
+        name: "file_mode",
+        kind: ValueKind::String,
+        default: || format!("{DEFAULT_FILE_MODE:04o}").into(),
+    },
+    ConfigKey {
+        name: "dir_mode",
+        kind: ValueKind::String,
+        default: || format!("{DEFAULT_DIR_MODE:04o}").into(),
+    },
+    ConfigKey {
+        name: "storage_backend",
+        kind: ValueKind::String,
+        default: || DEFAULT_STORAGE_BACKEND.into(),
+    },
+    ConfigKey {
+        name: "prefer_runtime_dir",
+        kind: ValueKind::Bool,
+        default: || DEFAULT_PREFER_RUNTIME_DIR.into(),
+    },
+    ConfigKey {
+        name: "secure_delete",
+        kind: ValueKind::Bool,
+        default: || DEFAULT_SECURE_DELETE.into(),
+    },
+    ConfigKey {
+        name: "durable_writes",
+        kind: ValueKind::Bool,
+        default: || DEFAULT_DURABLE_WRITES.into(),
+    },
+    ConfigKey {
+        name: "compress_storage",
+        kind: ValueKind::Bool,
+        default: || DEFAULT_COMPRESS_STORAGE.into(),
+    },
+    ConfigKey {
+        name: "encrypt_storage",
+        kind: ValueKind::Bool,
+        default: || DEFAULT_ENCRYPT_STORAGE.into(),
+    },
+    ConfigKey {
+        name: "verify_checksums",
+        kind: ValueKind::Bool,
+        default: || DEFAULT_VERIFY_CHECKSUMS.into(),
+    },
+    ConfigKey {
+        name: "history_size",
+        kind: ValueKind::Integer,
+        default: || toml_int(DEFAULT_HISTORY_SIZE).into(),
+    },
+    ConfigKey {
+        name: "max_storage_mb",
+        kind: ValueKind::Integer,
+        default: || toml_int(DEFAULT_MAX_STORAGE_MB).into(),
+    },
+];
+
+/// Alternate spellings accepted for a key, in the file and as env vars
+const KEY_ALIASES: &[(&str, &str)] = &[("ttl", "ttl_secs")];
+
+fn env_var_name(key: &str) -> String {
+    format!("WSL_CLIP_BRIDGE_{}", key.to_ascii_uppercase())
+}
+
+fn parse_env_value(kind: ValueKind, raw: &str) -> Result<toml::Value, String> {
+    let raw = raw.trim();
+    match kind {
+        ValueKind::Integer => raw
+            .parse::<i64>()
+            .map(toml::Value::Integer)
+            .map_err(|_| "expected an integer".to_string()),
+        ValueKind::Bool => match raw.to_ascii_lowercase().as_str() {
+            "1" | "true" | "yes" | "on" => Ok(true.into()),
+            "0" | "false" | "no" | "off" => Ok(false.into()),
+            _ => Err("expected true/false".to_string()),
+        },
+        ValueKind::Duration => parse_duration(raw).map(|secs| toml_int(secs).into()),
+        ValueKind::Size => parse_size(raw).map(|bytes| toml_int(bytes).into()),
+        ValueKind::String => Ok(raw.into()),
+        ValueKind::PathList => Ok(toml::Value::Array(
+            raw.split(':')
+                .filter(|p| !p.is_empty())
+                .map(toml::Value::from)
+                .collect(),
+        )),
+        ValueKind::List => Ok(toml::Value::Array(
+            raw.split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(toml::Value::from)
+                .collect(),
+        )),
+    }
+}
+
+/// Keys whose loss would silently widen file access if the config is ignored
+const SECURITY_KEYS: &[&str] = &[
+    "allowed_directories",
+    "denied_directories",
+    "follow_symlinks",
+    "allowed_mime_types",
+    "denied_mime_types",
+    "allowed_image_formats",
+    "read_only",
+    "encrypt_storage",
+];
+
+fn mentions_security_keys(contents: &str) -> bool {
+    // Prefer a structural check; fall back to a line scan for broken syntax
+    if let Ok(table) = toml::from_str::<toml::Table>(contents) {
+        return SECURITY_KEYS.iter().any(|k| table.contains_key(*k));
+    }
+    contents.lines().any(|line| {
+        let line = line.trim_start();
+        SECURITY_KEYS.iter().any(|k| {
+            line.strip_prefix(k)
+                .is_some_and(|rest| rest.trim_start().starts_with('='))
+        })
+    })
+}
+
+fn rename_aliases(table: &mut toml::Table) {
+    for (alias, key) in KEY_ALIASES {
+        if let Some(value) = table.remove(*alias) {
+            table.insert((*key).to_string(), value);
+        }
+    }
+    // Nested override tables accept the same spellings
+    for section in ["selections", "profiles"] {
+        if let Some(toml::Value::Table(entries)) = table.get_mut(section) {
+            for (_, entry) in entries.iter_mut() {
+                if let toml::Value::Table(entry) = entry {
+                    rename_aliases(entry);
                 }
             }
+        }
+    }
+}
 
-            // Clean up expired files
-            if image_path.exists() && !is_file_fresh(&image_path, ttl) {
-                let _ = fs::remove_file(&image_path);
-                let _ = fs::remove_file(get_image_format_path());
+/// Tables that hold further settings rather than being settings themselves
+const SECTION_KEYS: &[&str] = &["selections", "profiles"];
+
+/// Keys a `[selections.NAME]` table understands
+const SELECTION_KEYS: &[&str] = &["ttl_secs", "max_file_size", "max_file_size_mb"];
+
+/// A config key serde would silently ignore, with the closest known key
+#[derive(Debug)]
+struct UnknownKey {
+    path: String,
+    suggestion: Option<&'static str>,
+}
+
+impl fmt::Display for UnknownKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "'{}'", self.path)?;
+        if let Some(suggestion) = self.suggestion {
+            write!(f, " (did you mean '{suggestion}'?)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Keys in the (alias-renamed) config table that no setting reads
+fn find_unknown_keys(table: &toml::Table) -> Vec<UnknownKey> {
+    let settings: Vec<&'static str> = CONFIG_KEYS.iter().map(|key| key.name).collect();
+    let top_level: Vec<&'static str> = settings.iter().chain(SECTION_KEYS).copied().collect();
+    let profile_keys: Vec<&'static str> = settings.iter().chain(&["selections"]).copied().collect();
+
+    let mut unknown = Vec::new();
+    collect_unknown_keys(table, "", &top_level, &mut unknown);
+    for section in SECTION_KEYS {
+        let Some(toml::Value::Table(entries)) = table.get(*section) else {
+            continue;
+        };
+        let known = if *section == "selections" {
+            SELECTION_KEYS
+        } else {
+            &profile_keys
+        };
+        for (name, entry) in entries {
+            if let toml::Value::Table(entry) = entry {
+                collect_unknown_keys(entry, &format!("{section}.{name}."), known, &mut unknown);
             }
+        }
+    }
+    unknown
+}
 
-            Ok(1)
+fn collect_unknown_keys(
+    table: &toml::Table,
+    prefix: &str,
+    known: &[&'static str],
+    unknown: &mut Vec<UnknownKey>,
+) {
+    for key in table.keys() {
+        if known.contains(&key.as_str()) {
+            continue;
         }
-        _ => Ok(1),
+        // Close enough to be a typo rather than an unrelated name
+        let suggestion = known
+            .iter()
+            .map(|candidate| (edit_distance(key, candidate), *candidate))
+            .filter(|(distance, _)| *distance <= (key.len() / 3).max(2))
+            .min_by_key(|(distance, _)| *distance)
+            .map(|(_, candidate)| candidate);
+        unknown.push(UnknownKey {
+            path: format!("{prefix}{key}"),
+            suggestion,
+        });
     }
 }
 
-fn validate_file_access(path: &Path) -> io::Result<()> {
-    // Check file size limit
-    let config = load_config();
-    if let Some(cfg) = config {
-        // Check file size
-        if let Some(max_mb) = cfg.max_file_size_mb
-            && max_mb > 0
-            && let Ok(metadata) = fs::metadata(path)
-        {
-            let max_bytes = max_mb * 1024 * 1024;
-            if metadata.len() > max_bytes {
-                eprintln!("Error: File exceeds maximum size of {max_mb}MB");
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidInput,
-                    "File too large",
-                ));
+/// Levenshtein distance between two key names
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Parse the config file into a table, reporting problems on stderr
+fn read_config_file(path: &Path) -> (ConfigFileState, toml::Table) {
+    if !path.exists() {
+        return (ConfigFileState::Missing, toml::Table::new());
+    }
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            // A file we can't open may hold restrictions we can't see; one
+            // that isn't UTF-8 is scanned for them as far as it's readable
+            let security_relevant = matches!(
+                e.kind(),
+                io::ErrorKind::PermissionDenied | io::ErrorKind::IsADirectory
+            ) || fs::read(path)
+                .is_ok_and(|bytes| mentions_security_keys(&String::from_utf8_lossy(&bytes)));
+            eprintln!(
+                "Warning: Could not read config '{}': {e}{}",
+                path.display(),
+                if security_relevant {
+                    ""
+                } else {
+                    "; using defaults"
+                }
+            );
+            let state = ConfigFileState::Invalid { security_relevant };
+            return (state, toml::Table::new());
+        }
+    };
+    // Deserialize into the typed struct so field type errors are caught too
+    match toml::from_str::<BridgeConfig>(&contents) {
+        Ok(_) => {
+            let mut table: toml::Table = toml::from_str(&contents).unwrap_or_default();
+            rename_aliases(&mut table);
+            (ConfigFileState::Loaded, table)
+        }
+        Err(e) => {
+            let security_relevant = mentions_security_keys(&contents);
+            // toml's error already carries the line/column and a source excerpt
+            eprintln!(
+                "Warning: Invalid config '{}':\n{}",
+                path.display(),
+                e.to_string().trim_end()
+            );
+            if !security_relevant {
+                eprintln!("Warning: Ignoring config file and using defaults");
             }
+            (
+                ConfigFileState::Invalid { security_relevant },
+                toml::Table::new(),
+            )
+        }
+    }
+}
+
+fn resolve_config() -> ResolvedConfig {
+    let (file_state, mut values) = read_config_file(&config_path());
+
+    let unknown_keys = find_unknown_keys(&values);
+    if !quiet() {
+        for key in &unknown_keys {
+            eprintln!("Warning: Unknown config key {key} is ignored");
         }
+    }
 
-        // Security: Check allowed directories if configured
-        // If allowed_directories is set, only those paths are permitted (recursively)
-        // If not set or empty, all paths are allowed
-        if let Some(allowed_dirs) = &cfg.allowed_directories
-            && !allowed_dirs.is_empty()
-        {
-            let canonical_path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
-            let is_allowed = allowed_dirs
-                .iter()
-                .any(|dir| canonical_path.starts_with(PathBuf::from(dir)));
+    let mut sources = HashMap::new();
+    for key in CONFIG_KEYS {
+        if values.contains_key(key.name) {
+            sources.insert(key.name, ConfigSource::File);
+        }
+    }
 
-            if !is_allowed {
-                eprintln!(
-                    "Error: Access denied - file '{}' is not in allowed directories: {:?}",
-                    canonical_path.display(),
-                    allowed_dirs
-                );
-                return Err(io::Error::new(
-                    io::ErrorKind::PermissionDenied,
-                    "File not in allowed directories",
-                ));
+    // The profile's keys replace the top-level ones; env and CLI still win
+    let profile = requested_profile().map(|(name, origin)| {
+        let overrides = match values.get("profiles") {
+            Some(toml::Value::Table(profiles)) => match profiles.get(&name) {
+                Some(toml::Value::Table(overrides)) => Some(overrides.clone()),
+                _ => None,
+            },
+            _ => None,
+        };
+        let found = overrides.is_some();
+        for (key, value) in overrides.into_iter().flatten() {
+            if let Some(config_key) = CONFIG_KEYS.iter().find(|k| k.name == key) {
+                sources.insert(config_key.name, ConfigSource::Profile(name.clone()));
+            }
+            values.insert(key, value);
+        }
+        ActiveProfile {
+            name,
+            origin,
+            found,
+        }
+    });
+
+    for key in CONFIG_KEYS {
+        let aliases = KEY_ALIASES
+            .iter()
+            .filter(|(_, k)| *k == key.name)
+            .map(|(alias, _)| env_var_name(alias));
+        let Some((var, raw)) = std::iter::once(env_var_name(key.name))
+            .chain(aliases)
+            .find_map(|var| env::var(&var).ok().map(|raw| (var, raw)))
+        else {
+            continue;
+        };
+        // Reuse the typed deserializer so env values get the same validation
+        let checked = parse_env_value(key.kind, &raw).and_then(|value| {
+            let single = toml::Table::from_iter([(key.name.to_string(), value.clone())]);
+            single
+                .try_into::<BridgeConfig>()
+                .map(|_| value)
+                .map_err(|_| "value out of range".to_string())
+        });
+        match checked {
+            Ok(value) => {
+                values.insert(key.name.to_string(), value);
+                sources.insert(key.name, ConfigSource::Env(var));
+            }
+            Err(reason) => {
+                eprintln!("Warning: Ignoring {var}={raw:?}: {reason}");
             }
         }
-        // No restrictions if allowed_directories is not configured
     }
-    Ok(())
+
+    if let Some(overrides) = CLI_OVERRIDES.get() {
+        for key in CONFIG_KEYS {
+            if let Some(value) = overrides.get(key.name) {
+                values.insert(key.name.to_string(), value.clone());
+                sources.insert(key.name, ConfigSource::Cli);
+            }
+        }
+    }
+
+    let config = values.clone().try_into().unwrap_or_default();
+    check_dir_patterns(&config);
+    check_image_formats(&config);
+    check_jpeg_progressive(&config);
+    check_webp_settings(&config);
+    ResolvedConfig {
+        file_state,
+        unknown_keys,
+        profile,
+        config,
+        values,
+        sources,
+    }
 }
 
-#[allow(
-    clippy::cast_precision_loss,
-    clippy::cast_possible_truncation,
-    clippy::cast_sign_loss
-)]
-fn downscale_image_if_needed(data: &[u8], mime: &str, max_dim: Option<u32>) -> Vec<u8> {
-    // If no max dimension configured, return original
-    let max_dim = match max_dim {
-        Some(d) if d > 0 => d,
-        _ => return data.to_vec(),
-    };
+/// Commented starter config written by `init-config`
+const DEFAULT_CONFIG: &str = r##"# WSL Clip Bridge Configuration
+#
+# Any key can be overridden per process with WSL_CLIP_BRIDGE_<KEY>,
+# e.g. WSL_CLIP_BRIDGE_TTL_SECS=60 (lists are colon-separated)
+
+# Clipboard data TTL (default: 300, capped at max_ttl_secs)
+# Seconds, or a duration such as "90s", "15m", "2h" or "1h30m"
+# 0 or "never" means content never expires (it does NOT mean "expire
+# immediately"); expired-file cleanup is skipped entirely in that case
+ttl_secs = 300
+
+# Longest TTL that ttl_secs, --ttl or WSL_CLIP_BRIDGE_TTL_SECS can set;
+# longer values are cut down to it (default: one day). Raise it for
+# content that should last e.g. a week ("7d"). It can't go beyond 365 days;
+# 0 means that limit.
+max_ttl_secs = 86400
+
+# Restart the TTL whenever stored content is pasted, so it expires after
+# ttl_secs without use (an idle timeout) instead of ttl_secs after the
+# copy. Listing targets doesn't count as a paste. -verbose reports each
+# refresh.
+refresh_ttl_on_read = false
+
+# Copying exactly what is already stored (same bytes, same MIME type) skips
+# rewriting it and adds no history entry. This decides whether such a copy
+# still restarts the TTL, as a real copy would. -verbose reports each skip.
+refresh_ttl_on_identical = true
+
+# Maximum image dimension for automatic downscaling
+# Set to 1568 for optimal Claude API performance
+# Set to 0 to disable downscaling
+max_image_dimension = 1568
+
+# Separate width and height limits, e.g. for tall scrolling captures.
+# Each one replaces max_image_dimension for its own axis; 0 leaves that
+# axis unconstrained. Aspect ratio is kept and images are never upscaled.
+# max_image_width = 1568
+# max_image_height = 4000
+
+# When a downscaled image encodes larger than the original, try smaller
+# encodings of it (lower JPEG quality, maximum PNG compression) and keep
+# the smallest. The dimension limit always holds, so an image no encoding
+# gets below its original size is still stored downscaled.
+downscale_never_grow = false
+
+# Remove EXIF/XMP metadata (GPS position, camera/device details) from
+# copied images. Any EXIF rotation is applied to the pixels first.
+strip_exif = false
+
+# Rotate/flip copied photos whose EXIF orientation tag says they are stored
+# sideways, so the pixels are upright and the tag no longer matters once
+# downscaling or -rotate re-encodes them. Only images with such a tag are
+# re-encoded (dropping their metadata); all others are stored untouched.
+apply_exif_orientation = true
+
+# Convert every copied image to one format: "png", "jpeg", "webp", or
+# "original" to keep what was copied (default). Animated images and images
+# that fail to convert are stored unchanged.
+image_output_format = "original"
+
+# Store a PNG that is still larger than this many KB after downscaling as a
+# JPEG (at jpeg_quality) instead, if it has no transparent pixels and the
+# JPEG comes out smaller. Suits full-screen screenshots of photos or video;
+# 1024 is a good start. 0 (default) never converts.
+convert_large_png_to_jpeg_kb = 0
+
+# Quality (1-100) used whenever a JPEG is encoded (default: 75)
+jpeg_quality = 75
+
+# Write every encoded JPEG as progressive instead of baseline: it renders
+# coarse-to-fine in browsers and is often a little smaller. Needs the
+# `progressive-jpeg` build feature (on by default).
+jpeg_progressive = false
+
+# Color that transparent pixels are blended onto when an image is encoded as
+# JPEG, which has no transparency: "#rrggbb" or "#rgb" (default: "#ffffff")
+jpeg_background_color = "#ffffff"
+
+# PNG compression used whenever a PNG is encoded: "fast" (default),
+# "default" or "best" (smallest files, slowest)
+png_compression = "fast"
+
+# Losslessly shrink copied PNGs with 256 colors or fewer (terminal and UI
+# screenshots) by storing them with a palette, or grayscale ones as
+# grayscale. The result is kept only if it is smaller; the pixels are
+# identical either way. Costs a full decode of every copied PNG.
+optimize_png = false
+
+# How a WebP is encoded when one is written (downscaling, conversion to
+# image_output_format): "lossless" (default) keeps every pixel, so text in
+# screenshots stays crisp, and is the smaller choice for flat UI captures;
+# "lossy" at webp_quality (0-100, default 75) is far smaller for photos
+# but blurs fine text. "lossy" needs the `lossy-webp` build feature (on by
+# default).
+webp_mode = "lossless"
+# webp_quality = 75
+
+# Resampling filter used when an image is scaled down: "nearest",
+# "triangle", "catmullrom", "gaussian" or "lanczos3" (default; sharpest
+# text, slowest). "triangle" or "catmullrom" are much faster on very large
+# screenshots.
+downscale_filter = "lanczos3"
+
+# Cap on the stored image size in KB (0 = no limit). Images still larger
+# after downscaling are re-encoded (lower JPEG quality, then smaller
+# dimensions) until they fit; copying fails if they can't be made to fit.
+# Animated images are stored as is. Use -verbose to see the result.
+max_encoded_size_kb = 0
+
+# Largest image, in pixels (width x height), that is ever decoded
+# (default: 100000000, 0 = no limit). Checked from the header alone, so a
+# small file declaring huge dimensions (a decompression bomb) is refused
+# before it can exhaust memory. Decoders are also held to the memory such
+# an image would need, in case a header can't be read up front.
+max_image_pixels = 100000000
+
+# Store an image over max_image_pixels exactly as copied instead of
+# refusing it (exit code 4). It is never decoded, so it isn't downscaled,
+# converted or stripped of metadata, and -rotate/-flip still refuse it.
+store_oversized_images = false
+
+# Maximum size of a copied image or file (default: "100MB", 0 = no limit):
+# bytes, or a size such as "500KB", "10MB" or "1.5GB" (1KB = 1024 bytes).
+# The older max_file_size_mb (whole MB) is still read when this isn't set.
+max_file_size = "100MB"
+
+# Maximum text input size in KB (default: 10240 = 10MB, 0 = no limit)
+max_text_size_kb = 10240
+
+# Clipboard integration mode
+# "auto" = Check files first, then wl-clipboard (default)
+# "file_only" = Only use file-based clipboard (ShareX mode)
+clipboard_mode = "auto"
+
+# Cache images from wl-clipboard for faster subsequent access
+cache_wl_images = true
+
+# With nothing stored, make `-o -t text/uri-list` fall back to the files
+# copied in Windows Explorer, asking powershell.exe for them and listing
+# them as /mnt/<drive>/... file:// URIs. Files outside allowed_directories
+# (or under denied_directories) are left out, and UNC paths are skipped.
+# TARGETS doesn't list it, since that would start PowerShell every time.
+windows_file_fallback = false
+
+# What copying empty input does
+# "reject" = Store nothing and exit with code 6 (default)
+# "clear" = Remove the stored content for that type
+# `xclip -clear` (optionally with -t TYPE) clears on purpose either way,
+# so scripts don't have to depend on this setting.
+empty_input = "reject"
+
+# What copying text that isn't valid UTF-8 does
+# "keep" = Store the bytes as is, advertised as text/plain without a
+#          charset (default)
+# "transcode" = Treat the input as Latin-1 (ISO-8859-1) and store it as UTF-8
+# "reject" = Store nothing and exit with code 5
+invalid_utf8 = "keep"
+
+# Strip spaces and tabs from the end of every line of copied text (LF and
+# CRLF endings are kept). Off by default so text is stored byte for byte;
+# images are never touched.
+trim_trailing_whitespace = false
+
+# Remember which file `xclip -i FILE` copied and paste its path with
+# -o -t text/x-source-path. Off by default so paths don't leak into the
+# clipboard; files outside $HOME are never recorded, and a copy from
+# stdin replaces the recorded path.
+record_source_path = false
+
+# Copying a file list with -t text/uri-list (paths as arguments, or one per
+# line on stdin with -i -): what happens to a path that is missing or
+# outside allowed_directories
+# "skip" = Report it and copy the others (default)
+# "abort" = Copy nothing and exit with that path's error code
+invalid_paths = "skip"
+
+# An image copied with a -t that doesn't match its bytes (e.g. -t image/png
+# with JPEG piped in)
+# "correct" = Store it under the format the bytes really are, if that
+#             format is allowed (default; -verbose reports the correction)
+# "reject" = Copy nothing and exit with code 5
+mislabeled_images = "correct"
+
+# How often -watch checks for changes, in milliseconds (minimum 50)
+watch_interval_ms = 500
+
+# Refuse every copy (exit code 8) so pre-provisioned content can only be
+# pasted; pasting also stops deleting expired files
+read_only = false
+
+# What -o without -t pastes (-t TARGETS always lists targets)
+# "text" = The stored text (default)
+# "targets" = List the available targets
+# "auto" = The text if it's fresh, otherwise the stored image
+# "image" = The stored image, whatever its format
+# A MIME type such as "image/png" pastes that type
+# Override per invocation with --default-target; an explicit -t always wins
+default_output_target = "text"
+
+# What -i without -t copies from stdin or a single file. "image/png" suits
+# screenshot workflows: with mislabeled_images = "correct" any supported
+# image is stored under its real format. Several files are always copied as
+# an archive, and an explicit -t always wins.
+default_input_mime = "text/plain"
+
+# Order of -t TARGETS, for tools that take the first target listed when
+# text and an image are both fresh. MIME patterns such as "image/*" or
+# "text/plain"; targets matching an earlier pattern come first, the rest
+# follow in the usual order. Empty (the default) keeps the usual order,
+# which lists a stored image before stored text.
+#
+# preferred_target_order = ["text/plain", "image/*"]
+
+# When -o -t TYPE finds nothing to paste, list the targets that are
+# available on stderr (e.g. after asking for image/png while a JPEG is
+# stored). The exit code is still 1. Off by default, like xclip.
+suggest_targets = false
+
+# Security: Permissions of stored clipboard files and the storage directory
+# (octal; dir_mode applies when the directory is created). The defaults keep
+# clipboard contents private to your user. Loosening them, e.g. "0640" and
+# "0750" for a shared group, lets every member of that group read whatever
+# you copy, including passwords. The owner must keep read/write (files) or
+# full (directory) access, and world-writable modes are rejected; invalid
+# values fall back to the defaults.
+file_mode = "0600"
+dir_mode = "0700"
+
+# Where clipboard data is stored
+# "cache" = $XDG_CACHE_HOME/wsl-clip-bridge or ~/.cache/wsl-clip-bridge
+#           (default; survives reboots and may end up in backups)
+# "runtime" = A tmpfs, so copied content never reaches persistent disk:
+#             $XDG_RUNTIME_DIR/wsl-clip-bridge if that directory exists and
+#             is yours, else /dev/shm/wsl-clip-bridge-$UID, else the cache
+#             directory. Only the location picked is read, so content copied
+#             under the other setting isn't pasted; `xclip doctor` shows the
+#             active location and whether it really is tmpfs.
+# "memory" = Like "runtime", but either location is used only if it really
+#            is tmpfs, and there is no fallback: without one, copies and
+#            pastes are refused rather than touching the disk. Implies
+#            secure_delete = true unless that is set. tmpfs pages can still
+#            be swapped out; use encrypted swap (or none) to rule that out.
+# (prefer_runtime_dir = true, from older configs, means "runtime".)
+storage_backend = "cache"
+
+# Overwrite clipboard files with zeros and flush them to disk before they
+# are deleted or replaced: expiry, gc, empty-input clears, history eviction
+# and every new copy. Useful when bridging secrets on a persistent disk;
+# unneeded with storage_backend = "runtime". Files still linked from
+# history are left intact until their last copy goes. SSDs and
+# copy-on-write filesystems may keep old blocks regardless.
+secure_delete = false
+
+# Flush the storage directory to disk after each copy renames its payload
+# and index into place, so a copy survives `wsl --shutdown` or a crash
+# right after it. Costs a few milliseconds per copy; a flush that fails
+# fails the copy. The files themselves are always flushed before the rename.
+durable_writes = false
+
+# Compress stored text, file lists, archives and images with zstd. Data
+# that doesn't shrink (most PNG/JPEG screenshots) is stored as is, and files
+# written before this was turned on keep working. Size limits apply to the
+# original size; `xclip status` shows both. Other programs reading the
+# storage directory directly will see compressed files.
+compress_storage = false
+
+# Encrypt stored text, file lists, archives and images with
+# XChaCha20-Poly1305, using the 32-byte key in the file named by
+# $WSL_CLIP_BRIDGE_KEY_FILE (e.g. `head -c 32 /dev/urandom > ~/.clip.key`).
+# Copies fail without a usable key; pastes of encrypted data need it even
+# after this is turned off. The index (MIME types, times, checksums) stays
+# plaintext. To rotate the key, `xclip -clear` (in each --slot too), then
+# switch to the new key file.
+encrypt_storage = false
+
+# Check each payload against the CRC-32 recorded when it was copied before
+# pasting it. A mismatch (e.g. a file truncated by an unclean shutdown) is
+# reported, removed and pasted as nothing; `xclip status` and gc flag such
+# entries too. Turn off to skip the extra pass over very large payloads.
+verify_checksums = true
+
+# Number of earlier copies to keep (default: 0 = no history). Text,
+# images and archives share one chronological list, oldest dropped first,
+# and entries expire with the TTL like the current content.
+#   xclip -o --index 1     paste the copy before the latest one
+#   xclip history list     index, type, size, age and a preview
+history_size = 0
+
+# Cap on everything stored, history and slots included (default: 0 = no
+# cap). A copy that wouldn't fit first evicts from its own store: expired
+# entries, then the oldest history entries and other stored types, never
+# pinned content. A copy larger than the cap, or one that still can't fit,
+# fails with exit code 4. `xclip status` shows usage against the cap.
+max_storage_mb = 0
+
+# Security: Directory access restrictions
+# If not configured, all paths are allowed
+# To restrict access to specific directories (and their subdirectories):
+# A leading ~ and $VAR / ${VAR} are expanded; entries using an unset
+# variable are skipped with a warning
+# Entries may be glob patterns: * and ? match within one path component,
+# ** matches any number of them. A pattern names directories, so files
+# anywhere beneath a matching directory are covered. Plain entries keep
+# matching as path prefixes.
+#
+# allowed_directories = [
+#   "/mnt/c/Users/YOUR_USERNAME/Documents/ShareX",
+#   "/mnt/*/Users/*/Pictures/ShareX",
+#   "$WIN_HOME/Pictures/ShareX",
+#   "~",
+#   "/tmp"
+# ]
+#
+# Directories that are always refused, even inside allowed_directories.
+# Same syntax; an entry that can't be used refuses every file.
+#
+# denied_directories = ["~/.ssh", "/mnt/*/Users/*/AppData"]
+#
+# Files named with -i are judged by where they resolve to. Set this to
+# false to refuse any path that is a symlink or passes through one, even
+# inside allowed_directories. --no-follow-symlinks / --follow-symlinks
+# override it for one run.
+#
+# follow_symlinks = false
+
+# Security: MIME type policy, applied when copying and when pasting
+# Entries may use wildcards ("image/*"); denied_mime_types wins over
+# allowed_mime_types, and an empty or missing allow list allows everything.
+# "text/plain" covers every text target, "image/jpeg" also covers image/jpg.
+#
+# allowed_mime_types = ["image/png", "text/plain"]
+# denied_mime_types = ["image/*"]
+
+# Security: image formats accepted for copying and served when pasting
+# ("png", "jpeg", "gif", "webp", and "avif" in builds with the avif feature).
+# Other formats fail as unsupported and are not advertised by -t TARGETS; an
+# empty or missing list allows all of them.
+#
+# allowed_image_formats = ["png"]
 
-    // Try to load the image
-    let Ok(img) = image::load_from_memory(data) else {
-        return data.to_vec(); // If can't load, return original
-    };
+# Per-selection overrides of ttl_secs and max_file_size, looked up
+# before the global values (selection names: primary, secondary, clipboard).
+# Tables must come after all top-level keys.
+#
+# [selections.primary]
+# ttl_secs = "30s"
+#
+# [selections.clipboard]
+# ttl_secs = "1h"
+# max_file_size = "200MB"
 
-    let (width, height) = (img.width(), img.height());
-    let max_current = width.max(height);
+# Profiles: named sets of overrides for the top-level keys, selected with
+# --profile NAME or WSL_CLIP_BRIDGE_PROFILE=NAME. Environment variables and
+# command-line flags still take precedence over the profile.
+#
+# [profiles.claude]
+# max_image_dimension = 1568
+# ttl_secs = "5m"
+#
+# [profiles.general]
+# max_image_dimension = 0
+# ttl_secs = "1d"
+"##;
 
-    // Only downscale if exceeds max dimension
-    if max_current <= max_dim {
-        return data.to_vec();
+/// Write the default config, refusing to clobber an existing file unless forced
+fn init_config(force: bool) -> io::Result<i32> {
+    let path = config_path();
+    if path.exists() && !force {
+        eprintln!(
+            "Error: Config already exists at '{}' (use --force to overwrite)",
+            path.display()
+        );
+        return Ok(EXIT_USAGE);
     }
+    ensure_config_directory(&path)?;
+    fs::write(&path, DEFAULT_CONFIG)?;
+    #[cfg(unix)]
+    {
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+    }
+    println!("Wrote default config to {}", path.display());
+    Ok(EXIT_SUCCESS)
+}
 
-    // Calculate new dimensions preserving aspect ratio
-    let scale = max_dim as f32 / max_current as f32;
-    let new_width = (width as f32 * scale) as u32;
-    let new_height = (height as f32 * scale) as u32;
+fn ensure_config_directory(path: &Path) -> io::Result<()> {
+    if let Some(dir) = path.parent()
+        && !dir.exists()
+    {
+        fs::create_dir_all(dir)?;
+        #[cfg(unix)]
+        {
+            let _ = fs::set_permissions(dir, fs::Permissions::from_mode(0o700));
+        }
+    }
+    Ok(())
+}
 
-    // Resize using Lanczos3 (best quality for screenshots with text)
-    let resized = img.resize_exact(new_width, new_height, FilterType::Lanczos3);
+/// `config get|set|add|remove KEY [VALUE]`
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ConfigEdit {
+    Get(String),
+    Set(String, String),
+    Add(String, String),    // append to a list-valued key
+    Remove(String, String), // drop an entry from a list-valued key
+}
 
-    // Encode back to original format
-    let format = match mime {
-        "image/png" => ImageFormat::Png,
-        "image/jpeg" | "image/jpg" => ImageFormat::Jpeg,
-        "image/gif" => ImageFormat::Gif,
-        "image/webp" => ImageFormat::WebP,
-        _ => return data.to_vec(), // Unknown format, return original
-    };
+fn parse_config_edit(it: &mut impl Iterator<Item = String>) -> Result<ConfigEdit, String> {
+    const USAGE: &str =
+        "usage: config get KEY | config set KEY VALUE | config add|remove KEY VALUE";
+    let verb = it.next().ok_or(USAGE)?;
+    let key = it.next().ok_or(USAGE)?;
+    if verb == "get" {
+        return Ok(ConfigEdit::Get(key));
+    }
+    let value = it.next().ok_or(USAGE)?;
+    match verb.as_str() {
+        "set" => Ok(ConfigEdit::Set(key, value)),
+        "add" => Ok(ConfigEdit::Add(key, value)),
+        "remove" => Ok(ConfigEdit::Remove(key, value)),
+        _ => Err(USAGE.to_string()),
+    }
+}
 
-    let mut output = Cursor::new(Vec::new());
-    if resized.write_to(&mut output, format).is_err() {
-        return data.to_vec(); // If encoding fails, return original
+/// What a value of this kind must look like, for error messages
+const fn describe_kind(kind: ValueKind) -> &'static str {
+    match kind {
+        ValueKind::Integer => "a non-negative integer",
+        ValueKind::Bool => "true or false",
+        ValueKind::String => "a string",
+        ValueKind::PathList => "a colon-separated list of paths",
+        ValueKind::List => "a comma-separated list",
+        ValueKind::Duration => "seconds or a duration like \"15m\" or \"never\"",
+        ValueKind::Size => "bytes or a size like \"500KB\" or \"1.5GB\"",
     }
+}
 
-    output.into_inner()
+/// Look up a settable key by name or alias
+fn find_config_key(name: &str) -> Result<&'static ConfigKey, String> {
+    let name = KEY_ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == name)
+        .map_or(name, |(_, key)| *key);
+    CONFIG_KEYS
+        .iter()
+        .find(|key| key.name == name)
+        .ok_or_else(|| {
+            let suggestion = CONFIG_KEYS
+                .iter()
+                .map(|key| (edit_distance(name, key.name), key.name))
+                .filter(|(distance, _)| *distance <= (name.len() / 3).max(2))
+                .min_by_key(|(distance, _)| *distance)
+                .map_or_else(String::new, |(_, key)| format!(" (did you mean '{key}'?)"));
+            format!("Unknown config key '{name}'{suggestion}")
+        })
 }
 
-#[allow(clippy::too_many_lines)]
-fn input_type(mime: &str, file: Option<&String>) -> io::Result<i32> {
-    ensure_storage_directory()?;
-    match mime {
-        m if m.starts_with("text/plain") => {
-            let text_path = get_text_path();
-            if let Some(path_str) = file {
-                let path = Path::new(path_str);
-                validate_file_access(path)?;
-                fs::copy(path, &text_path)?;
-            } else {
-                let mut buffer = Vec::new();
-                io::stdin().read_to_end(&mut buffer)?;
-                let mut file = File::create(&text_path)?;
-                file.write_all(&buffer)?;
-            }
-            // restrict perms to user on unix
-            #[cfg(unix)]
-            {
-                let _ = fs::set_permissions(&text_path, fs::Permissions::from_mode(0o600));
-            }
-            Ok(0)
+/// Parse and type-check a value the same way environment overrides are
+fn parse_config_value(key: &ConfigKey, raw: &str) -> Result<toml::Value, String> {
+    let invalid = || {
+        format!(
+            "Invalid value {raw:?} for {}: expected {}",
+            key.name,
+            describe_kind(key.kind)
+        )
+    };
+    let value = parse_env_value(key.kind, raw).map_err(|_| invalid())?;
+    // Keep durations ("15m") and sizes ("10MB") as written when they aren't
+    // plain numbers
+    let value = match key.kind {
+        ValueKind::Duration | ValueKind::Size if raw.trim().parse::<u64>().is_err() => {
+            raw.trim().into()
         }
-        "image/png" | "image/jpeg" | "image/jpg" | "image/gif" | "image/webp" => {
-            let image_path = get_image_path();
-            let format_path = get_image_format_path();
+        _ => value,
+    };
+    toml::Table::from_iter([(key.name.to_string(), value.clone())])
+        .try_into::<BridgeConfig>()
+        .map(|_| value)
+        .map_err(|_| invalid())
+}
 
-            // Read the image data
-            let mut img_data = Vec::new();
-            if let Some(path_str) = file {
-                let path = Path::new(path_str);
-                validate_file_access(path)?;
-                let mut f = File::open(path)?;
-                f.read_to_end(&mut img_data)?;
-            } else {
-                // Check stdin size limit
-                let config = load_config();
-                let max_bytes = config
-                    .and_then(|c| c.max_file_size_mb)
-                    .map_or(100 * 1024 * 1024, |mb| mb * 1024 * 1024); // Default 100MB
-
-                let mut limited_reader = io::stdin().take(max_bytes + 1);
-                limited_reader.read_to_end(&mut img_data)?;
-
-                if img_data.len() > max_bytes.try_into().unwrap_or(usize::MAX) {
-                    eprintln!("Error: Input exceeds maximum size");
-                    return Ok(1);
-                }
-            }
+fn to_edit_value(value: &toml::Value) -> Option<toml_edit::Value> {
+    match value {
+        toml::Value::Integer(i) => Some((*i).into()),
+        toml::Value::Boolean(b) => Some((*b).into()),
+        toml::Value::String(s) => Some(s.as_str().into()),
+        toml::Value::Array(items) => items
+            .iter()
+            .map(to_edit_value)
+            .collect::<Option<toml_edit::Array>>()
+            .map(toml_edit::Value::Array),
+        _ => None,
+    }
+}
 
-            // Optionally downscale based on config
-            let config = load_config();
-            let max_dim = config.and_then(|c| c.max_image_dimension);
-            let processed_data = downscale_image_if_needed(&img_data, mime, max_dim);
+/// The list stored under `key` with `entry` added or removed; None when
+/// removing an entry that isn't there
+fn edited_list(
+    doc: &toml_edit::DocumentMut,
+    key: &str,
+    entry: &str,
+    add: bool,
+) -> Option<toml::Value> {
+    let mut items: Vec<toml::Value> = doc
+        .get(key)
+        .and_then(|item| item.as_array())
+        .map(|array| {
+            array
+                .iter()
+                .filter_map(|v| v.as_str().map(toml::Value::from))
+                .collect()
+        })
+        .unwrap_or_default();
+    let entry = toml::Value::from(entry);
+    if add {
+        if !items.contains(&entry) {
+            items.push(entry);
+        }
+    } else {
+        let pos = items.iter().position(|item| *item == entry)?;
+        items.remove(pos);
+    }
+    Some(toml::Value::Array(items))
+}
 
-            // Write the (possibly downscaled) image
-            let mut file = File::create(&image_path)?;
-            file.write_all(&processed_data)?;
+/// Read or change one key in config.toml, keeping comments and layout
+fn edit_config(edit: &ConfigEdit) -> io::Result<i32> {
+    let (name, raw) = match edit {
+        ConfigEdit::Get(name) => (name, None),
+        ConfigEdit::Set(name, raw) | ConfigEdit::Add(name, raw) | ConfigEdit::Remove(name, raw) => {
+            (name, Some(raw))
+        }
+    };
+    let key = match find_config_key(name) {
+        Ok(key) => key,
+        Err(msg) => {
+            eprintln!("Error: {msg}");
+            return Ok(EXIT_USAGE);
+        }
+    };
+    let Some(raw) = raw else {
+        // get reports the effective value, wherever it came from
+        let value = resolved_config()
+            .values
+            .get(key.name)
+            .cloned()
+            .unwrap_or_else(key.default);
+        println!("{value}");
+        return Ok(EXIT_SUCCESS);
+    };
 
-            // Store the format (normalize jpg to jpeg)
-            let format = if mime == "image/jpg" {
-                "image/jpeg"
-            } else {
-                mime
-            };
-            fs::write(&format_path, format)?;
+    let is_list = matches!(key.kind, ValueKind::PathList | ValueKind::List);
+    if !is_list && !matches!(edit, ConfigEdit::Set(..)) {
+        eprintln!(
+            "Error: add/remove only work on list-valued keys; use config set for {}",
+            key.name
+        );
+        return Ok(EXIT_USAGE);
+    }
 
-            #[cfg(unix)]
-            {
-                let _ = fs::set_permissions(&image_path, fs::Permissions::from_mode(0o600));
-                let _ = fs::set_permissions(&format_path, fs::Permissions::from_mode(0o600));
-            }
-            Ok(0)
-        }
-        _ => {
-            // Reject unsupported formats
+    let path = config_path();
+    let contents = if path.exists() {
+        fs::read_to_string(&path)?
+    } else {
+        String::new()
+    };
+    let mut doc: toml_edit::DocumentMut = match contents.parse() {
+        Ok(doc) => doc,
+        Err(e) => {
             eprintln!(
-                "Error: Unsupported format '{mime}'. Only PNG, JPEG, GIF, and WebP are supported."
+                "Error: Can't edit '{}' because it isn't valid TOML:\n{}",
+                path.display(),
+                e.to_string().trim_end()
             );
-            Ok(1)
+            return Ok(EXIT_USAGE);
         }
-    }
-}
+    };
 
-fn main() -> ExitCode {
-    let args = parse_args();
+    // An alias spelling in the file is replaced by the canonical key
+    for (alias, _) in KEY_ALIASES.iter().filter(|(_, k)| *k == key.name) {
+        doc.remove(alias);
+    }
 
-    // Output mode handling
-    if args.mode_output {
-        let code = match args.mime_type.as_deref() {
-            Some("TARGETS") => {
-                print_targets();
-                0
+    let new_value = if matches!(edit, ConfigEdit::Set(..)) {
+        match parse_config_value(key, raw) {
+            Ok(value) => value,
+            Err(msg) => {
+                eprintln!("Error: {msg}");
+                return Ok(EXIT_USAGE);
             }
-            None => output_type("text/plain").unwrap_or(1), // Default to text/plain
-            Some(m) => output_type(m).unwrap_or(1),
+        }
+    } else {
+        let add = matches!(edit, ConfigEdit::Add(..));
+        let Some(list) = edited_list(&doc, key.name, raw, add) else {
+            eprintln!("Warning: {raw:?} is not in {}; nothing to remove", key.name);
+            return Ok(EXIT_SUCCESS);
         };
-        return ExitCode::from(code.try_into().unwrap_or(1));
+        list
+    };
+    let Some(edit_value) = to_edit_value(&new_value) else {
+        eprintln!("Error: Can't write {} as TOML", key.name);
+        return Ok(EXIT_USAGE);
+    };
+    doc[key.name] = toml_edit::value(edit_value);
+
+    // Never leave behind a file the bridge itself would reject
+    let updated = doc.to_string();
+    if let Err(e) = toml::from_str::<BridgeConfig>(&updated) {
+        eprintln!("Error: The edited config would be invalid: {e}");
+        return Ok(EXIT_USAGE);
     }
 
-    // input mode: default type to text/plain if none provided
-    let mime = args.mime_type.as_deref().unwrap_or("text/plain");
-    let code = input_type(mime, args.input_file.as_ref()).unwrap_or(1);
-    ExitCode::from(code.try_into().unwrap_or(1))
+    // Write a sibling temp file and rename it over the original
+    ensure_config_directory(&path)?;
+    let tmp_path = path.with_extension("toml.tmp");
+    fs::write(&tmp_path, &updated)?;
+    #[cfg(unix)]
+    {
+        let mode = fs::metadata(&path).map_or(0o600, |m| m.permissions().mode() & 0o777);
+        fs::set_permissions(&tmp_path, fs::Permissions::from_mode(mode))?;
+    }
+    fs::rename(&tmp_path, &path)?;
+    println!("{} = {new_value}", key.name);
+    Ok(EXIT_SUCCESS)
 }
 
-// Config & TTL handling
-#[derive(Debug, Deserialize, Default)]
-struct BridgeConfig {
-    #[serde(default)]
-    ttl_secs: Option<u64>,
-    #[serde(default)]
-    max_image_dimension: Option<u32>,
-    #[serde(default)]
-    max_file_size_mb: Option<u64>,
-    #[serde(default)]
-    allowed_directories: Option<Vec<String>>,
-
-    // wl-clipboard integration options
-    #[serde(default)]
-    clipboard_mode: Option<String>, // "auto", "file_only"
-    #[serde(default)]
-    cache_wl_images: Option<bool>, // Cache converted BMP→PNG
-}
+/// TTL meaning "never expire", configured as `ttl_secs = 0` or `"never"`
+const NEVER_EXPIRES: Duration = Duration::MAX;
 
-fn config_dir() -> PathBuf {
-    if let Ok(xdg) = env::var("XDG_CONFIG_HOME")
-        && !xdg.trim().is_empty()
+fn load_ttl() -> Duration {
+    // WSL_CLIP_BRIDGE_TTL_SECS or $XDG_CONFIG_HOME/wsl-clip-bridge/config.toml;
+    // an explicit --ttl still beats a per-selection value
+    let selection_ttl = selection_overrides()
+        .and_then(|overrides| overrides.ttl_secs)
+        .filter(|_| *resolved_config().source("ttl_secs") != ConfigSource::Cli);
+    match selection_ttl
+        .or_else(|| load_config().ttl_secs)
+        .unwrap_or(DEFAULT_TTL_SECS)
     {
-        return PathBuf::from(xdg).join("wsl-clip-bridge");
+        0 => NEVER_EXPIRES, // The cap doesn't apply to "never"
+        secs => Duration::from_secs(secs.min(max_ttl_secs())),
     }
-    env::var("HOME").map_or_else(
-        |_| PathBuf::from("/tmp").join("wsl-clip-bridge"),
-        |h| PathBuf::from(h).join(".config").join("wsl-clip-bridge"),
-    )
 }
 
-fn config_path() -> PathBuf {
-    if let Ok(p) = env::var("WSL_CLIP_BRIDGE_CONFIG")
-        && !p.trim().is_empty()
-    {
-        return PathBuf::from(p);
+/// Longest TTL honoured: `max_ttl_secs`, where 0 means the absolute ceiling
+fn max_ttl_secs() -> u64 {
+    match load_config().max_ttl_secs.unwrap_or(DEFAULT_MAX_TTL_SECS) {
+        0 => ABSOLUTE_MAX_TTL_SECS,
+        secs => secs.min(ABSOLUTE_MAX_TTL_SECS),
     }
-    config_dir().join("config.toml")
 }
 
-fn load_config() -> Option<BridgeConfig> {
-    let path = config_path();
-    if !path.exists() {
-        // attempt to create default config file
-        if let Some(dir) = path.parent() {
-            let _ = fs::create_dir_all(dir);
-            #[cfg(unix)]
-            {
-                let _ = fs::set_permissions(dir, fs::Permissions::from_mode(0o700));
+/// Print every setting with its effective value and where it came from.
+/// The output is valid TOML so it can seed a new config file.
+fn print_effective_config() {
+    let resolved = resolved_config();
+    let file_state = match resolved.file_state {
+        ConfigFileState::Missing => "not found, using defaults",
+        ConfigFileState::Loaded => "loaded",
+        ConfigFileState::Invalid { .. } => "invalid, ignored",
+    };
+
+    println!("# Effective wsl-clip-bridge configuration");
+    println!("# Config file: {} ({file_state})", config_path().display());
+    println!("# Storage directory: {}", get_storage_directory().display());
+    if let Some(profile) = &resolved.profile {
+        println!("# Profile: {} (from {})", profile.name, profile.origin);
+    }
+    for key in &resolved.unknown_keys {
+        println!("# Unknown key (ignored): {key}");
+    }
+    println!();
+
+    for key in CONFIG_KEYS {
+        let value = match key.name {
+            // Show the TTL after clamping, since that's what actually applies
+            "ttl_secs" => match load_ttl() {
+                NEVER_EXPIRES => 0.into(),
+                ttl => toml_int(ttl.as_secs()).into(),
+            },
+            // Unset axis limits fall back to max_image_dimension
+            "max_image_width" => image_size_limits().0.into(),
+            "max_image_height" => image_size_limits().1.into(),
+            name => resolved
+                .values
+                .get(name)
+                .cloned()
+                .unwrap_or_else(key.default),
+        };
+        print_setting(key.name, &value, &resolved.source(key.name).to_string());
+    }
+
+    // Tables have to follow the top-level keys to stay valid TOML
+    if let Some(toml::Value::Table(selections)) = resolved.values.get("selections") {
+        for (name, overrides) in selections {
+            let toml::Value::Table(overrides) = overrides else {
+                continue;
+            };
+            println!();
+            println!("[selections.{name}]");
+            for (key, value) in overrides {
+                print_setting(key, value, "config file");
             }
         }
-        let default = "# WSL Clip Bridge Configuration\n\n# Clipboard data TTL in seconds (default: 300)\nttl_secs = 300\n\n# Maximum image dimension for automatic downscaling\n# Set to 1568 for optimal Claude API performance\n# Set to 0 to disable downscaling\nmax_image_dimension = 1568\n\n# Maximum file size in MB (default: 100)\nmax_file_size_mb = 100\n\n# Clipboard integration mode\n# \"auto\" = Check files first, then wl-clipboard (default)\n# \"file_only\" = Only use file-based clipboard (ShareX mode)\nclipboard_mode = \"auto\"\n\n# Cache images from wl-clipboard for faster subsequent access\ncache_wl_images = true\n\n# Security: Directory access restrictions\n# If not configured, all paths are allowed\n# To restrict access to specific directories (and their subdirectories):\n#\n# allowed_directories = [\n#   \"/mnt/c/Users/YOUR_USERNAME/Documents/ShareX\",\n#   \"/home/YOUR_USERNAME\",\n#   \"/tmp\"\n# ]\n";
-        let _ = fs::write(&path, default);
-        #[cfg(unix)]
-        {
-            let _ = fs::set_permissions(&path, fs::Permissions::from_mode(0o600));
-        }
-        return None;
     }
-    fs::read_to_string(&path)
-        .ok()
-        .and_then(|s| toml::from_str::<BridgeConfig>(&s).ok())
 }
 
-fn load_ttl() -> Duration {
-    // Env var override in seconds
-    if let Ok(v) = env::var("WSL_CLIP_BRIDGE_TTL_SECS")
-        && let Ok(secs) = v.trim().parse::<u64>()
-    {
-        return Duration::from_secs(secs.min(86_400));
-    }
-    // TOML config: $XDG_CONFIG_HOME/wsl-clip-bridge/config.toml
-    if let Some(cfg) = load_config()
-        && let Some(secs) = cfg.ttl_secs
-    {
-        return Duration::from_secs(secs.min(86_400));
-    }
-    // default 5 minutes
-    Duration::from_secs(300)
+/// No setting holds key material: `encrypt_storage` reads its key from the
+/// file named by `WSL_CLIP_BRIDGE_KEY_FILE`, which is never printed
+fn print_setting(key: &str, value: &toml::Value, source: &str) {
+    println!("{key} = {value} # {source}");
+}
+
+// TOML integers are signed 64-bit
+fn toml_int(value: u64) -> i64 {
+    i64::try_from(value).unwrap_or(i64::MAX)
 }
 
 fn is_file_fresh(path: &Path, ttl: Duration) -> bool {
-    fs::metadata(path)
-        .and_then(|metadata| metadata.modified())
-        .is_ok_and(|modified_time| {
-            SystemTime::now()
-                .duration_since(modified_time)
-                .is_ok_and(|elapsed| elapsed <= ttl && is_file_non_empty(path))
-        })
+    if ttl == NEVER_EXPIRES || is_pinned() {
+        return is_file_non_empty(path);
+    }
+    primed_at(path).is_some_and(|primed| {
+        SystemTime::now()
+            .duration_since(primed)
+            .is_ok_and(|elapsed| elapsed <= ttl && is_file_non_empty(path))
+    })
 }
 
 // wl-clipboard integration functions
 fn wl_clipboard_available() -> bool {
     // Check config first
-    let config = load_config();
-    if let Some(cfg) = config
-        && let Some(mode) = cfg.clipboard_mode.as_deref()
-        && mode == "file_only"
-    {
+    if load_config().clipboard_mode.as_deref() == Some("file_only") {
+        return false;
+    }
+    // A named slot holds only what was copied into it
+    if current_slot().is_some() {
         return false;
     }
 
@@ -609,8 +8487,7 @@ fn wl_clipboard_available() -> bool {
     Command::new("which")
         .arg("wl-paste")
         .output()
-        .map(|o| o.status.success())
-        .unwrap_or(false)
+        .is_ok_and(|o| o.status.success())
 }
 
 fn get_wl_clipboard_types() -> io::Result<Vec<String>> {
@@ -633,3 +8510,505 @@ fn fetch_from_wl_clipboard(mime_type: &str) -> io::Result<Vec<u8>> {
         Err(io::Error::other("Failed to fetch from clipboard"))
     }
 }
+
+// Windows clipboard file list (CF_HDROP), read through PowerShell
+fn windows_file_fallback_enabled() -> bool {
+    let config = load_config();
+    config
+        .windows_file_fallback
+        .unwrap_or(DEFAULT_WINDOWS_FILE_FALLBACK)
+        && config.clipboard_mode.as_deref() != Some("file_only")
+        && current_slot().is_none()
+}
+
+/// Lists the dropped files one per line, as base64 of UTF-8 so names
+/// survive whatever code page the console uses
+const FILE_DROP_SCRIPT: &str = "Add-Type -AssemblyName System.Windows.Forms; \
+    $files = [System.Windows.Forms.Clipboard]::GetFileDropList(); \
+    [Convert]::ToBase64String([Text.Encoding]::UTF8.GetBytes(($files -join \"`n\")))";
+
+/// The files copied in Explorer as a text/uri-list, or None when the
+/// clipboard holds none (or PowerShell can't be run). Each path is held to
+/// the access policy, like a copied uri-list.
+fn windows_file_drop_list() -> Option<String> {
+    let output = Command::new("powershell.exe")
+        .args(["-NoProfile", "-NonInteractive", "-STA", "-Command"])
+        .arg(FILE_DROP_SCRIPT)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let encoded = String::from_utf8_lossy(&output.stdout);
+    let listed = base64::engine::general_purpose::STANDARD
+        .decode(encoded.trim())
+        .ok()?;
+    let listed = String::from_utf8(listed).ok()?;
+
+    let mut uris = String::new();
+    for windows_path in listed.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        let Some(path) = wsl_path(windows_path) else {
+            eprintln!("Warning: Skipping '{windows_path}': not on a drive letter");
+            continue;
+        };
+        if let Err(e) = check_path_policy(&path) {
+            eprintln!("Warning: Skipping '{windows_path}': {e}");
+            continue;
+        }
+        uris.push_str(&file_uri(&path));
+        uris.push_str("\r\n");
+    }
+    (!uris.is_empty()).then_some(uris)
+}
+
+/// `C:\Users\me\a b.txt` as `/mnt/c/Users/me/a b.txt`. UNC paths
+/// (network shares, `\\wsl$`) have no /mnt form and give None.
+fn wsl_path(windows_path: &str) -> Option<PathBuf> {
+    let mut chars = windows_path.chars();
+    let drive = chars.next().filter(char::is_ascii_alphabetic)?;
+    let rest = chars.as_str().strip_prefix(':')?;
+    if !(rest.is_empty() || rest.starts_with('\\')) {
+        return None; // "C:foo" is relative to C:'s current directory
+    }
+    let mut path = format!("/mnt/{}", drive.to_ascii_lowercase());
+    for component in rest.split('\\').filter(|c| !c.is_empty()) {
+        path.push('/');
+        path.push_str(component);
+    }
+    Some(PathBuf::from(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory under the system temp dir, unique to this test
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("xclip-test-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.canonicalize().unwrap()
+    }
+
+    /// Run with built-in defaults rather than whatever config the
+    /// environment points at
+    fn use_default_config() {
+        let mut slot = CONFIG.write().unwrap_or_else(PoisonError::into_inner);
+        slot.get_or_insert_with(|| {
+            Box::leak(Box::new(ResolvedConfig {
+                file_state: ConfigFileState::Missing,
+                unknown_keys: Vec::new(),
+                profile: None,
+                config: BridgeConfig::default(),
+                values: toml::Table::new(),
+                sources: HashMap::new(),
+            }))
+        });
+    }
+
+    /// A PNG that is all header: an IHDR declaring `width` x `height`, a
+    /// token IDAT and IEND
+    fn png_header(width: u32, height: u32) -> Vec<u8> {
+        let mut ihdr = Vec::new();
+        ihdr.extend_from_slice(&width.to_be_bytes());
+        ihdr.extend_from_slice(&height.to_be_bytes());
+        ihdr.extend_from_slice(&[8, 6, 0, 0, 0]); // 8-bit RGBA, no interlace
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        for (kind, body) in [
+            (b"IHDR", &ihdr[..]),
+            (b"IDAT", &[0u8; 4][..]),
+            (b"IEND", &[]),
+        ] {
+            png.extend_from_slice(&u32::try_from(body.len()).unwrap().to_be_bytes());
+            let start = png.len();
+            png.extend_from_slice(kind);
+            png.extend_from_slice(body);
+            let crc = crc32fast::hash(&png[start..]);
+            png.extend_from_slice(&crc.to_be_bytes());
+        }
+        png
+    }
+
+    #[test]
+    fn dot_dot_out_of_an_allowed_directory_is_refused() {
+        let root = scratch_dir("dot-dot");
+        fs::create_dir(root.join("allowed")).unwrap();
+        fs::create_dir(root.join("secret")).unwrap();
+        fs::write(root.join("allowed/file"), b"ok").unwrap();
+        fs::write(root.join("secret/file"), b"secret").unwrap();
+        let cfg = BridgeConfig {
+            allowed_directories: Some(vec![root.join("allowed").to_string_lossy().into_owned()]),
+            ..BridgeConfig::default()
+        };
+
+        assert!(check_path_policy_with(&cfg, &root.join("allowed/file")).is_ok());
+        let escaped = root.join("allowed/../secret/file");
+        let err = check_path_policy_with(&cfg, &escaped).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        // Judged on the resolved path, not the one spelled with `..`
+        assert!(err.to_string().contains("secret/file"), "{err}");
+        assert!(!err.to_string().contains(".."), "{err}");
+    }
+
+    #[test]
+    fn oversized_png_header_is_rejected_before_decoding() {
+        use_default_config();
+        let png = png_header(100_000, 100_000);
+        assert_eq!(header_dimensions(&png), Some((100_000, 100_000)));
+
+        let err = check_pixel_limit(&png).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::FileTooLarge);
+        assert!(err.to_string().contains("100000x100000"), "{err}");
+        let err = check_dimensions(100_000, 100_000).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::FileTooLarge);
+        // The header alone decides; the decoder never sees the missing pixels
+        let err = decode_image(&png).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::FileTooLarge);
+
+        assert!(check_dimensions(1000, 1000).is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stored_payload_that_is_a_symlink_is_not_opened() {
+        let root = scratch_dir("stored-symlink");
+        fs::write(root.join("secret"), b"secret").unwrap();
+        std::os::unix::fs::symlink(root.join("secret"), root.join("text.txt")).unwrap();
+
+        let err = open_stored(&root.join("text.txt"), OpenOptions::new().read(true)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(err.to_string().contains("is a symlink"), "{err}");
+        // Wiping skips it rather than zeroing whatever it points at
+        zero_file(&root.join("text.txt")).unwrap();
+        assert_eq!(fs::read(root.join("secret")).unwrap(), b"secret");
+    }
+
+    #[test]
+    fn wiping_zeroes_the_file_in_place() {
+        let root = scratch_dir("zero-file");
+        let path = root.join("text.txt");
+        let data = vec![0xa5; 20_000];
+        fs::write(&path, &data).unwrap();
+
+        zero_file(&path).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), vec![0; data.len()]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn wiping_leaves_data_still_linked_from_history() {
+        let root = scratch_dir("zero-linked");
+        let path = root.join("text.txt");
+        fs::write(&path, b"still in history").unwrap();
+        fs::hard_link(&path, root.join("history-copy")).unwrap();
+
+        zero_file(&path).unwrap();
+        assert_eq!(
+            fs::read(root.join("history-copy")).unwrap(),
+            b"still in history"
+        );
+    }
+
+    fn encoded_png(width: u32, height: u32) -> Vec<u8> {
+        let img = DynamicImage::new_rgb8(width, height);
+        let mut png = Vec::new();
+        img.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        png
+    }
+
+    #[test]
+    fn image_within_the_dimension_limit_is_never_decoded() {
+        use_default_config();
+        let png = encoded_png(1200, 800);
+        let before = DECODE_COUNT.with(std::cell::Cell::get);
+
+        let out = downscale_image_if_needed(&png, "image/png", (1280, 800));
+        assert_eq!(out, png);
+        assert_eq!(DECODE_COUNT.with(std::cell::Cell::get), before);
+
+        // One over the limit does go through the decoder
+        let out = downscale_image_if_needed(&png, "image/png", (600, 600));
+        assert_eq!(DECODE_COUNT.with(std::cell::Cell::get), before + 1);
+        assert_eq!(header_dimensions(&out), Some((600, 400)));
+    }
+
+    #[test]
+    fn mime_aliases_resolve_to_one_canonical_type() {
+        let cases = [
+            ("image/jpg", Some("image/jpeg")),
+            ("image/jpeg", Some("image/jpeg")),
+            ("IMAGE/JPG", Some("image/jpeg")),
+            ("Image/Jpeg", Some("image/jpeg")),
+            (" image/jpg ", Some("image/jpeg")),
+            ("image/jpg; q=0.9", Some("image/jpeg")),
+            ("image/PNG", Some("image/png")),
+            ("image/png;charset=binary", Some("image/png")),
+            ("image/gif", Some("image/gif")),
+            ("image/webp", Some("image/webp")),
+            ("image/bmp", None),
+            ("image/", None),
+            ("text/plain", None),
+        ];
+        for (mime, canonical) in cases {
+            assert_eq!(canonical_image_mime(mime), canonical, "{mime}");
+        }
+
+        let cases = [
+            ("image/jpg", "image/jpeg"),
+            ("IMAGE/JPG; q=0.9", "image/jpeg"),
+            ("text/plain;charset=utf-8", "text/plain"),
+            ("TEXT/PLAIN", "text/plain"),
+            ("UTF8_STRING", "text/plain"),
+            ("STRING", "text/plain"),
+            ("text", "text/plain"),
+            ("Text/HTML", "text/html"),
+            ("image/bmp", "image/bmp"),
+        ];
+        for (mime, normalized) in cases {
+            assert_eq!(normalize_mime(mime), normalized, "{mime}");
+        }
+        assert!(mime_matches("image/jpg", "image/jpeg"));
+        assert!(mime_matches("IMAGE/*", "image/jpeg"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn real_uid_is_the_first_uid_field() {
+        let status =
+            "Name:\txclip\nUmask:\t0022\nUid:\t1000\t0\t0\t0\nGid:\t1000\t1000\t1000\t1000\n";
+        assert_eq!(real_uid(status), Some(1000));
+        assert_eq!(real_uid("Name:\txclip\n"), None);
+        assert_eq!(
+            current_uid(),
+            Some(fs::metadata("/proc/self").unwrap().uid())
+        );
+    }
+
+    #[test]
+    fn sizes_parse_with_binary_units() {
+        let cases: &[(&str, u64)] = &[
+            ("1048576", 1_048_576),
+            ("0", 0),
+            ("500KB", 500 * 1024),
+            ("500kb", 500 * 1024),
+            ("1.5GB", 1_610_612_736),
+            ("10 mb", 10 << 20),
+            (" 2KiB ", 2048),
+            ("3K", 3072),
+            ("7B", 7),
+            ("1TB", 1 << 40),
+        ];
+        for (raw, bytes) in cases {
+            assert_eq!(parse_size(raw), Ok(*bytes), "{raw:?}");
+        }
+    }
+
+    #[test]
+    fn malformed_or_overflowing_sizes_are_rejected() {
+        for raw in [
+            "",
+            ".",
+            "-5KB",
+            "KiB",
+            "5XB",
+            "1.5.5MB",
+            "20000000TB",
+            "16777216.5TB",
+            "18446744073709551616",
+        ] {
+            assert!(parse_size(raw).is_err(), "{raw:?}");
+        }
+    }
+
+    #[test]
+    fn sizes_format_in_the_largest_unit_reached() {
+        let cases: &[(u64, &str)] = &[
+            (0, "0B"),
+            (1023, "1023B"),
+            (1536, "1.5KB"),
+            (500 * 1024, "500KB"),
+            (100 << 20, "100MB"),
+            (1_610_612_736, "1.5GB"),
+        ];
+        for (bytes, text) in cases {
+            assert_eq!(format_size(*bytes), *text);
+            assert_eq!(parse_size(text), Ok(*bytes), "{text:?}");
+        }
+    }
+
+    #[test]
+    fn durations_parse_to_seconds() {
+        let cases: &[(&str, u64)] = &[
+            ("300", 300),
+            ("90s", 90),
+            ("15m", 900),
+            ("2h", 7200),
+            ("1d", 86_400),
+            ("1h30m", 5400),
+            ("never", 0),
+            ("NEVER", 0),
+        ];
+        for (raw, secs) in cases {
+            assert_eq!(parse_duration(raw), Ok(*secs), "{raw:?}");
+        }
+        for raw in ["", "1h30", "5x", "-5", "h", "99999999999999999999s"] {
+            assert!(parse_duration(raw).is_err(), "{raw:?}");
+        }
+    }
+
+    #[test]
+    fn allowed_directory_entries_match_by_prefix_or_pattern() {
+        let root = scratch_dir("dir-rules");
+        for dir in ["work/alpha/src", "work/beta", "projects", "projects2"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        let file = |dir: &str| {
+            let path = root.join(dir).join("file.txt");
+            fs::write(&path, b"x").unwrap();
+            path
+        };
+        let allowed = |entry: &str| BridgeConfig {
+            allowed_directories: Some(vec![format!("{}/{entry}", root.display())]),
+            ..BridgeConfig::default()
+        };
+
+        // `*` stays within one component, and covers everything beneath it
+        let cfg = allowed("work/*/src");
+        assert!(check_path_policy_with(&cfg, &file("work/alpha/src")).is_ok());
+        let err = check_path_policy_with(&cfg, &file("work/beta")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(
+            DirRule::compile(&format!("{}/work/*/src", root.display()))
+                .is_some_and(|rule| rule.covers(&root.join("work/alpha/src/deeper/file.txt")))
+        );
+
+        // A plain entry is a whole-component prefix: projects2 isn't in it
+        let cfg = allowed("projects");
+        assert!(check_path_policy_with(&cfg, &file("projects")).is_ok());
+        assert!(check_path_policy_with(&cfg, &file("projects2")).is_err());
+    }
+
+    #[test]
+    fn quarter_turns_swap_width_and_height_and_a_half_turn_does_not() {
+        use_default_config();
+        // Red top-left corner, to follow where the rotation takes it
+        let mut img = image::RgbImage::new(30, 20);
+        img.put_pixel(0, 0, image::Rgb([255, 0, 0]));
+        let mut png = Vec::new();
+        DynamicImage::ImageRgb8(img)
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+
+        let cases = [
+            (Rotation::Quarter, (20, 30), (19, 0)),
+            (Rotation::Half, (30, 20), (29, 19)),
+            (Rotation::ThreeQuarter, (20, 30), (0, 29)),
+        ];
+        for (rotate, size, (x, y)) in cases {
+            let options = InputOptions {
+                rotate: Some(rotate),
+                ..InputOptions::default()
+            };
+            let out = decode_image(&transform_image(&png, "image/png", options))
+                .unwrap()
+                .to_rgb8();
+            assert_eq!(out.dimensions(), size, "{rotate:?}");
+            assert_eq!(out.get_pixel(x, y).0, [255, 0, 0], "{rotate:?}");
+        }
+    }
+
+    /// A 30x20 PNG with red, green, blue and white corners (clockwise from
+    /// top-left), tagged with EXIF `orientation` when one is given
+    fn cornered_png(orientation: Option<u16>) -> Vec<u8> {
+        let mut img = image::RgbImage::new(30, 20);
+        img.put_pixel(0, 0, image::Rgb([255, 0, 0]));
+        img.put_pixel(29, 0, image::Rgb([0, 255, 0]));
+        img.put_pixel(29, 19, image::Rgb([0, 0, 255]));
+        img.put_pixel(0, 19, image::Rgb([255, 255, 255]));
+        let mut png = Vec::new();
+        DynamicImage::ImageRgb8(img)
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        let Some(orientation) = orientation else {
+            return png;
+        };
+
+        // Little-endian TIFF with one IFD entry: Orientation, a SHORT
+        let mut exif = b"II*\0\x08\0\0\0\x01\0\x12\x01\x03\0\x01\0\0\0".to_vec();
+        exif.extend_from_slice(&orientation.to_le_bytes());
+        exif.extend_from_slice(&[0; 6]);
+        let mut chunk = u32::try_from(exif.len()).unwrap().to_be_bytes().to_vec();
+        chunk.extend_from_slice(b"eXIf");
+        chunk.extend_from_slice(&exif);
+        chunk.extend_from_slice(&crc32fast::hash(&chunk[4..]).to_be_bytes());
+        // Straight after the signature and IHDR
+        png.splice(33..33, chunk);
+        png
+    }
+
+    #[test]
+    fn exif_orientation_is_baked_into_the_pixels() {
+        use_default_config();
+        let (red, green, blue, white) = ([255, 0, 0], [0, 255, 0], [0, 0, 255], [255, 255, 255]);
+        // Orientation, upright size, then the upright top-left, top-right
+        // and bottom-left corners
+        let cases = [
+            (3, (30, 20), [blue, white, green]),
+            (6, (20, 30), [white, red, blue]),
+            (8, (20, 30), [green, blue, red]),
+        ];
+        for (orientation, (width, height), corners) in cases {
+            let png = cornered_png(Some(orientation));
+            assert_ne!(exif_orientation(&png), Orientation::NoTransforms);
+            let upright = apply_exif_orientation(png, "image/png");
+            assert_eq!(exif_orientation(&upright), Orientation::NoTransforms);
+            let out = decode_image(&upright).unwrap().to_rgb8();
+            assert_eq!(out.dimensions(), (width, height), "{orientation}");
+            let found =
+                [(0, 0), (width - 1, 0), (0, height - 1)].map(|(x, y)| out.get_pixel(x, y).0);
+            assert_eq!(found, corners, "{orientation}");
+        }
+    }
+
+    #[test]
+    fn image_without_exif_orientation_is_left_byte_identical() {
+        use_default_config();
+        let png = cornered_png(None);
+        assert_eq!(apply_exif_orientation(png.clone(), "image/png"), png);
+        assert_eq!(strip_image_metadata(&png, "image/png"), png);
+
+        let jpeg = encode_jpeg(&decode_image(&png).unwrap(), 90).unwrap();
+        assert_eq!(apply_exif_orientation(jpeg.clone(), "image/jpeg"), jpeg);
+    }
+
+    #[test]
+    fn half_transparent_pixels_blend_with_the_background() {
+        let mut img = image::RgbaImage::new(3, 1);
+        img.put_pixel(0, 0, image::Rgba([200, 100, 0, 128]));
+        img.put_pixel(1, 0, image::Rgba([200, 100, 0, 255]));
+        img.put_pixel(2, 0, image::Rgba([200, 100, 0, 0]));
+        let flat = flatten_alpha(&DynamicImage::ImageRgba8(img), [32, 64, 96]);
+        // (fg * 128 + bg * 127) / 255, rounded
+        assert_eq!(flat.get_pixel(0, 0).0, [116, 82, 48]);
+        assert_eq!(flat.get_pixel(1, 0).0, [200, 100, 0]);
+        assert_eq!(flat.get_pixel(2, 0).0, [32, 64, 96]);
+    }
+
+    #[test]
+    fn invalid_background_color_fails_the_config_load() {
+        let parse = |value: &str| {
+            toml::from_str::<BridgeConfig>(&format!("jpeg_background_color = {value:?}"))
+                .map(|config| config.jpeg_background_color)
+        };
+        assert_eq!(parse("#204060").unwrap(), Some([32, 64, 96]));
+        assert_eq!(parse("#f80").unwrap(), Some([255, 136, 0]));
+        for invalid in ["204060", "#20406", "#gg0000", "white", ""] {
+            let err = parse(invalid).unwrap_err();
+            assert!(
+                err.to_string().contains("invalid color"),
+                "{invalid}: {err}"
+            );
+        }
+    }
+}
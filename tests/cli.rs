@@ -0,0 +1,836 @@
+//! End-to-end checks against the built binary, each in a private HOME with
+//! its own config and storage directories
+
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::mpsc;
+use std::time::{Duration, Instant, SystemTime};
+
+const EXIT_SUCCESS: i32 = 0;
+const EXIT_NO_CONTENT: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_ACCESS_DENIED: i32 = 3;
+const EXIT_TOO_LARGE: i32 = 4;
+const EXIT_EMPTY_INPUT: i32 = 6;
+const EXIT_PINNED: i32 = 9;
+const EXIT_INSECURE_STORAGE: i32 = 11;
+
+struct Sandbox {
+    root: PathBuf,
+}
+
+impl Sandbox {
+    fn new(name: &str) -> Self {
+        let root = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("config/wsl-clip-bridge")).unwrap();
+        fs::create_dir_all(root.join("cache")).unwrap();
+        Self { root }
+    }
+
+    fn config_path(&self) -> PathBuf {
+        self.root.join("config/wsl-clip-bridge/config.toml")
+    }
+
+    fn storage(&self) -> PathBuf {
+        self.root.join("cache/wsl-clip-bridge")
+    }
+
+    /// xclip with only this sandbox's environment
+    fn command(&self, args: &[&str]) -> Command {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_xclip"));
+        command
+            .args(args)
+            .env_clear()
+            .env("PATH", std::env::var_os("PATH").unwrap_or_default())
+            .env("HOME", &self.root)
+            .env("XDG_CONFIG_HOME", self.root.join("config"))
+            .env("XDG_CACHE_HOME", self.root.join("cache"));
+        command
+    }
+
+    fn run(&self, args: &[&str], stdin: &[u8]) -> Output {
+        self.run_env(args, &[], stdin)
+    }
+
+    /// Run xclip with this sandbox's environment plus `vars`, feeding it
+    /// `stdin`
+    fn run_env(&self, args: &[&str], vars: &[(&str, &Path)], stdin: &[u8]) -> Output {
+        let mut child = self
+            .command(args)
+            .envs(vars.iter().copied())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        // A run refused before reading stdin closes the pipe early
+        let _ = child.stdin.take().unwrap().write_all(stdin);
+        child.wait_with_output().unwrap()
+    }
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+fn assert_exit(output: &Output, code: i32) {
+    assert_eq!(output.status.code(), Some(code), "{}", stderr(output));
+}
+
+#[test]
+fn unreadable_config_with_security_keys_is_refused_with_one_warning() {
+    let sandbox = Sandbox::new("unreadable_config_security");
+    let mut contents = b"allowed_directories = [\"/tmp\"]\n# ".to_vec();
+    contents.extend_from_slice(&[0xff, 0xfe, b'\n']);
+    fs::write(sandbox.config_path(), contents).unwrap();
+
+    let output = sandbox.run(&["-o"], b"");
+    let stderr = stderr(&output);
+    assert_eq!(output.status.code(), Some(EXIT_USAGE), "{stderr}");
+    assert_eq!(
+        stderr.matches("Could not read config").count(),
+        1,
+        "{stderr}"
+    );
+    assert!(stderr.contains("Refusing to run"), "{stderr}");
+}
+
+#[test]
+fn config_path_that_is_a_directory_is_refused() {
+    let sandbox = Sandbox::new("config_is_directory");
+    fs::create_dir(sandbox.config_path()).unwrap();
+
+    let output = sandbox.run(&["-o"], b"");
+    let stderr = stderr(&output);
+    assert_eq!(output.status.code(), Some(EXIT_USAGE), "{stderr}");
+    assert_eq!(
+        stderr.matches("Could not read config").count(),
+        1,
+        "{stderr}"
+    );
+}
+
+#[test]
+fn unreadable_config_without_security_keys_falls_back_to_defaults() {
+    let sandbox = Sandbox::new("unreadable_config_plain");
+    fs::write(sandbox.config_path(), b"ttl_secs = 60\n# \xff\n").unwrap();
+
+    let output = sandbox.run(&["-i"], b"hello");
+    let stderr = stderr(&output);
+    assert_eq!(output.status.code(), Some(EXIT_SUCCESS), "{stderr}");
+    assert_eq!(
+        stderr.matches("Could not read config").count(),
+        1,
+        "{stderr}"
+    );
+    assert!(stderr.contains("using defaults"), "{stderr}");
+    assert!(sandbox.storage().join("text.txt").is_file());
+}
+
+#[test]
+fn copy_over_pinned_content_is_refused_until_forced() {
+    let sandbox = Sandbox::new("pinned_copy");
+    let pin = sandbox.storage().join("pinned");
+    let text = sandbox.storage().join("text.txt");
+
+    let output = sandbox.run(&["-i", "-pin"], b"kept");
+    assert_exit(&output, EXIT_SUCCESS);
+    assert!(pin.exists());
+
+    let output = sandbox.run(&["-i"], b"replaced");
+    assert_exit(&output, EXIT_PINNED);
+    assert_eq!(fs::read(&text).unwrap(), b"kept");
+    assert!(pin.exists());
+
+    let output = sandbox.run(&["-i", "-force"], b"forced");
+    assert_exit(&output, EXIT_SUCCESS);
+    assert_eq!(fs::read(&text).unwrap(), b"forced");
+    assert!(!pin.exists());
+
+    let output = sandbox.run(&["-i", "-pin"], b"pinned again");
+    assert_exit(&output, EXIT_SUCCESS);
+    let output = sandbox.run(&["-i", "-force", "-pin"], b"repinned");
+    assert_exit(&output, EXIT_SUCCESS);
+    assert_eq!(fs::read(&text).unwrap(), b"repinned");
+    assert!(pin.exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn storage_directory_writable_by_others_is_refused() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let sandbox = Sandbox::new("shared_storage");
+    fs::create_dir(sandbox.storage()).unwrap();
+    fs::set_permissions(sandbox.storage(), fs::Permissions::from_mode(0o777)).unwrap();
+
+    let output = sandbox.run(&["-i"], b"hello");
+    let stderr = stderr(&output);
+    assert_eq!(
+        output.status.code(),
+        Some(EXIT_INSECURE_STORAGE),
+        "{stderr}"
+    );
+    assert!(stderr.contains("writable by other users"), "{stderr}");
+    assert!(!sandbox.storage().join("text.txt").exists());
+
+    let output = sandbox.run(&["-i", "--insecure-storage"], b"hello");
+    assert_exit(&output, EXIT_SUCCESS);
+}
+
+#[cfg(unix)]
+#[test]
+fn storage_directory_owned_by_someone_else_is_refused() {
+    let sandbox = Sandbox::new("foreign_storage");
+    fs::create_dir(sandbox.storage()).unwrap();
+    // Handing a directory to another user takes root; elsewhere this can't
+    // be set up
+    if std::os::unix::fs::chown(sandbox.storage(), Some(65534), Some(65534)).is_err() {
+        return;
+    }
+
+    let output = sandbox.run(&["-i"], b"hello");
+    let stderr = stderr(&output);
+    assert_eq!(
+        output.status.code(),
+        Some(EXIT_INSECURE_STORAGE),
+        "{stderr}"
+    );
+    assert!(stderr.contains("is owned by uid 65534"), "{stderr}");
+    assert!(!sandbox.storage().join("text.txt").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn storage_directory_that_is_a_symlink_is_refused() {
+    let sandbox = Sandbox::new("symlinked_storage");
+    let elsewhere = sandbox.root.join("elsewhere");
+    fs::create_dir(&elsewhere).unwrap();
+    std::os::unix::fs::symlink(&elsewhere, sandbox.storage()).unwrap();
+
+    let output = sandbox.run(&["-i"], b"hello");
+    let stderr = stderr(&output);
+    assert_eq!(
+        output.status.code(),
+        Some(EXIT_INSECURE_STORAGE),
+        "{stderr}"
+    );
+    assert!(stderr.contains("is a symlink"), "{stderr}");
+    assert!(!elsewhere.join("text.txt").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn pasting_a_symlinked_payload_is_refused() {
+    let sandbox = Sandbox::new("symlinked_payload");
+    let output = sandbox.run(&["-i"], b"hello");
+    assert_exit(&output, EXIT_SUCCESS);
+    let secret = sandbox.root.join("secret");
+    fs::write(&secret, b"not for pasting").unwrap();
+    let text = sandbox.storage().join("text.txt");
+    fs::remove_file(&text).unwrap();
+    std::os::unix::fs::symlink(&secret, &text).unwrap();
+
+    let output = sandbox.run(&["-o"], b"");
+    assert_ne!(output.status.code(), Some(EXIT_SUCCESS));
+    assert!(output.stdout.is_empty());
+    assert!(
+        stderr(&output).contains("is a symlink"),
+        "{}",
+        stderr(&output)
+    );
+    assert_eq!(fs::read(&secret).unwrap(), b"not for pasting");
+}
+
+#[test]
+fn encrypted_store_needs_the_key_it_was_written_with() {
+    let sandbox = Sandbox::new("encrypted_store");
+    fs::write(sandbox.config_path(), "encrypt_storage = true\n").unwrap();
+    let key = sandbox.root.join("key");
+    let other_key = sandbox.root.join("other-key");
+    fs::write(&key, [7u8; 32]).unwrap();
+    fs::write(&other_key, [9u8; 32]).unwrap();
+    let key_var = "WSL_CLIP_BRIDGE_KEY_FILE";
+
+    // No key: the copy fails rather than storing plaintext
+    let output = sandbox.run(&["-i"], b"top secret");
+    let stderr_text = stderr(&output);
+    assert_eq!(
+        output.status.code(),
+        Some(EXIT_ACCESS_DENIED),
+        "{stderr_text}"
+    );
+    assert!(stderr_text.contains("is not set"), "{stderr_text}");
+    assert!(!sandbox.storage().join("text.txt").exists());
+
+    let output = sandbox.run_env(&["-i"], &[(key_var, &key)], b"top secret");
+    assert_exit(&output, EXIT_SUCCESS);
+    let stored = fs::read(sandbox.storage().join("text.txt")).unwrap();
+    assert!(!stored.windows(10).any(|w| w == b"top secret"));
+
+    let output = sandbox.run(&["-o"], b"");
+    assert_exit(&output, EXIT_ACCESS_DENIED);
+    assert!(
+        stderr(&output).contains("is not set"),
+        "{}",
+        stderr(&output)
+    );
+
+    let output = sandbox.run_env(&["-o"], &[(key_var, &other_key)], b"");
+    assert_exit(&output, EXIT_ACCESS_DENIED);
+    assert!(output.stdout.is_empty());
+    assert!(
+        stderr(&output).contains("another key"),
+        "{}",
+        stderr(&output)
+    );
+    // Kept for when the right key is back
+    assert!(sandbox.storage().join("text.txt").exists());
+
+    let output = sandbox.run_env(&["-o"], &[(key_var, &key)], b"");
+    assert_exit(&output, EXIT_SUCCESS);
+    assert_eq!(output.stdout, b"top secret");
+}
+
+#[cfg(unix)]
+#[test]
+fn symlinked_input_is_refused_without_follow_symlinks() {
+    let sandbox = Sandbox::new("no_follow_symlinks");
+    let real = sandbox.root.join("real.txt");
+    let link = sandbox.root.join("link.txt");
+    fs::write(&real, b"through a link").unwrap();
+    std::os::unix::fs::symlink(&real, &link).unwrap();
+    let link = link.to_str().unwrap();
+
+    let output = sandbox.run(&["-i", link], b"");
+    assert_exit(&output, EXIT_SUCCESS);
+
+    fs::write(sandbox.config_path(), "follow_symlinks = false\n").unwrap();
+    let output = sandbox.run(&["-i", link], b"");
+    let stderr = stderr(&output);
+    assert_eq!(output.status.code(), Some(EXIT_ACCESS_DENIED), "{stderr}");
+    assert!(stderr.contains("follow_symlinks = false"), "{stderr}");
+    assert!(
+        sandbox
+            .run(&["-i", real.to_str().unwrap()], b"")
+            .status
+            .success()
+    );
+}
+
+#[test]
+fn image_that_lost_its_index_entry_is_sniffed_and_reindexed() {
+    let sandbox = Sandbox::new("lost_index_entry");
+    let mut png = Vec::new();
+    image::DynamicImage::new_rgb8(8, 8)
+        .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+        .unwrap();
+    let output = sandbox.run(&["-i", "-t", "image/png"], &png);
+    assert_exit(&output, EXIT_SUCCESS);
+
+    let index_path = sandbox.storage().join("index.json");
+    let mut index: serde_json::Value =
+        serde_json::from_slice(&fs::read(&index_path).unwrap()).unwrap();
+    index["entries"]
+        .as_object_mut()
+        .unwrap()
+        .remove("image.bin")
+        .unwrap();
+    fs::write(&index_path, serde_json::to_vec(&index).unwrap()).unwrap();
+
+    let output = sandbox.run(&["-o", "-t", "TARGETS"], b"");
+    assert_exit(&output, EXIT_SUCCESS);
+    assert!(
+        String::from_utf8_lossy(&output.stdout).contains("image/png"),
+        "{}",
+        String::from_utf8_lossy(&output.stdout)
+    );
+    let output = sandbox.run(&["-o", "-t", "image/png"], b"");
+    assert_exit(&output, EXIT_SUCCESS);
+    assert_eq!(output.stdout, png);
+
+    let index: serde_json::Value = serde_json::from_slice(&fs::read(&index_path).unwrap()).unwrap();
+    assert_eq!(index["entries"]["image.bin"]["mime"], "image/png");
+}
+
+#[test]
+fn mistyped_security_key_is_refused_with_one_warning() {
+    let sandbox = Sandbox::new("mistyped_security_key");
+    fs::write(sandbox.config_path(), "follow_symlinks = \"yes\"\n").unwrap();
+
+    let output = sandbox.run(&["-i"], b"hello");
+    let stderr = stderr(&output);
+    assert_eq!(output.status.code(), Some(EXIT_USAGE), "{stderr}");
+    assert_eq!(stderr.matches("Invalid config").count(), 1, "{stderr}");
+    assert!(stderr.contains("Refusing to run"), "{stderr}");
+    assert!(!sandbox.storage().join("text.txt").exists());
+}
+
+#[test]
+fn mistyped_plain_key_falls_back_to_defaults_with_one_warning() {
+    let sandbox = Sandbox::new("mistyped_plain_key");
+    fs::write(sandbox.config_path(), "jpeg_quality = \"high\"\n").unwrap();
+
+    let output = sandbox.run(&["-i"], b"hello");
+    let stderr = stderr(&output);
+    assert_eq!(output.status.code(), Some(EXIT_SUCCESS), "{stderr}");
+    assert_eq!(stderr.matches("Invalid config").count(), 1, "{stderr}");
+    assert_eq!(stderr.matches("using defaults").count(), 1, "{stderr}");
+    let output = sandbox.run(&["-o"], b"");
+    assert_exit(&output, EXIT_SUCCESS);
+    assert_eq!(output.stdout, b"hello");
+}
+
+#[test]
+fn show_config_is_valid_toml_without_key_material() {
+    let sandbox = Sandbox::new("show_config");
+    fs::write(
+        sandbox.config_path(),
+        "encrypt_storage = true\nttl_secs = \"1h\"\n",
+    )
+    .unwrap();
+    let key = sandbox.root.join("key");
+    fs::write(&key, [b'k'; 32]).unwrap();
+
+    let output = sandbox.run_env(&["show-config"], &[("WSL_CLIP_BRIDGE_KEY_FILE", &key)], b"");
+    assert_exit(&output, EXIT_SUCCESS);
+    let text = String::from_utf8(output.stdout).unwrap();
+    let table: toml::Table = toml::from_str(&text).unwrap();
+    assert_eq!(table["encrypt_storage"], toml::Value::Boolean(true));
+    assert_eq!(table["ttl_secs"], toml::Value::Integer(3600));
+    assert!(!text.contains("kkkk"), "{text}");
+}
+
+#[test]
+fn several_input_files_round_trip_as_a_tar() {
+    let sandbox = Sandbox::new("archive_round_trip");
+    let first = sandbox.root.join("first.txt");
+    let second = sandbox.root.join("second.bin");
+    fs::write(&first, b"first file\n").unwrap();
+    fs::write(&second, [0u8, 1, 2, 255]).unwrap();
+
+    let output = sandbox.run(
+        &["-i", first.to_str().unwrap(), second.to_str().unwrap()],
+        b"",
+    );
+    assert_exit(&output, EXIT_SUCCESS);
+    let output = sandbox.run(&["-o", "-t", "application/x-tar"], b"");
+    assert_exit(&output, EXIT_SUCCESS);
+
+    let mut archive = tar::Archive::new(output.stdout.as_slice());
+    let mut entries: Vec<(String, Vec<u8>)> = archive
+        .entries()
+        .unwrap()
+        .map(|entry| {
+            let mut entry = entry.unwrap();
+            let name = entry.path().unwrap().to_string_lossy().into_owned();
+            let mut data = Vec::new();
+            std::io::Read::read_to_end(&mut entry, &mut data).unwrap();
+            (name, data)
+        })
+        .collect();
+    entries.sort();
+    assert_eq!(
+        entries,
+        [
+            ("first.txt".to_string(), b"first file\n".to_vec()),
+            ("second.bin".to_string(), vec![0, 1, 2, 255]),
+        ]
+    );
+}
+
+#[test]
+fn input_files_over_the_storage_quota_are_refused() {
+    let sandbox = Sandbox::new("archive_over_quota");
+    fs::write(sandbox.config_path(), "max_storage_mb = 1\n").unwrap();
+    let files: Vec<String> = ["a.bin", "b.bin"]
+        .iter()
+        .map(|name| {
+            let path = sandbox.root.join(name);
+            fs::write(&path, vec![7u8; 600 * 1024]).unwrap();
+            path.to_string_lossy().into_owned()
+        })
+        .collect();
+
+    let output = sandbox.run(&["-i", &files[0], &files[1]], b"");
+    assert_exit(&output, EXIT_TOO_LARGE);
+    assert!(
+        stderr(&output).contains("max_storage_mb"),
+        "{}",
+        stderr(&output)
+    );
+    assert!(!sandbox.storage().join("archive.tar").exists());
+}
+
+#[test]
+fn empty_stdin_is_rejected_and_keeps_the_stored_text() {
+    let sandbox = Sandbox::new("empty_input_reject");
+    fs::write(sandbox.config_path(), "empty_input = \"reject\"\n").unwrap();
+    assert_exit(&sandbox.run(&["-i"], b"kept"), EXIT_SUCCESS);
+
+    let output = sandbox.run(&["-i"], b"");
+    assert_exit(&output, EXIT_EMPTY_INPUT);
+    assert_eq!(
+        fs::read(sandbox.storage().join("text.txt")).unwrap(),
+        b"kept"
+    );
+}
+
+#[test]
+fn empty_stdin_clears_the_stored_text() {
+    let sandbox = Sandbox::new("empty_input_clear");
+    fs::write(sandbox.config_path(), "empty_input = \"clear\"\n").unwrap();
+    assert_exit(&sandbox.run(&["-i"], b"gone soon"), EXIT_SUCCESS);
+
+    let output = sandbox.run(&["-i"], b"");
+    assert_exit(&output, EXIT_SUCCESS);
+    assert!(!sandbox.storage().join("text.txt").exists());
+    assert_exit(&sandbox.run(&["-o"], b""), EXIT_NO_CONTENT);
+}
+
+/// Deterministic noise, which JPEG compresses worst
+fn noisy_jpeg(size: u32, quality: u8) -> Vec<u8> {
+    let mut state: u32 = 0x2545_f491;
+    let img = image::RgbImage::from_fn(size, size, |_, _| {
+        state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+        let [r, g, b, _] = state.to_le_bytes();
+        image::Rgb([r, g, b])
+    });
+    let mut jpeg = Vec::new();
+    image::codecs::jpeg::JpegEncoder::new_with_quality(&mut jpeg, quality)
+        .encode_image(&img)
+        .unwrap();
+    jpeg
+}
+
+#[test]
+fn downscale_never_grow_keeps_the_dimension_limit_when_reencoding_bloats() {
+    let original = noisy_jpeg(800, 10);
+    let stored_size = |name: &str, never_grow: bool| {
+        let sandbox = Sandbox::new(name);
+        fs::write(
+            sandbox.config_path(),
+            format!(
+                "max_image_dimension = 400\njpeg_quality = 95\n\
+                 downscale_never_grow = {never_grow}\n"
+            ),
+        )
+        .unwrap();
+        let output = sandbox.run(&["-i", "-t", "image/jpeg"], &original);
+        assert_exit(&output, EXIT_SUCCESS);
+        let stored = fs::read(sandbox.storage().join("image.bin")).unwrap();
+        let img = image::load_from_memory(&stored).unwrap();
+        assert_eq!((img.width(), img.height()), (400, 400));
+        stored.len()
+    };
+
+    let bloated = stored_size("never_grow_off", false);
+    assert!(bloated > original.len(), "{bloated} <= {}", original.len());
+    let shrunk = stored_size("never_grow_on", true);
+    assert!(shrunk < original.len(), "{shrunk} >= {}", original.len());
+}
+
+/// Lines from a child's pipe, read on a thread so a test can wait with a
+/// timeout
+fn line_channel(pipe: impl std::io::Read + Send + 'static) -> mpsc::Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        for line in BufReader::new(pipe).lines().map_while(Result::ok) {
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+fn wait_for_line(lines: &mpsc::Receiver<String>, needle: &str) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+        match lines.recv_timeout(left) {
+            Ok(line) if line.contains(needle) => return,
+            Ok(_) => {}
+            Err(_) => break,
+        }
+    }
+    panic!("no line containing {needle:?}");
+}
+
+fn send_sighup(child: &Child) {
+    let status = Command::new("kill")
+        .args(["-HUP", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
+
+#[test]
+fn watch_reloads_config_on_sighup_and_keeps_it_when_the_file_is_broken() {
+    let sandbox = Sandbox::new("watch_sighup");
+    fs::write(sandbox.config_path(), "watch_interval_ms = 50\n").unwrap();
+    let mut watch = sandbox
+        .command(&["-watch", "-verbose"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let stdout = line_channel(watch.stdout.take().unwrap());
+    let stderr = line_channel(watch.stderr.take().unwrap());
+
+    // A reported copy means the loop, and so the SIGHUP handler, is running
+    assert_exit(&sandbox.run(&["-i"], b"first"), EXIT_SUCCESS);
+    wait_for_line(&stdout, "clipboard text/plain");
+
+    fs::write(sandbox.config_path(), "watch_interval_ms = 100\n").unwrap();
+    send_sighup(&watch);
+    wait_for_line(&stderr, "Reloaded config from");
+
+    // Had any of this file applied, the next copy would go unreported for a
+    // minute
+    fs::write(
+        sandbox.config_path(),
+        "watch_interval_ms = 60000\nverbose = [\n",
+    )
+    .unwrap();
+    send_sighup(&watch);
+    wait_for_line(
+        &stderr,
+        "Config reload failed; keeping the previous settings",
+    );
+    assert_exit(&sandbox.run(&["-i"], b"second"), EXIT_SUCCESS);
+    wait_for_line(&stdout, "clipboard text/plain");
+
+    watch.kill().unwrap();
+    watch.wait().unwrap();
+}
+
+#[test]
+fn directory_pattern_is_checked_after_variable_expansion() {
+    let sandbox = Sandbox::new("dir_pattern_expanded");
+    fs::write(
+        sandbox.config_path(),
+        "denied_directories = [\"$PROJECTS/*\"]\n",
+    )
+    .unwrap();
+
+    let output = sandbox.run_env(&["-i"], &[("PROJECTS", Path::new("/tmp/[a"))], b"x");
+    assert_exit(&output, EXIT_SUCCESS);
+    assert!(
+        stderr(&output).contains("Invalid pattern '$PROJECTS/*' in denied_directories"),
+        "{}",
+        stderr(&output)
+    );
+
+    let output = sandbox.run_env(&["-i"], &[("PROJECTS", Path::new("/tmp/a"))], b"x");
+    assert_exit(&output, EXIT_SUCCESS);
+    assert!(
+        !stderr(&output).contains("Invalid pattern"),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn paste_during_a_copy_sees_the_old_or_the_new_payload() {
+    let sandbox = Sandbox::new("paste_mid_prime");
+    let old = vec![b'o'; 1 << 20];
+    let new = vec![b'n'; 1 << 20];
+    assert_exit(&sandbox.run(&["-i"], &old), EXIT_SUCCESS);
+
+    std::thread::scope(|scope| {
+        scope.spawn(|| {
+            for round in 0..20 {
+                let payload = if round % 2 == 0 { &new } else { &old };
+                assert_exit(&sandbox.run(&["-i"], payload), EXIT_SUCCESS);
+            }
+        });
+        for _ in 0..40 {
+            let output = sandbox.run(&["-o"], b"");
+            assert_exit(&output, EXIT_SUCCESS);
+            assert!(
+                output.stdout == old || output.stdout == new,
+                "torn paste of {} bytes",
+                output.stdout.len()
+            );
+        }
+    });
+}
+
+#[test]
+fn status_reports_how_much_of_the_quota_is_used() {
+    let sandbox = Sandbox::new("status_quota_percent");
+    fs::write(sandbox.config_path(), "max_storage_mb = 1\n").unwrap();
+    assert_exit(&sandbox.run(&["-i"], &vec![b'q'; 300 * 1024]), EXIT_SUCCESS);
+
+    let output = sandbox.run(&["status", "-json"], b"");
+    assert_exit(&output, EXIT_SUCCESS);
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let quota = &report["quota"];
+    assert_eq!(quota["quota_bytes"], 1024 * 1024);
+    let used = quota["used_bytes"].as_u64().unwrap();
+    assert!(used >= 300 * 1024, "{used}");
+    assert_eq!(quota["used_percent"], used * 100 / (1024 * 1024));
+
+    let output = sandbox.run(&["status"], b"");
+    assert_exit(&output, EXIT_SUCCESS);
+    let text = String::from_utf8_lossy(&output.stdout);
+    let percent = used * 100 / (1024 * 1024);
+    assert!(
+        text.contains(&format!("quota: {used} of 1048576 bytes used ({percent}%)")),
+        "{text}"
+    );
+}
+
+#[test]
+fn concurrent_copies_and_pastes_never_tear_or_time_out() {
+    let sandbox = Sandbox::new("concurrent_stress");
+    let payloads: Vec<Vec<u8>> = (b'a'..=b'd').map(|fill| vec![fill; 256 * 1024]).collect();
+    assert_exit(&sandbox.run(&["-i"], &payloads[0]), EXIT_SUCCESS);
+
+    std::thread::scope(|scope| {
+        for writer in 0..4 {
+            let (sandbox, payloads) = (&sandbox, &payloads);
+            scope.spawn(move || {
+                for round in 0..10 {
+                    let payload = &payloads[(writer + round) % payloads.len()];
+                    assert_exit(&sandbox.run(&["-i"], payload), EXIT_SUCCESS);
+                }
+            });
+        }
+        for _ in 0..4 {
+            let (sandbox, payloads) = (&sandbox, &payloads);
+            scope.spawn(move || {
+                for _ in 0..10 {
+                    let output = sandbox.run(&["-o"], b"");
+                    assert_exit(&output, EXIT_SUCCESS);
+                    assert!(
+                        payloads.contains(&output.stdout),
+                        "torn paste of {} bytes",
+                        output.stdout.len()
+                    );
+                }
+            });
+        }
+    });
+}
+
+#[test]
+fn freshness_comes_from_the_prime_record_not_the_file_mtime() {
+    let sandbox = Sandbox::new("prime_record_freshness");
+    fs::write(sandbox.config_path(), "ttl_secs = 60\n").unwrap();
+    let day_ago = SystemTime::now() - Duration::from_secs(86_400);
+
+    // A restored backup: its old mtime must not make the copy stale
+    let input = sandbox.root.join("restored.txt");
+    fs::write(&input, "from a backup").unwrap();
+    File::options()
+        .write(true)
+        .open(&input)
+        .unwrap()
+        .set_modified(day_ago)
+        .unwrap();
+    assert_exit(
+        &sandbox.run(&["-i", input.to_str().unwrap()], b""),
+        EXIT_SUCCESS,
+    );
+    let output = sandbox.run(&["-o"], b"");
+    assert_exit(&output, EXIT_SUCCESS);
+    assert_eq!(output.stdout, b"from a backup");
+
+    // Age the record, then `touch` the payload: it stays expired
+    let index_path = sandbox.storage().join("index.json");
+    let mut index: serde_json::Value =
+        serde_json::from_slice(&fs::read(&index_path).unwrap()).unwrap();
+    let primed = day_ago.duration_since(SystemTime::UNIX_EPOCH).unwrap();
+    index["entries"]["text.txt"]["primed"] = u64::try_from(primed.as_millis()).unwrap().into();
+    fs::write(&index_path, serde_json::to_vec(&index).unwrap()).unwrap();
+    File::options()
+        .write(true)
+        .open(sandbox.storage().join("text.txt"))
+        .unwrap()
+        .set_modified(SystemTime::now())
+        .unwrap();
+    assert_exit(&sandbox.run(&["-o"], b""), EXIT_NO_CONTENT);
+}
+
+fn png_bytes(img: &image::DynamicImage) -> Vec<u8> {
+    let mut png = Vec::new();
+    img.write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+        .unwrap();
+    png
+}
+
+#[test]
+fn optimize_png_shrinks_few_color_images_and_keeps_photos() {
+    let sandbox = Sandbox::new("optimize_png");
+    fs::write(sandbox.config_path(), "optimize_png = true\n").unwrap();
+    let copy = |png: &[u8]| {
+        assert_exit(&sandbox.run(&["-i", "-t", "image/png"], png), EXIT_SUCCESS);
+        fs::read(sandbox.storage().join("image.bin")).unwrap()
+    };
+
+    // A screenshot-like image: 16 flat colors in bands
+    let screenshot = image::RgbaImage::from_fn(320, 200, |x, y| {
+        let shade = u8::try_from((x / 20 + y / 50) % 16).unwrap() * 16;
+        image::Rgba([shade, 255 - shade, shade / 2, 255])
+    });
+    let original = png_bytes(&image::DynamicImage::ImageRgba8(screenshot.clone()));
+    let stored = copy(&original);
+    assert!(
+        stored.len() < original.len(),
+        "{} >= {}",
+        stored.len(),
+        original.len()
+    );
+    let decoded = image::load_from_memory(&stored).unwrap().to_rgba8();
+    assert_eq!(decoded, screenshot);
+
+    // A photo-like image has far more than 256 colors to index
+    let mut state: u32 = 7;
+    let photo = image::RgbImage::from_fn(64, 64, |_, _| {
+        state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+        let [r, g, b, _] = state.to_le_bytes();
+        image::Rgb([r, g, b])
+    });
+    let original = png_bytes(&image::DynamicImage::ImageRgb8(photo));
+    assert_eq!(copy(&original), original);
+}
+
+/// Start-of-frame marker of a JPEG: 0xC0 for baseline, 0xC2 for progressive
+#[cfg(feature = "progressive-jpeg")]
+fn jpeg_frame_marker(jpeg: &[u8]) -> Option<u8> {
+    let mut pos = 2; // Past SOI
+    while let [0xFF, marker, high, low, ..] = jpeg[pos..] {
+        if matches!(marker, 0xC0..=0xCF) && !matches!(marker, 0xC4 | 0xC8 | 0xCC) {
+            return Some(marker);
+        }
+        pos += 2 + usize::from(u16::from_be_bytes([high, low]));
+    }
+    None
+}
+
+#[cfg(feature = "progressive-jpeg")]
+#[test]
+fn jpeg_progressive_writes_a_progressive_frame() {
+    let original = noisy_jpeg(128, 80);
+    assert_eq!(jpeg_frame_marker(&original), Some(0xC0));
+    for (progressive, marker) in [(true, 0xC2), (false, 0xC0)] {
+        let sandbox = Sandbox::new(&format!("jpeg_progressive_{progressive}"));
+        fs::write(
+            sandbox.config_path(),
+            format!("max_image_dimension = 64\njpeg_progressive = {progressive}\n"),
+        )
+        .unwrap();
+        assert_exit(
+            &sandbox.run(&["-i", "-t", "image/jpeg"], &original),
+            EXIT_SUCCESS,
+        );
+        let stored = fs::read(sandbox.storage().join("image.bin")).unwrap();
+        assert_eq!(jpeg_frame_marker(&stored), Some(marker), "{progressive}");
+        let img = image::load_from_memory(&stored).unwrap();
+        assert_eq!((img.width(), img.height()), (64, 64));
+    }
+}